@@ -188,7 +188,7 @@ impl syn::parse::Parse for Asm {
             } else if look.peek(Ident) {
                 let ident = content.parse::<Ident>()?;
                 if content.is_empty() {
-                    return Ok(Self::Var(ident));
+                    return Ok(Self::Label(HackLabel::Ident(ident.into())));
                 } else {
                     return Err(look.error())
                 }
@@ -1,12 +1,21 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use arbitrary_int::{u15, u3, u7};
 use bitbybit::{bitenum, bitfield};
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::iter::zip;
 use std::path::Path;
 
+pub mod diagnostic;
+pub mod disassembler;
+pub mod listing;
+mod preprocessor;
+pub mod rom;
+pub mod usage;
+
+use diagnostic::{Diagnostic, Diagnostics, Location};
+use preprocessor::Preprocessor;
+
 #[bitenum(u3, exhaustive: true)]
 #[derive(Debug, PartialEq)]
 /// The destination bits of a Hack C-Instruction.
@@ -575,6 +584,10 @@ impl CompBits {
 impl std::fmt::Display for CompBits {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use CBits as C;
+        // Anything unofficial is written as its raw bits so that it assembles back to exactly the same instruction
+        if self.get().is_none() {
+            return write!(f, "X{:02X}", self.raw_value().value());
+        }
         match self.c_bits() {
             C::Zero
             | C::Zero0
@@ -785,6 +798,17 @@ impl Instruction {
         )
     }
 
+    #[inline]
+    /// Creates a new C instruction from raw computation bits, which need not be an official configuration.
+    pub(crate) const fn c_raw(dest: Dest, comp: CompBits, jump: Jump) -> Self {
+        Instruction::DEFAULT.with_c_inst(
+            CInstruction::DEFAULT
+                .with_dest(DestBits::DEFAULT.with_get(dest))
+                .with_comp(comp)
+                .with_jump(jump),
+        )
+    }
+
     #[inline]
     /// Creates a new A instruction from the given 16 bit signed integer.
     ///
@@ -1001,6 +1025,7 @@ impl std::fmt::Octal for Instruction {
 // Consider making a NewLine variant and giving every variant an Option<&'a str> for a comment
 // would make representing any user formatting pretty comfy
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 pub enum Asm<'a> {
     Comment(Cow<'a, str>),
     Label(Cow<'a, str>),
@@ -1163,11 +1188,30 @@ impl<'a> Asm<'a> {
         // Optional @
         Self::At(input.strip_prefix('@').unwrap_or(input).into())
     }
+
+    /// Detaches this line from whatever source it was borrowed from.
+    pub fn into_owned(self) -> Asm<'static> {
+        match self {
+            Asm::Comment(c) => Asm::Comment(Cow::Owned(c.into_owned())),
+            Asm::Label(l) => Asm::Label(Cow::Owned(l.into_owned())),
+            Asm::At(a) => Asm::At(Cow::Owned(a.into_owned())),
+            Asm::Asm(i) => Asm::Asm(i),
+        }
+    }
 }
 
+/// How many instructions fit in ROM, which is as far as an A instruction can reach.
+pub const ROM_SIZE: usize = 0x8000;
+
 pub struct Assembler {
     pub labels: HashMap<String, i16>,
     pub var_counter: i16,
+    /// Whether an unknown `@symbol` quietly becomes a new variable, as in the official assembler.
+    ///
+    /// Turning this off makes every one of them an error, which catches typos in hand-written assembly.
+    pub implicit_variables: bool,
+    /// Anything suspicious from the last assembly that did not stop it
+    pub warnings: Vec<Diagnostic>,
 }
 
 impl Assembler {
@@ -1175,11 +1219,18 @@ impl Assembler {
         Assembler {
             labels: HashMap::new(),
             var_counter: 15, // Starts at 15 so we can increment it pre insertion
+            implicit_variables: true,
+            warnings: vec![],
         }
     }
 
     // Helper function to abstract over checking the static list first, then the labels unique to this assembly
     fn get_label(&mut self, label: &str) -> Option<i16> {
+        Self::builtin(label).or_else(|| self.labels.get(label).copied())
+    }
+
+    /// The predefined symbols every Hack program can use
+    pub(crate) fn builtin(label: &str) -> Option<i16> {
         match label {
             "SP" | "R0" => Some(0),
             "LCL" | "R1" => Some(1),
@@ -1200,7 +1251,7 @@ impl Assembler {
             "SCREEN" => Some(0x4000),
             "KBD" => Some(0x6000),
             "MAX" => Some(i16::MAX),
-            _ => self.labels.get(label).copied(),
+            _ => None,
         }
     }
 
@@ -1239,7 +1290,7 @@ impl Assembler {
                 "JNE" => Jump::JNE,
                 "JLE" => Jump::JLE,
                 "JMP" => Jump::JMP,
-                _ => bail!("Semicolon requires a valid jump command!"),
+                j => bail!("'{j}' is not a jump, expected JGT, JEQ, JGE, JLT, JNE, JLE or JMP"),
             }
         } else {
             Jump::Never
@@ -1281,62 +1332,346 @@ impl Assembler {
             "M-D" => Comp::MMinusD,
             x if x.starts_with('X') => {
                 // Support unofficial comp configurations by using Xnn, where nn is the 2 digit hex representation
-                // of the configuration in question.
-                Comp::A
+                // of the configuration in question, with the `a` bit as 0x40 for reading `M` instead of `A`.
+                let hex = &x[1..];
+                let bits = match u8::from_str_radix(hex, 16) {
+                    Ok(b) if hex.len() == 2 && hex.bytes().all(|c| c.is_ascii_hexdigit()) && b < 0x80 => b,
+                    _ => bail!("'{x}' is not an ALU configuration, expected X00 to X7F"),
+                };
+                let comp = CompBits::new_with_raw_value(u7::new(bits));
+                return Ok(Asm::Asm(Instruction::c_raw(dest, comp, jump)));
             }
-            _ => bail!("invalid or unsupported computation field"),
+            c => match Self::unofficial_comp(c) {
+                Some(comp) => return Ok(Asm::Asm(Instruction::c_raw(dest, comp, jump))),
+                None => bail!("'{c}' is not a computation the Hack ALU can do"),
+            },
         };
 
         Ok(Asm::Asm(Instruction::c(dest, comp, jump)))
     }
 
-    pub fn assemble(&mut self, asm: &[Asm]) -> Vec<Instruction> {
+    /// Readable names for the ALU configurations that compute something no official one does.
+    ///
+    /// As with the official computations either `A` or `M` can be used, and `~` works as well as `!`.
+    /// Where the result is simpler arithmetic than the literal operation, both are accepted (`D+!A` or `D-A-1`).
+    fn unofficial_comp(comp: &str) -> Option<CompBits> {
+        if comp.contains('A') && comp.contains('M') {
+            return None;
+        }
+        let mode = if comp.contains('M') { Mode::M } else { Mode::A };
+        let c_bits = match comp.replace('M', "A").replace('~', "!").as_str() {
+            "!D|!A" | "!A|!D" | "!(D&A)" | "!(A&D)" => CBits::DNandA,
+            "!(D+A)" | "!(A+D)" | "-D-A-1" => CBits::NotOfDPlusA,
+            "D&!A" | "!A&D" => CBits::DAndNotA,
+            "!D|A" | "A|!D" => CBits::NotDOrA,
+            "D+!A" | "!A+D" | "D-A-1" => CBits::DPlusNotA,
+            "!D&A" | "A&!D" => CBits::NotDAndA,
+            "D|!A" | "!A|D" => CBits::DOrNotA,
+            "!D+A" | "A+!D" | "A-D-1" => CBits::NotDPlusA,
+            "!D&!A" | "!A&!D" | "!(D|A)" | "!(A|D)" => CBits::DNorA,
+            "!D+!A" | "!A+!D" | "-D-A-2" => CBits::NotDPlusNotA,
+            "!D-1" | "-D-2" => CBits::NotDMinus1,
+            "!(!D+!A)" | "D+A+1" | "A+D+1" => CBits::NotNotDPlusNotA,
+            "!A-1" | "-A-2" => CBits::NotAMinus1,
+            "-2" => CBits::NegTwo,
+            _ => return None,
+        };
+        Some(CompBits::new_with_raw_value(u7::new(0)).with_mode(mode).with_c_bits(c_bits))
+    }
+
+    /// Assembles generated code, which has no source file to point diagnostics at.
+    ///
+    /// Lines are numbered as if the assembly had been written out one per line, as `translate` does.
+    pub fn assemble(&mut self, asm: &[Asm]) -> Result<Vec<Instruction>, Diagnostics> {
+        self.assemble_located(asm, &Location::generated(asm.len()))
+    }
+
+    /// Assembles parsed source, reporting problems against the lines they came from.
+    pub fn assemble_source(&mut self, source: &AsmSource) -> Result<Vec<Instruction>, Diagnostics> {
+        self.assemble_located(&source.asm, &source.locations)
+    }
+
+    fn assemble_located(
+        &mut self,
+        asm: &[Asm],
+        locations: &[Location],
+    ) -> Result<Vec<Instruction>, Diagnostics> {
+        // Kept alongside the index of the line they are about, so they can be put back in source order
+        let mut diagnostics = vec![];
+        let mut defined = HashMap::new();
+
         // first pass
-        let mut line: i16 = 0;
-        for com in asm {
-            if let Asm::Label(s) = com {
-                if self.get_label(s).is_none() {
-                    self.labels.insert(s.to_string(), line);
+        let mut line: usize = 0;
+        for (index, (com, location)) in zip(asm, locations).enumerate() {
+            match com {
+                Asm::Label(s) => {
+                    let error = if let Err(e) = check_symbol(s) {
+                        Some(e)
+                    } else if Self::builtin(s).is_some() {
+                        Some(format!("`{s}` is a built in symbol and cannot be redefined"))
+                    } else if let Some(first) = defined.insert(s.as_ref(), location) {
+                        Some(format!("label `{s}` is already defined at {first}"))
+                    } else if line >= ROM_SIZE {
+                        Some(format!("label `{s}` would be at {line}, past the end of ROM"))
+                    } else {
+                        self.labels.insert(s.to_string(), line as i16);
+                        None
+                    };
+                    if let Some(e) = error {
+                        diagnostics.push((index, Diagnostic::error(location.offset(1), e)));
+                    }
                 }
-            } else {
-                line += 1;
+                Asm::At(_) | Asm::Asm(_) => {
+                    // Only the first instruction that does not fit is reported, with the total
+                    if line == ROM_SIZE {
+                        let total = asm[index..]
+                            .iter()
+                            .filter(|line| matches!(line, Asm::At(_) | Asm::Asm(_)))
+                            .count()
+                            + line;
+                        let message = format!(
+                            "the program is {total} instructions long, but ROM only holds {ROM_SIZE}; this is the first one that does not fit"
+                        );
+                        diagnostics.push((index, Diagnostic::error(location.offset(1), message)));
+                    }
+                    line += 1;
+                }
+                // Comments do not take up any space in ROM
+                Asm::Comment(_) => {}
             }
         }
-        asm.iter()
-            .filter_map(|c| match c {
-                Asm::At(l) => l
-                    .parse::<i16>()
-                    .or_else(|_| i16::from_str_radix(l, 16))
-                    .ok()
-                    .or_else(|| self.get_label(l))
-                    .or_else(|| {
+
+        // How many times each implicit variable is used, and where it first was
+        let mut variables: HashMap<&str, (usize, usize)> = HashMap::new();
+        let mut rom = Vec::with_capacity(line);
+        for (index, (com, location)) in zip(asm, locations).enumerate() {
+            let symbol = match com {
+                Asm::At(symbol) => symbol,
+                Asm::Asm(i) => {
+                    rom.push(*i);
+                    continue;
+                }
+                _ => continue,
+            };
+            let value = match parse_constant(symbol) {
+                Some(value) => value,
+                None => check_symbol(symbol).and_then(|()| {
+                    if let Some(value) = self.get_label(symbol) {
+                        if let Some((uses, _)) = variables.get_mut(symbol.as_ref()) {
+                            *uses += 1;
+                        }
+                        Ok(value)
+                    } else if self.implicit_variables {
                         self.var_counter += 1;
-                        self.labels.insert(l.to_string(), self.var_counter);
-                        Some(self.var_counter)
-                    })
-                    .map(Instruction::from),
-                Asm::Asm(i) => Some(*i),
-                _ => None,
-            })
-            .collect()
+                        self.labels.insert(symbol.to_string(), self.var_counter);
+                        variables.insert(symbol.as_ref(), (1, index));
+                        Ok(self.var_counter)
+                    } else {
+                        Err(format!(
+                            "`{symbol}` is not defined, and implicit variables are turned off"
+                        ))
+                    }
+                }),
+            };
+            match value {
+                Ok(value) => rom.push(Instruction::from(value)),
+                Err(e) => {
+                    diagnostics.push((index, Diagnostic::error(location.offset(1), e)));
+                    rom.push(Instruction::from(0));
+                }
+            }
+        }
+
+        // A variable used only once is never actually shared with anything, so is more likely a misspelt label
+        for (name, &(uses, index)) in &variables {
+            if uses > 1 {
+                continue;
+            }
+            let closest = defined
+                .keys()
+                .filter_map(|label| similarity(name, label).map(|d| (d, *label)))
+                .min();
+            if let Some((_, label)) = closest {
+                let message = format!(
+                    "`{name}` is only used once, so it is a new variable; did you mean the label `{label}`?"
+                );
+                diagnostics.push((index, Diagnostic::warning(locations[index].offset(1), message)));
+            }
+        }
+
+        diagnostics.sort_by_key(|(index, _)| *index);
+        let diagnostics: Vec<_> = diagnostics.into_iter().map(|(_, d)| d).collect();
+        let diagnostics = Diagnostics(diagnostics);
+        if diagnostics.errors().next().is_some() {
+            self.warnings.clear();
+            Err(diagnostics)
+        } else {
+            self.warnings = diagnostics.0;
+            Ok(rom)
+        }
+    }
+}
+
+/// Parses an `@` value that is a number rather than a symbol, either decimal or `0x` hex, which has to fit in 15 bits.
+pub(crate) fn parse_constant(value: &str) -> Option<Result<i16, String>> {
+    let (digits, radix) = match value.strip_prefix("0x") {
+        Some(hex) => (hex, 16),
+        None if value.starts_with(|c: char| c.is_ascii_digit()) => (value, 10),
+        None => return None,
+    };
+    Some(match i16::from_str_radix(digits, radix) {
+        Ok(v) if v >= 0 && digits.bytes().all(|b| b.is_ascii_hexdigit()) => Ok(v),
+        _ => Err(format!("`{value}` is not a constant from 0 to 32767")),
+    })
+}
+
+/// Symbols are letters, digits, `_`, `.`, `$` and `:`, and cannot start with a digit.
+fn check_symbol(symbol: &str) -> Result<(), String> {
+    if symbol.is_empty() {
+        Err(String::from("missing a symbol or constant"))
+    } else if symbol.starts_with(|c: char| c.is_ascii_digit()) {
+        Err(format!("`{symbol}` is not a valid symbol, which cannot start with a digit"))
+    } else if !symbol.chars().all(is_symbol_char) {
+        Err(format!(
+            "`{symbol}` is not a valid symbol, which can only contain letters, digits, `_`, `.`, `$` and `:`"
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_.$:".contains(c)
+}
+
+/// How far apart two symbols are if they are close enough to be a typo of each other.
+///
+/// Case is ignored, and longer names are allowed more mistakes.
+fn similarity(a: &str, b: &str) -> Option<usize> {
+    let (a, b) = (a.to_ascii_lowercase(), b.to_ascii_lowercase());
+    let distance = edit_distance(&a, &b);
+    let allowed = match a.len().min(b.len()) {
+        0..=2 => 0,
+        3..=6 => 1,
+        _ => 2,
+    };
+    (distance <= allowed).then_some(distance)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<_> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = prev[j] + usize::from(ca != cb);
+            curr.push(substitute.min(prev[j + 1] + 1).min(curr[j] + 1));
+        }
+        prev = curr;
     }
+    prev[b.len()]
+}
+
+/// Hack assembly along with where each line came from, so that problems can be reported against the source.
+#[derive(Debug, Clone, Default)]
+pub struct AsmSource {
+    pub asm: Vec<Asm<'static>>,
+    pub locations: Vec<Location>,
+    /// Everything put in RAM by `.data` and `.string`, in the order they were written
+    pub data: Vec<DataBlock>,
+}
+
+/// Words that a `.data` or `.string` directive puts in RAM, starting at `addr`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataBlock {
+    pub addr: u16,
+    pub values: Vec<i16>,
 }
 
-fn parse_asm<'a>(path: impl AsRef<Path>) -> Vec<Asm<'a>> {
-    let mut asm = vec![];
-
-    if let Ok(f) = File::open(path) {
-        let reader = BufReader::new(f);
-        for line in reader.lines().flatten() {
-            match line.trim().split_once("//") {
-                Some(("", comment)) => asm.push(Asm::Comment(Cow::from(comment.trim().to_string()))),
-                Some((inst, comment)) => asm.push([
-                    Asm::Comment(Cow::from(comment.trim().to_string())),
-                    Asm::parse_c_instruction(inst)]),
+impl AsmSource {
+    /// Parses Hack assembly, keeping comments so they can be carried through to any listings.
+    ///
+    /// Macros, `.include`s and `.define`s are expanded along the way, with included files read from disk
+    /// relative to `file`. Every malformed line is reported, not just the first.
+    pub fn parse(file: &str, text: &str) -> Result<Self, Diagnostics> {
+        Self::parse_with(file, text, false, |path| std::fs::read_to_string(path))
+    }
+
+    /// Like [`AsmSource::parse`], but with `.include`d files read by `load`.
+    ///
+    /// With `preload_data`, `.data` and `.string` only end up in [`AsmSource::data`] for a RAM image,
+    /// instead of being assembled into code that writes them to RAM when the program starts.
+    pub fn parse_with(
+        file: &str,
+        text: &str,
+        preload_data: bool,
+        mut load: impl FnMut(&Path) -> std::io::Result<String>,
+    ) -> Result<Self, Diagnostics> {
+        let mut preprocessor = Preprocessor::new(&mut load);
+        preprocessor.preload_data = preload_data;
+        preprocessor.file(Path::new(file), text);
+        if preprocessor.errors.is_empty() {
+            Ok(preprocessor.source)
+        } else {
+            Err(Diagnostics(preprocessor.errors))
+        }
+    }
+
+    fn parse_line(inst: &str) -> Result<Asm<'static>> {
+        Ok(if let Some(label) = inst.strip_prefix('(') {
+            match label.strip_suffix(')') {
+                Some(label) => Asm::Label(Cow::Owned(label.to_string())),
+                None => bail!("unclosed label \"{inst}\""),
             }
+        } else if let Some(addr) = inst.strip_prefix('@') {
+            Asm::At(Cow::Owned(addr.to_string()))
+        } else {
+            Assembler::parse_c_instruction(inst)?.into_owned()
+        })
+    }
+
+    fn push(&mut self, asm: Asm<'static>, location: Location) {
+        self.asm.push(asm);
+        self.locations.push(location);
+    }
+
+    /// Adds another file on to the end of this one
+    pub fn extend(&mut self, other: Self) {
+        self.asm.extend(other.asm);
+        self.locations.extend(other.locations);
+        self.data.extend(other.data);
+    }
+
+    /// RAM from address 0 up to the end of the last data table, with later tables overwriting earlier ones
+    pub fn ram_image(&self) -> Vec<i16> {
+        let len = self
+            .data
+            .iter()
+            .map(|block| block.addr as usize + block.values.len())
+            .max()
+            .unwrap_or(0);
+        let mut ram = vec![0; len];
+        for block in &self.data {
+            let start = block.addr as usize;
+            ram[start..start + block.values.len()].copy_from_slice(&block.values);
         }
+        ram
     }
 }
+
+/// Reads and parses a Hack assembly file, see [`AsmSource::parse_with`] for `preload_data`.
+pub fn parse_asm(path: impl AsRef<Path>, preload_data: bool) -> Result<AsmSource> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    Ok(AsmSource::parse_with(
+        &path.display().to_string(),
+        &text,
+        preload_data,
+        |path| std::fs::read_to_string(path),
+    )?)
+}
+
 // fn write_bin() {
 //     let args: Vec<String> = std::env::args().collect();
 //     let filename = args[1].clone();
@@ -1365,3 +1700,139 @@ fn parse_asm<'a>(path: impl AsRef<Path>) -> Vec<Asm<'a>> {
 //         .unwrap_or(input)
 //         .replace(' ', "")
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(
+        src: &str,
+        implicit_variables: bool,
+    ) -> (Result<Vec<Instruction>, Diagnostics>, Vec<Diagnostic>) {
+        let source = AsmSource::parse("Test.asm", src).unwrap();
+        let mut assembler = Assembler::new();
+        assembler.implicit_variables = implicit_variables;
+        let result = assembler.assemble_source(&source);
+        (result, assembler.warnings)
+    }
+
+    #[test]
+    fn test_reports_every_malformed_line() {
+        let src = "@1\n  D=Q\n(LOOP\n  0;JMPP // comment\n";
+        let errors = AsmSource::parse("Test.asm", src).unwrap_err();
+        let errors: Vec<_> = errors.0.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            errors,
+            [
+                "Test.asm:2:3: error: 'Q' is not a computation the Hack ALU can do",
+                "Test.asm:3:1: error: unclosed label \"(LOOP\"",
+                "Test.asm:4:3: error: 'JMPP' is not a jump, expected JGT, JEQ, JGE, JLT, JNE, JLE or JMP",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reports_bad_symbols() {
+        let (result, _) = assemble(
+            "(LOOP)\n@40000\n@-1\n(LOOP)\n(SCREEN)\n@0x7FFF\n@12ab\n@LOOP\n0;JMP",
+            true,
+        );
+        let errors: Vec<_> = result.unwrap_err().0.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            errors,
+            [
+                "Test.asm:2:2: error: `40000` is not a constant from 0 to 32767",
+                "Test.asm:3:2: error: `-1` is not a valid symbol, which can only contain letters, digits, `_`, `.`, `$` and `:`",
+                "Test.asm:4:2: error: label `LOOP` is already defined at Test.asm:1:1",
+                "Test.asm:5:2: error: `SCREEN` is a built in symbol and cannot be redefined",
+                "Test.asm:7:2: error: `12ab` is not a constant from 0 to 32767",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_implicit_variables() {
+        let src = "@counter\nM=0\n@counter\nM=M+1\n@other\nM=1";
+        let (result, warnings) = assemble(src, true);
+        assert_eq!(
+            result.unwrap()[..5],
+            [16, -5496, 16, -568, 17].map(Instruction::from)
+        );
+        assert!(warnings.is_empty());
+
+        let (result, _) = assemble(src, false);
+        let errors = result.unwrap_err();
+        assert_eq!(errors.errors().count(), 3);
+        assert_eq!(
+            errors.0[0].to_string(),
+            "Test.asm:1:2: error: `counter` is not defined, and implicit variables are turned off"
+        );
+    }
+
+    #[test]
+    fn test_warns_about_likely_typos() {
+        let (result, warnings) = assemble(
+            "(Loop)\n@i\nM=M+1\n@loop\n0;JMP\n(END_GAME)\n@END_GMAE\n0;JMP\n@i\nM=0",
+            true,
+        );
+        assert!(result.is_ok());
+        let warnings: Vec<_> = warnings.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            warnings,
+            [
+                "Test.asm:4:2: warning: `loop` is only used once, so it is a new variable; did you mean the label `Loop`?",
+                "Test.asm:7:2: warning: `END_GMAE` is only used once, so it is a new variable; did you mean the label `END_GAME`?",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unofficial_computations() {
+        let parse = |c: &'static str| Assembler::parse_c_instruction(c).unwrap();
+        for (alias, bits) in [
+            ("D=D&!A", "D=X04"),
+            ("D=!D|A", "D=X05"),
+            ("D=-2", "D=X3E"),
+            ("M=!(D+A)", "M=X03"),
+            ("AM=~(D+M)", "AM=X43"),
+            ("D=D-M-1", "D=X46"),
+            ("D=!M-1;JLT", "D=X76;JLT"),
+            ("D=M+D+1", "D=X57"),
+        ] {
+            assert_eq!(parse(alias), parse(bits), "{alias}");
+        }
+        // Unofficial computations show up as their raw bits so that they assemble back the same
+        assert_eq!(parse("D=!(D|A)").to_string(), "D=X14");
+        for bad in ["D=X80", "D=X4", "D=X+1", "D=!D|M|A", "D=!(A+M)"] {
+            assert!(Assembler::parse_c_instruction(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_generated_code_locations() {
+        let asm = [Asm::at("SP"), Asm::Label("R0".into())];
+        let errors = Assembler::new().assemble(&asm).unwrap_err();
+        assert_eq!(
+            errors.to_string(),
+            "2:2: error: `R0` is a built in symbol and cannot be redefined"
+        );
+    }
+
+    #[test]
+    fn test_rom_overflow() {
+        let mut asm = vec![Asm::at("0"); ROM_SIZE];
+        asm.push(Asm::Label("END".into()));
+        assert_eq!(Assembler::new().assemble(&asm[..ROM_SIZE]).unwrap().len(), ROM_SIZE);
+
+        let errors = Assembler::new().assemble(&asm).unwrap_err();
+        assert_eq!(
+            errors.to_string(),
+            "32769:2: error: label `END` would be at 32768, past the end of ROM"
+        );
+        asm.extend([Asm::at("END"), Asm::at("0")]);
+        let errors = Assembler::new().assemble(&asm).unwrap_err();
+        assert!(errors.to_string().contains(
+            "32770:2: error: the program is 32770 instructions long, but ROM only holds 32768"
+        ));
+    }
+}
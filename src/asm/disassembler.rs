@@ -0,0 +1,155 @@
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+use anyhow::{anyhow, Result};
+
+use super::{Asm, CInstruction, Instruction, InstructionType, Jump, Mode};
+
+/// Turns a ROM back into assembly that assembles to exactly the same instructions.
+///
+/// Any address loaded right before a jump is treated as code and given an `L<addr>` label,
+/// `SCREEN` and `KBD` are always named, and `SP` through `THAT` are named when the next instruction goes through `M`.
+/// Unofficial computations come out in the `Xnn` syntax.
+pub fn disassemble(rom: &[Instruction]) -> Result<Vec<Asm<'static>>> {
+    let decoded = rom
+        .iter()
+        .enumerate()
+        .map(|(addr, inst)| {
+            inst.get()
+                .map_err(|raw| anyhow!("ROM[{addr}] ({raw:016b}) is not a Hack instruction"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let jumps_after = |addr: usize| match decoded.get(addr + 1) {
+        Some(InstructionType::C(c)) => c.jump() != Jump::Never,
+        _ => false,
+    };
+    let targets: BTreeSet<usize> = decoded
+        .iter()
+        .enumerate()
+        .filter_map(|(addr, inst)| match inst {
+            InstructionType::A(target) if jumps_after(addr) => Some(target.value() as usize),
+            _ => None,
+        })
+        // A label can go anywhere up to just past the last instruction
+        .filter(|&target| target <= rom.len())
+        .collect();
+
+    let mut asm = vec![];
+    for (addr, inst) in decoded.iter().enumerate() {
+        if targets.contains(&addr) {
+            asm.push(Asm::Label(Cow::Owned(label(addr))));
+        }
+        asm.push(match inst {
+            InstructionType::A(value) => {
+                let value = value.value() as usize;
+                let next = match decoded.get(addr + 1) {
+                    Some(InstructionType::C(c)) => Some(c),
+                    _ => None,
+                };
+                if jumps_after(addr) && targets.contains(&value) {
+                    Asm::At(Cow::Owned(label(value)))
+                } else if let Some(name) = register_name(value, next) {
+                    Asm::At(Cow::Borrowed(name))
+                } else {
+                    Asm::Asm(rom[addr])
+                }
+            }
+            InstructionType::C(_) => Asm::Asm(rom[addr]),
+        });
+    }
+    if targets.contains(&rom.len()) {
+        asm.push(Asm::Label(Cow::Owned(label(rom.len()))));
+    }
+    Ok(asm)
+}
+
+fn label(addr: usize) -> String {
+    format!("L{addr}")
+}
+
+/// The built in symbol for an address, if it is clear from the next instruction that it is being used as one
+fn register_name(addr: usize, next: Option<&CInstruction>) -> Option<&'static str> {
+    let through_m = next.is_some_and(|c| c.comp().mode() == Mode::M || c.dest().m());
+    match addr {
+        0x4000 => Some("SCREEN"),
+        0x6000 => Some("KBD"),
+        0..=4 if through_m => Some(["SP", "LCL", "ARG", "THIS", "THAT"][addr]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+
+    fn assemble(src: &str) -> Vec<Instruction> {
+        let asm: Vec<_> = src
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(|l| match l.strip_prefix('(') {
+                Some(label) => Asm::Label(label.trim_end_matches(')').into()),
+                None if l.starts_with('@') => Asm::at(l),
+                None => Assembler::parse_c_instruction(l).unwrap(),
+            })
+            .collect();
+        Assembler::new().assemble(&asm).unwrap()
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let rom = assemble(
+            "@0
+            D=A
+            @SP
+            M=D
+            (LOOP)
+            @SCREEN
+            D=A
+            @1
+            AM=M+1
+            M=X38
+            @LOOP
+            D;JGT
+            @17
+            0;JMP",
+        );
+        let text: Vec<_> = disassemble(&rom)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let expected = [
+            "@0", "D=A", "@SP", "M=D", "(L4)", "@SCREEN", "D=A", "@LCL", "AM=M+1", "M=X38", "@L4",
+            "D;JGT", "@17", "0;JMP",
+        ];
+        assert_eq!(text, expected);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let rom = &*crate::pong::PONG;
+        let asm = disassemble(rom).unwrap();
+        assert!(asm.iter().any(|line| matches!(line, Asm::Label(_))));
+        assert_eq!(&Assembler::new().assemble(&asm).unwrap(), rom);
+
+        // Every possible computation, including the unofficial ones
+        let rom: Vec<_> = (0..0x80)
+            .map(|comp| Instruction::from(-8192 | (comp << 6) | 0b011_010))
+            .collect();
+        let text: Vec<_> = disassemble(&rom)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(assemble(&text.join("\n")), rom);
+    }
+
+    #[test]
+    fn test_rejects_invalid_instructions() {
+        let rom = [Instruction::from(0), Instruction::from(-24576)];
+        assert!(disassemble(&rom).is_err());
+    }
+}
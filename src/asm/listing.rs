@@ -0,0 +1,373 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::iter::zip;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use super::diagnostic::Location;
+use super::{parse_constant, Asm, Assembler, Instruction};
+
+/// Where every instruction of an assembled program ended up in ROM, and what every symbol stood for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listing {
+    pub lines: Vec<ListingLine>,
+    /// Labels in the order they were defined, followed by variables in the order they were allocated
+    pub symbols: Vec<Symbol>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListingLine {
+    pub addr: usize,
+    pub instruction: Instruction,
+    /// The instruction as it was written, with any symbol left unresolved
+    pub source: String,
+    pub location: Location,
+    /// Any comments since the previous instruction, such as the VM command this one was translated from
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Label,
+    /// Allocated from RAM[16] onwards the first time an unknown `@symbol` was seen
+    Variable,
+}
+
+impl Display for SymbolKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SymbolKind::Label => f.pad("label"),
+            SymbolKind::Variable => f.pad("variable"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// A ROM address for labels, a RAM address for variables
+    pub value: i16,
+    /// Where a label was defined, or where a variable was first used
+    pub location: Location,
+}
+
+impl Assembler {
+    /// Lists a program this assembler has just assembled into `rom`, using the symbols it resolved.
+    ///
+    /// `locations` are the same ones the program was assembled with,
+    /// see [`Location::generated`] for assembly that never came from a file.
+    pub fn listing(&self, asm: &[Asm], locations: &[Location], rom: &[Instruction]) -> Listing {
+        let mut lines = vec![];
+        let mut labels = vec![];
+        let mut variables = vec![];
+        let defined: HashSet<&str> = asm
+            .iter()
+            .filter_map(|line| match line {
+                Asm::Label(name) => Some(name.as_ref()),
+                _ => None,
+            })
+            .collect();
+        let mut seen = HashSet::new();
+        let mut comment: Option<String> = None;
+
+        for (line, location) in zip(asm, locations) {
+            let addr = lines.len();
+            match line {
+                Asm::Comment(text) if text.is_empty() => {}
+                Asm::Comment(text) => match &mut comment {
+                    Some(comment) => {
+                        comment.push_str("; ");
+                        comment.push_str(text);
+                    }
+                    None => comment = Some(text.to_string()),
+                },
+                Asm::Label(name) => labels.push(Symbol {
+                    name: name.to_string(),
+                    kind: SymbolKind::Label,
+                    value: addr as i16,
+                    location: location.clone(),
+                }),
+                Asm::At(_) | Asm::Asm(_) => {
+                    if let Asm::At(name) = line {
+                        let name = name.as_ref();
+                        let variable = parse_constant(name).is_none()
+                            && Self::builtin(name).is_none()
+                            && !defined.contains(name);
+                        match self.labels.get(name) {
+                            Some(&value) if variable && seen.insert(name) => {
+                                variables.push(Symbol {
+                                    name: name.to_string(),
+                                    kind: SymbolKind::Variable,
+                                    value,
+                                    location: location.clone(),
+                                })
+                            }
+                            _ => {}
+                        }
+                    }
+                    lines.push(ListingLine {
+                        addr,
+                        instruction: rom[addr],
+                        source: line.to_string(),
+                        location: location.clone(),
+                        comment: comment.take(),
+                    });
+                }
+            }
+        }
+
+        labels.extend(variables);
+        Listing {
+            lines,
+            symbols: labels,
+        }
+    }
+}
+
+impl Listing {
+    /// Writes the listing as a table, with each label on its own line above the instruction it points to,
+    /// followed by the symbol map.
+    pub fn write_text(&self, mut out: impl Write) -> std::io::Result<()> {
+        let mut labels: HashMap<usize, Vec<&Symbol>> = HashMap::new();
+        for symbol in &self.symbols {
+            if symbol.kind == SymbolKind::Label {
+                labels
+                    .entry(symbol.value as usize)
+                    .or_default()
+                    .push(symbol);
+            }
+        }
+        let source_width = self.lines.iter().map(|l| l.source.len()).max().unwrap_or(0);
+        let location_width = self
+            .lines
+            .iter()
+            .map(|l| &l.location)
+            .chain(labels.values().flatten().map(|l| &l.location))
+            .map(|l| short_location(l).len())
+            .max()
+            .unwrap_or(0)
+            .max("Line".len());
+
+        let write_labels = |out: &mut dyn Write, addr: usize| {
+            for label in labels.get(&addr).into_iter().flatten() {
+                writeln!(
+                    out,
+                    "{:31}{:location_width$}  ({})",
+                    "",
+                    short_location(&label.location),
+                    label.name
+                )?;
+            }
+            std::io::Result::Ok(())
+        };
+
+        writeln!(
+            out,
+            "  ROM  Binary            Hex   {:location_width$}  Source",
+            "Line"
+        )?;
+        for line in &self.lines {
+            write_labels(&mut out, line.addr)?;
+            let source = match &line.comment {
+                Some(comment) => format!("{:source_width$}  // {comment}", line.source),
+                None => line.source.clone(),
+            };
+            writeln!(
+                out,
+                "{:>5}  {:b}  {:X}  {:location_width$}  {source}",
+                line.addr,
+                line.instruction,
+                line.instruction,
+                short_location(&line.location),
+            )?;
+        }
+        // Labels at the very end of the program point just past the last instruction
+        write_labels(&mut out, self.lines.len())?;
+
+        writeln!(out)?;
+        writeln!(out, "Symbols")?;
+        for symbol in &self.symbols {
+            writeln!(
+                out,
+                "  {:<8}  {:>5}  {}",
+                symbol.kind, symbol.value, symbol.name
+            )?;
+        }
+        out.flush()
+    }
+
+    /// Writes the listing as a JSON object with `instructions` and `symbols` arrays.
+    pub fn write_json(&self, mut out: impl Write) -> std::io::Result<()> {
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"instructions\": [")?;
+        for (i, line) in self.lines.iter().enumerate() {
+            let comment = match &line.comment {
+                Some(comment) => json_string(comment),
+                None => String::from("null"),
+            };
+            writeln!(
+                out,
+                "    {{\"address\": {}, \"binary\": \"{:b}\", \"hex\": \"{:X}\", \"source\": {}, \"file\": {}, \"line\": {}, \"comment\": {comment}}}{}",
+                line.addr,
+                line.instruction,
+                line.instruction,
+                json_string(&line.source),
+                json_string(&line.location.file),
+                line.location.line,
+                if i + 1 < self.lines.len() { "," } else { "" },
+            )?;
+        }
+        writeln!(out, "  ],")?;
+        writeln!(out, "  \"symbols\": [")?;
+        for (i, symbol) in self.symbols.iter().enumerate() {
+            writeln!(
+                out,
+                "    {{\"name\": {}, \"kind\": \"{}\", \"value\": {}, \"file\": {}, \"line\": {}}}{}",
+                json_string(&symbol.name),
+                symbol.kind,
+                symbol.value,
+                json_string(&symbol.location.file),
+                symbol.location.line,
+                if i + 1 < self.symbols.len() { "," } else { "" },
+            )?;
+        }
+        writeln!(out, "  ]")?;
+        writeln!(out, "}}")?;
+        out.flush()
+    }
+}
+
+/// Writes a listing as JSON if the file ends in `.json`, or as a table otherwise.
+pub fn write_listing(path: &Path, listing: &Listing) -> Result<()> {
+    let out = BufWriter::new(
+        File::create(path).with_context(|| format!("could not create {}", path.display()))?,
+    );
+    if path.extension().is_some_and(|e| e == "json") {
+        listing.write_json(out)
+    } else {
+        listing.write_text(out)
+    }
+    .with_context(|| format!("could not write {}", path.display()))
+}
+
+/// `file:line`, leaving out the column since whole lines are being listed
+fn short_location(location: &Location) -> String {
+    if location.file.is_empty() {
+        location.line.to_string()
+    } else {
+        format!("{}:{}", location.file, location.line)
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::AsmSource;
+
+    const SOURCE: &str = "// Counts down from 3
+@3
+D=A
+@count
+M=D // start
+(LOOP)
+@count
+MD=M-1
+@LOOP
+D;JGT
+(END)";
+
+    fn listing() -> Listing {
+        let source = AsmSource::parse("Count.asm", SOURCE).unwrap();
+        let mut assembler = Assembler::new();
+        let rom = assembler.assemble_source(&source).unwrap();
+        assembler.listing(&source.asm, &source.locations, &rom)
+    }
+
+    #[test]
+    fn test_listing() {
+        let listing = listing();
+        assert_eq!(listing.lines.len(), 8);
+        let first = &listing.lines[0];
+        assert_eq!(first.source, "@3");
+        assert_eq!(first.location.line, 2);
+        assert_eq!(first.comment.as_deref(), Some("Counts down from 3"));
+        assert_eq!(listing.lines[3].comment.as_deref(), Some("start"));
+        assert_eq!(listing.lines[4].source, "@count");
+        assert_eq!(listing.lines[4].instruction, Instruction::from(16));
+
+        let symbols: Vec<_> = listing
+            .symbols
+            .iter()
+            .map(|s| (s.name.as_str(), s.kind, s.value, s.location.line))
+            .collect();
+        assert_eq!(
+            symbols,
+            [
+                ("LOOP", SymbolKind::Label, 4, 6),
+                ("END", SymbolKind::Label, 8, 11),
+                ("count", SymbolKind::Variable, 16, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_text() {
+        let mut text = vec![];
+        listing().write_text(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines[0],
+            "  ROM  Binary            Hex   Line          Source"
+        );
+        assert_eq!(
+            lines[1],
+            "    0  0000000000000011  0003  Count.asm:2   @3      // Counts down from 3"
+        );
+        assert_eq!(
+            lines[5],
+            "                               Count.asm:6   (LOOP)"
+        );
+        assert_eq!(
+            lines[10],
+            "                               Count.asm:11  (END)"
+        );
+        assert!(text.ends_with(
+            "Symbols\n  label         4  LOOP\n  label         8  END\n  variable     16  count\n"
+        ));
+    }
+
+    #[test]
+    fn test_json() {
+        let mut json = vec![];
+        listing().write_json(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.contains(
+            r#"{"address": 3, "binary": "1110001100001000", "hex": "E308", "source": "M=D", "file": "Count.asm", "line": 5, "comment": "start"},"#
+        ));
+        assert!(json.contains(
+            r#"{"name": "count", "kind": "variable", "value": 16, "file": "Count.asm", "line": 4}"#
+        ));
+        assert_eq!(json_string("a \"b\"\\\n"), r#""a \"b\"\\\n""#);
+    }
+}
@@ -0,0 +1,663 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use asm_macro::asm;
+
+use super::diagnostic::{Diagnostic, Location};
+use super::{check_symbol, is_symbol_char, parse_constant, Asm, AsmSource, Assembler, DataBlock};
+
+/// How deeply macros can expand, or files include each other, before one is assumed to be using itself.
+const MAX_DEPTH: usize = 32;
+
+/// A `.macro NAME a, b` ... `.endm` block.
+struct Macro {
+    params: Vec<String>,
+    /// Labels defined in the body, which are renamed in each expansion so that the macro can be used more than once
+    labels: HashSet<String>,
+    body: Vec<(String, Location)>,
+}
+
+/// Expands macros, `.include`s, `.define`s and data tables while parsing hand-written assembly,
+/// so that the assembler itself only ever sees plain [`Asm`].
+///
+/// Expanded lines keep the location they were written at inside the macro,
+/// and each use of a macro or data directive is marked by a comment so that listings show where it came from.
+pub(super) struct Preprocessor<'l> {
+    load: &'l mut dyn FnMut(&Path) -> std::io::Result<String>,
+    defines: HashMap<String, String>,
+    macros: HashMap<String, Rc<Macro>>,
+    /// How many macros have been expanded so far, which keeps their local labels unique
+    expansions: usize,
+    /// The files currently being read, innermost last, to catch files that include themselves
+    including: Vec<PathBuf>,
+    /// Whether `.data` and `.string` are only recorded in [`AsmSource::data`], for a RAM image,
+    /// rather than also being turned into code that writes them
+    pub preload_data: bool,
+    pub source: AsmSource,
+    pub errors: Vec<Diagnostic>,
+}
+
+impl<'l> Preprocessor<'l> {
+    pub fn new(load: &'l mut dyn FnMut(&Path) -> std::io::Result<String>) -> Self {
+        Self {
+            load,
+            defines: HashMap::new(),
+            macros: HashMap::new(),
+            expansions: 0,
+            including: vec![],
+            preload_data: false,
+            source: AsmSource::default(),
+            errors: vec![],
+        }
+    }
+
+    pub fn file(&mut self, path: &Path, text: &str) {
+        let file: Arc<str> = Arc::from(path.display().to_string());
+        self.including.push(normalize(path));
+        // The name, if it was a valid one, and body of the macro currently being defined
+        let mut defining: Option<(Option<String>, Macro, Location)> = None;
+
+        for (line_no, line) in text.lines().enumerate() {
+            let code = line.split("//").next().unwrap_or_default();
+            let indent = code.len() - code.trim_start().len();
+            let location = Location::new(&file, line_no + 1, line[..indent].chars().count() + 1);
+            let code = code.trim();
+
+            if let Some((name, mut body, start)) = defining.take() {
+                if code == ".endm" {
+                    if let Some(name) = name {
+                        self.macros.insert(name, Rc::new(body));
+                    }
+                } else if directive(code, "macro").is_some() {
+                    self.error(&location, "macros cannot be defined inside other macros");
+                    defining = Some((name, body, start));
+                } else {
+                    if let Some(label) = code.strip_prefix('(') {
+                        let label = label.trim_end_matches(')').trim();
+                        body.labels.insert(label.to_string());
+                    }
+                    body.body.push((line.to_string(), location));
+                    defining = Some((name, body, start));
+                }
+            } else if let Some(header) = directive(code, "macro") {
+                let (name, params) = match self.macro_header(header, &location) {
+                    Some((name, params)) => (Some(name), params),
+                    None => (None, vec![]),
+                };
+                let body = Macro {
+                    params,
+                    labels: HashSet::new(),
+                    body: vec![],
+                };
+                defining = Some((name, body, location));
+            } else {
+                self.line(line, &location, 0);
+            }
+        }
+
+        if let Some((name, _, start)) = defining {
+            let name = name.unwrap_or_default();
+            self.error(
+                &start,
+                format!("`.macro {name}` is never closed with `.endm`"),
+            );
+        }
+        self.including.pop();
+    }
+
+    /// Checks `NAME a, b` from the start of a macro, returning the name and parameters if they are valid.
+    fn macro_header(&mut self, header: &str, location: &Location) -> Option<(String, Vec<String>)> {
+        let (name, params) = header
+            .split_once(char::is_whitespace)
+            .unwrap_or((header, ""));
+        let params: Vec<String> = split_args(params).into_iter().map(String::from).collect();
+        let error = if let Err(e) = check_symbol(name) {
+            Some(e)
+        } else if self.macros.contains_key(name) {
+            Some(format!("macro `{name}` is already defined"))
+        } else {
+            params.iter().find_map(|param| check_symbol(param).err())
+        };
+        match error {
+            Some(e) => {
+                self.error(location, e);
+                None
+            }
+            None => Some((name.to_string(), params)),
+        }
+    }
+
+    /// Handles a single line outside of a macro definition, or one from the body of a macro being expanded.
+    fn line(&mut self, line: &str, at: &Location, depth: usize) {
+        let column = |byte: usize| line[..byte].chars().count() + 1;
+        let (inst, comment) = match line.split_once("//") {
+            Some((inst, comment)) => (inst, Some(comment.trim())),
+            None => (line, None),
+        };
+        if let Some(comment) = comment {
+            let location = Location::new(&at.file, at.line, column(inst.len()));
+            self.source
+                .push(Asm::Comment(Cow::Owned(comment.to_string())), location);
+        }
+        let Some(start) = inst.find(|c: char| !c.is_whitespace()) else {
+            return;
+        };
+        let location = Location::new(&at.file, at.line, column(start));
+        let inst = inst.trim();
+
+        if let Some(directive) = inst.strip_prefix('.') {
+            return self.directive(directive, &location);
+        }
+        let (word, args) = inst.split_once(char::is_whitespace).unwrap_or((inst, ""));
+        if let Some(mac) = self.macros.get(word).cloned() {
+            return self.expand(word, &mac, args, &location, depth);
+        }
+
+        // Whitespace is insignificant inside an instruction
+        let inst: String = inst.chars().filter(|c| !c.is_whitespace()).collect();
+        let inst = match inst
+            .strip_prefix('@')
+            .and_then(|name| self.defines.get(name))
+        {
+            Some(value) => format!("@{value}"),
+            None => inst,
+        };
+        match AsmSource::parse_line(&inst) {
+            Ok(asm) => self.source.push(asm, location),
+            Err(e) => self.error(&location, e.to_string()),
+        }
+    }
+
+    fn directive(&mut self, directive: &str, location: &Location) {
+        let (name, args) = directive
+            .split_once(char::is_whitespace)
+            .unwrap_or((directive, ""));
+        let args = args.trim();
+        match name {
+            "define" => self.define(args, location),
+            "include" => self.include(args, location),
+            "data" => self.data(args, location),
+            "string" => self.string(args, location),
+            "macro" => self.error(location, "macros cannot be defined inside other macros"),
+            "endm" => self.error(location, "`.endm` without a `.macro` to end"),
+            _ => self.error(
+                location,
+                format!(
+                    "unknown directive `.{name}`, expected .define, .include, .macro, .endm, .data or .string"
+                ),
+            ),
+        }
+    }
+
+    /// `.define NAME value` replaces every later `@NAME` with `@value`.
+    fn define(&mut self, args: &str, location: &Location) {
+        let mut parts = args.split_whitespace();
+        let (Some(name), Some(value), None) = (parts.next(), parts.next(), parts.next()) else {
+            return self.error(location, "expected `.define NAME value`");
+        };
+        if let Err(e) = check_symbol(name) {
+            self.error(location, e);
+        } else if Assembler::builtin(name).is_some() {
+            self.error(
+                location,
+                format!("`{name}` is a built in symbol and cannot be redefined"),
+            );
+        } else if self.defines.contains_key(name) {
+            self.error(location, format!("`{name}` is already defined"));
+        } else {
+            self.defines.insert(name.to_string(), value.to_string());
+        }
+    }
+
+    /// `.include "file.asm"` reads another file in place, relative to the one including it.
+    fn include(&mut self, args: &str, location: &Location) {
+        let Some(name) = args
+            .strip_prefix('"')
+            .and_then(|name| name.strip_suffix('"'))
+        else {
+            return self.error(location, "expected `.include \"file.asm\"`");
+        };
+        let dir = Path::new(&*location.file).parent().unwrap_or(Path::new(""));
+        let path = normalize(&dir.join(name));
+        if self.including.contains(&path) {
+            return self.error(location, format!("{} includes itself", path.display()));
+        }
+        // Such as through a link, which the paths alone do not give away
+        if self.including.len() >= MAX_DEPTH {
+            return self.error(
+                location,
+                format!("includes are nested more than {MAX_DEPTH} deep"),
+            );
+        }
+        match (self.load)(&path) {
+            Ok(text) => self.file(&path, &text),
+            Err(e) => self.error(location, format!("could not read {}: {e}", path.display())),
+        }
+    }
+
+    /// `.data ADDR 1, -2, 0x3F` puts the values in RAM from `ADDR` onwards.
+    fn data(&mut self, args: &str, location: &Location) {
+        let (addr, values) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        let addr = self.address(addr, location);
+        let values = split_args(values);
+        if values.is_empty() {
+            return self.error(location, "expected `.data ADDR value, ...`");
+        }
+        let mut words = vec![];
+        for &value in &values {
+            let value = self.defines.get(value).map_or(value, String::as_str);
+            match parse_word(value) {
+                Some(word) => words.push(word),
+                None => self.error(
+                    location,
+                    format!("`{value}` is not a 16 bit value, expected -32768 to 32767 or 0x0000 to 0xFFFF"),
+                ),
+            }
+        }
+        match addr {
+            Some(addr) if words.len() == values.len() => {
+                self.data_block(addr, words, &format!(".data {args}"), location)
+            }
+            _ => {}
+        }
+    }
+
+    /// `.string ADDR "text"` puts the Hack character codes of the text in RAM from `ADDR` onwards, followed by a 0.
+    ///
+    /// `\n` is the Hack newline (128), and `\"` and `\\` are a quote and a backslash.
+    fn string(&mut self, args: &str, location: &Location) {
+        let (addr, text) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        let addr = self.address(addr, location);
+        let Some(text) = text
+            .trim()
+            .strip_prefix('"')
+            .and_then(|t| t.strip_suffix('"'))
+        else {
+            return self.error(location, "expected `.string ADDR \"text\"`");
+        };
+        let mut words = vec![];
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            let c = match c {
+                '\\' => match chars.next() {
+                    Some('n') => '\u{80}',
+                    Some(c @ ('"' | '\\')) => c,
+                    _ => return self.error(location, "unknown escape, expected \\n, \\\" or \\\\"),
+                },
+                ' '..='~' => c,
+                c => {
+                    return self.error(location, format!("'{c}' is not in the Hack character set"))
+                }
+            };
+            words.push(c as i16);
+        }
+        words.push(0);
+        if let Some(addr) = addr {
+            self.data_block(addr, words, &format!(".string {args}"), location);
+        }
+    }
+
+    /// Where a data directive starts, which has to be known before assembly so has to be a constant,
+    /// a built in symbol or a `.define`.
+    fn address(&mut self, addr: &str, location: &Location) -> Option<i16> {
+        let addr = self.defines.get(addr).map_or(addr, String::as_str);
+        let value = match Assembler::builtin(addr) {
+            Some(value) => Ok(value),
+            None => parse_constant(addr).unwrap_or_else(|| {
+                Err(format!(
+                    "`{addr}` is not a constant, a data address has to be a number, a built in symbol or a .define"
+                ))
+            }),
+        };
+        value.map_err(|e| self.error(location, e)).ok()
+    }
+
+    fn data_block(&mut self, addr: i16, values: Vec<i16>, directive: &str, location: &Location) {
+        if addr as usize + values.len() > 0x8000 {
+            return self.error(
+                location,
+                "the data runs past the end of addressable RAM at 32767",
+            );
+        }
+        if !self.preload_data {
+            self.source.push(
+                Asm::Comment(Cow::Owned(directive.to_string())),
+                location.clone(),
+            );
+            // The value currently in D, which saves loading it again for repeats
+            let mut d = None;
+            for (addr, &value) in (addr..).zip(&values) {
+                let store = match value {
+                    -1 => asm!(M = -1),
+                    0 => asm!(M = 0),
+                    1 => asm!(M = 1),
+                    _ => {
+                        if d != Some(value) {
+                            // Negative values are loaded as their complement, which always fits in an A instruction
+                            let load = match value {
+                                0.. => [Asm::from(value), asm!(D = A)],
+                                _ => [Asm::from(!value), asm!(D = !A)],
+                            };
+                            for asm in load {
+                                self.source.push(asm, location.clone());
+                            }
+                            d = Some(value);
+                        }
+                        asm!(M = D)
+                    }
+                };
+                self.source.push(Asm::from(addr), location.clone());
+                self.source.push(store, location.clone());
+            }
+        }
+        self.source.data.push(DataBlock {
+            addr: addr as u16,
+            values,
+        });
+    }
+
+    fn expand(&mut self, name: &str, mac: &Macro, args: &str, location: &Location, depth: usize) {
+        if depth == MAX_DEPTH {
+            return self.error(
+                location,
+                format!("macro `{name}` is nested too deeply, does it use itself?"),
+            );
+        }
+        let args = split_args(args);
+        if args.len() != mac.params.len() {
+            return self.error(
+                location,
+                format!(
+                    "macro `{name}` takes {} arguments but was given {}",
+                    mac.params.len(),
+                    args.len()
+                ),
+            );
+        }
+
+        self.expansions += 1;
+        let unique = format!("{name}${}$", self.expansions);
+        let text = format!("{name} {}", args.join(", "));
+        self.source.push(
+            Asm::Comment(Cow::Owned(text.trim_end().to_string())),
+            location.clone(),
+        );
+        for (line, at) in &mac.body {
+            let line = substitute(line, |token| {
+                if let Some(i) = mac.params.iter().position(|p| p == token) {
+                    Some(args[i].to_string())
+                } else if mac.labels.contains(token) {
+                    Some(format!("{unique}{token}"))
+                } else {
+                    None
+                }
+            });
+            self.line(&line, at, depth + 1);
+        }
+    }
+
+    fn error(&mut self, location: &Location, message: impl Into<String>) {
+        self.errors
+            .push(Diagnostic::error(location.clone(), message));
+    }
+}
+
+/// The arguments of a directive if `code` is that directive, which has to be followed by whitespace or nothing.
+fn directive<'t>(code: &'t str, name: &str) -> Option<&'t str> {
+    let args = code.strip_prefix('.')?.strip_prefix(name)?;
+    (args.is_empty() || args.starts_with(char::is_whitespace)).then(|| args.trim())
+}
+
+/// A decimal number from -32768 to 32767, or `0x` hex up to `0xFFFF` for any bit pattern
+fn parse_word(value: &str) -> Option<i16> {
+    match value.strip_prefix("0x") {
+        Some(hex) if hex.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            u16::from_str_radix(hex, 16).ok().map(|v| v as i16)
+        }
+        Some(_) => None,
+        None => value.parse().ok(),
+    }
+}
+
+/// Removes the `.` and `..` parts of a path where it can, so that the same file is always named the same way
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for part in path.components() {
+        match part {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(normal.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                normal.pop();
+            }
+            _ => normal.push(part),
+        }
+    }
+    normal
+}
+
+/// Comma separated arguments, where nothing at all is no arguments rather than one empty one
+fn split_args(args: &str) -> Vec<&str> {
+    if args.trim().is_empty() {
+        vec![]
+    } else {
+        args.split(',').map(str::trim).collect()
+    }
+}
+
+/// Replaces whole symbols in a line, leaving everything around them as it was.
+fn substitute(line: &str, replace: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while !rest.is_empty() {
+        let end = rest.find(|c| !is_symbol_char(c)).unwrap_or(rest.len());
+        let (token, after) = rest.split_at(end);
+        match replace(token) {
+            Some(replacement) if !token.is_empty() => out.push_str(&replacement),
+            _ => out.push_str(token),
+        }
+        let mut chars = after.chars();
+        if let Some(c) = chars.next() {
+            out.push(c);
+        }
+        rest = chars.as_str();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(files: &[(&str, &str)]) -> Result<AsmSource, Vec<String>> {
+        let files: HashMap<PathBuf, String> = files
+            .iter()
+            .map(|(name, text)| (PathBuf::from(name), text.to_string()))
+            .collect();
+        let (main, text) = files.iter().find(|(p, _)| p.ends_with("Main.asm")).unwrap();
+        let mut load = |path: &Path| {
+            files
+                .get(path)
+                .cloned()
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))
+        };
+        let mut pre = Preprocessor::new(&mut load);
+        pre.file(main, text);
+        if pre.errors.is_empty() {
+            Ok(pre.source)
+        } else {
+            Err(pre.errors.iter().map(ToString::to_string).collect())
+        }
+    }
+
+    fn code(source: &AsmSource) -> Vec<String> {
+        source
+            .asm
+            .iter()
+            .filter(|asm| !matches!(asm, Asm::Comment(_)))
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_macros() {
+        let source = parse(&[(
+            "Main.asm",
+            ".define COUNT 3
+            .macro PUSH_D
+            @SP
+            AM=M+1
+            A=A-1
+            M=D
+            .endm
+            .macro REPEAT from, times
+            @times
+            D=A
+            (LOOP)
+            @from // the address
+            M=M+1
+            D=D-1
+            @LOOP
+            D;JGT
+            PUSH_D
+            .endm
+            REPEAT R5, COUNT
+            REPEAT R6, 2",
+        )])
+        .unwrap();
+        let code = code(&source);
+        assert_eq!(
+            &code[..12],
+            [
+                "@3",
+                "D=A",
+                "(REPEAT$1$LOOP)",
+                "@R5",
+                "M=M+1",
+                "D=D-1",
+                "@REPEAT$1$LOOP",
+                "D;JGT",
+                "@SP",
+                "AM=M+1",
+                "A=A-1",
+                "M=D"
+            ]
+        );
+        assert_eq!(code[14], "(REPEAT$3$LOOP)");
+        assert_eq!(code.len(), 24);
+
+        // Each use is marked, and expanded lines point back into the macro
+        assert_eq!(source.asm[0], Asm::Comment("REPEAT R5, COUNT".into()));
+        let at = source
+            .asm
+            .iter()
+            .position(|a| a.to_string() == "@R5")
+            .unwrap();
+        assert_eq!(source.locations[at].to_string(), "Main.asm:12:13");
+        assert_eq!(source.asm[at - 1], Asm::Comment("the address".into()));
+    }
+
+    #[test]
+    fn test_include() {
+        let source = parse(&[
+            ("dir/Main.asm", ".include \"lib/Macros.asm\"\nINC R7"),
+            (
+                "dir/lib/Macros.asm",
+                "// Adds one\n.macro INC addr\n@addr\nM=M+1\n.endm",
+            ),
+        ])
+        .unwrap();
+        assert_eq!(code(&source), ["@R7", "M=M+1"]);
+        assert_eq!(source.locations[0].to_string(), "dir/lib/Macros.asm:1:1");
+
+        let errors = parse(&[(
+            "Main.asm",
+            ".include \"Main.asm\"\n.include \"Missing.asm\"",
+        )])
+        .unwrap_err();
+        assert_eq!(errors[0], "Main.asm:1:1: error: Main.asm includes itself");
+        assert!(errors[1].starts_with("Main.asm:2:1: error: could not read Missing.asm"));
+
+        // The same file is found however the path to it is written
+        let errors = parse(&[("dir/Main.asm", ".include \"../dir/./Main.asm\"")]).unwrap_err();
+        assert_eq!(
+            errors,
+            ["dir/Main.asm:1:1: error: dir/Main.asm includes itself"]
+        );
+    }
+
+    #[test]
+    fn test_data() {
+        let text = ".define TABLE 100
+            .data TABLE 5, 5, -1, -300, 0xFFFE
+            .string SCREEN \"A\\n\"";
+        let source = parse(&[("Main.asm", text)]).unwrap();
+        assert_eq!(
+            code(&source),
+            [
+                "@5", "D=A", "@100", "M=D", "@101", "M=D", "@102", "M=-1", "@299", "D=!A", "@103",
+                "M=D", "@1", "D=!A", "@104", "M=D", "@65", "D=A", "@16384", "M=D", "@128", "D=A",
+                "@16385", "M=D", "@16386", "M=0"
+            ]
+        );
+
+        // Preloading leaves out the code, but keeps the data for a RAM image
+        let preloaded = AsmSource::parse_with("Main.asm", text, true, |_| unreachable!()).unwrap();
+        assert!(preloaded.asm.is_empty());
+        assert_eq!(preloaded.data, source.data);
+        assert_eq!(
+            preloaded.data[1],
+            DataBlock {
+                addr: 16384,
+                values: vec![65, 128, 0]
+            }
+        );
+        let ram = preloaded.ram_image();
+        assert_eq!(ram.len(), 16387);
+        assert_eq!(ram[100..105], [5, 5, -1, -300, -2]);
+
+        let errors = parse(&[(
+            "Main.asm",
+            ".data x 1\n.data 32767 1, 2\n.data 0 65536\n.string 0 \"\u{e9}\"",
+        )])
+        .unwrap_err();
+        assert_eq!(errors.len(), 4);
+        assert!(errors[0].contains("`x` is not a constant"));
+        assert!(errors[1].contains("past the end of addressable RAM"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let errors = parse(&[(
+            "Main.asm",
+            ".define X 1
+            .define X 2
+            .macro M1 a
+            @a
+            .endm
+            M1
+            .macro LOOP
+            LOOP
+            .endm
+            LOOP
+            .endm
+            .rept 3
+            .macro NEVER",
+        )])
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            [
+                "Main.asm:2:13: error: `X` is already defined",
+                "Main.asm:6:13: error: macro `M1` takes 1 arguments but was given 0",
+                "Main.asm:8:13: error: macro `LOOP` is nested too deeply, does it use itself?",
+                "Main.asm:11:13: error: `.endm` without a `.macro` to end",
+                "Main.asm:12:13: error: unknown directive `.rept`, expected .define, .include, .macro, .endm, .data or .string",
+                "Main.asm:13:13: error: `.macro NEVER` is never closed with `.endm`",
+            ]
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+
+use super::Instruction;
+
+/// The ways a ROM image can be stored on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RomFormat {
+    /// The official `.hack` text format, one instruction per line as 16 ASCII `0`s and `1`s
+    Hack,
+    /// Raw 16 bit words, least significant byte first
+    #[value(name = "le")]
+    LittleEndian,
+    /// Raw 16 bit words, most significant byte first
+    #[value(name = "be")]
+    BigEndian,
+}
+
+impl RomFormat {
+    /// `.hack` files are text and anything else is assumed to be a little-endian image,
+    /// which is what a ROM dumped straight from memory on most machines looks like.
+    pub fn from_path(path: &Path) -> Self {
+        if path.extension().is_some_and(|e| e == "hack") {
+            Self::Hack
+        } else {
+            Self::LittleEndian
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Hack => "hack",
+            Self::LittleEndian | Self::BigEndian => "bin",
+        }
+    }
+}
+
+/// Parses the contents of a `.hack` file.
+///
+/// Blank lines are skipped, but every other line has to be exactly 16 binary digits, as the official tools require.
+pub fn parse_hack(text: &str) -> Result<Vec<Instruction>> {
+    text.lines()
+        .enumerate()
+        .map(|(line, l)| (line + 1, l.trim()))
+        .filter(|(_, l)| !l.is_empty())
+        .map(|(line, l)| {
+            if l.len() != 16 || !l.bytes().all(|b| b == b'0' || b == b'1') {
+                bail!("line {line}: \"{l}\" is not 16 binary digits");
+            }
+            // Every 16 digit binary string fits, the top bit just ends up as the sign
+            Ok(Instruction::from(u16::from_str_radix(l, 2)? as i16))
+        })
+        .collect()
+}
+
+/// Formats a ROM as the contents of a `.hack` file.
+pub fn to_hack(rom: &[Instruction]) -> String {
+    rom.iter().map(|inst| format!("{inst:b}\n")).collect()
+}
+
+/// Reads a raw ROM image, two bytes per instruction.
+pub fn from_bytes(bytes: &[u8], format: RomFormat) -> Result<Vec<Instruction>> {
+    if !bytes.len().is_multiple_of(2) {
+        bail!(
+            "a binary ROM image has to be a whole number of 16 bit words, but is {} bytes",
+            bytes.len()
+        );
+    }
+    Ok(bytes
+        .chunks_exact(2)
+        .map(|word| {
+            let word = [word[0], word[1]];
+            Instruction::from(match format {
+                RomFormat::BigEndian => i16::from_be_bytes(word),
+                _ => i16::from_le_bytes(word),
+            })
+        })
+        .collect())
+}
+
+/// Writes a ROM as a raw image, two bytes per instruction.
+pub fn to_bytes(rom: &[Instruction], format: RomFormat) -> Vec<u8> {
+    rom.iter()
+        .flat_map(|inst| match format {
+            RomFormat::BigEndian => inst.raw_value().to_be_bytes(),
+            _ => inst.raw_value().to_le_bytes(),
+        })
+        .collect()
+}
+
+/// Reads a ROM in the given format, or the one implied by the file's extension.
+pub fn read_rom(path: &Path, format: Option<RomFormat>) -> Result<Vec<Instruction>> {
+    let format = format.unwrap_or_else(|| RomFormat::from_path(path));
+    let bytes =
+        std::fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+    match format {
+        RomFormat::Hack => parse_hack(&String::from_utf8_lossy(&bytes)),
+        _ => from_bytes(&bytes, format),
+    }
+    .with_context(|| format!("could not load {}", path.display()))
+}
+
+/// Writes a ROM in the given format, or the one implied by the file's extension.
+pub fn write_rom(path: &Path, rom: &[Instruction], format: Option<RomFormat>) -> Result<()> {
+    let format = format.unwrap_or_else(|| RomFormat::from_path(path));
+    let bytes = match format {
+        RomFormat::Hack => to_hack(rom).into_bytes(),
+        _ => to_bytes(rom, format),
+    };
+    std::fs::write(path, bytes).with_context(|| format!("could not write {}", path.display()))
+}
+
+/// Reads a `.hack` file, one 16 digit binary instruction per line.
+pub fn read_hack(path: impl AsRef<Path>) -> Result<Vec<Instruction>> {
+    read_rom(path.as_ref(), Some(RomFormat::Hack))
+}
+
+/// Reads a RAM image, raw little-endian words from address 0 onwards, as written by [`write_ram_image`].
+pub fn read_ram_image(path: &Path) -> Result<Vec<i16>> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+    if !bytes.len().is_multiple_of(2) || bytes.len() > 0x8000 * 2 {
+        bail!(
+            "{} is not a RAM image, which has to be a whole number of 16 bit words up to 32K of them",
+            path.display()
+        );
+    }
+    Ok(bytes
+        .chunks_exact(2)
+        .map(|word| i16::from_le_bytes([word[0], word[1]]))
+        .collect())
+}
+
+/// Writes RAM from address 0 onwards as raw little-endian words, the same as a little-endian ROM image.
+pub fn write_ram_image(path: &Path, ram: &[i16]) -> Result<()> {
+    let bytes: Vec<u8> = ram.iter().flat_map(|word| word.to_le_bytes()).collect();
+    std::fs::write(path, bytes).with_context(|| format!("could not write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: [i16; 4] = [0x4000, -5616, 0x0006, -7416];
+
+    #[test]
+    fn test_hack_round_trip() {
+        let rom = ROM.map(Instruction::from);
+        let text = to_hack(&rom);
+        assert_eq!(text.lines().next(), Some("0100000000000000"));
+        assert_eq!(text.lines().nth(1), Some("1110101000010000"));
+        assert_eq!(parse_hack(&text).unwrap(), rom);
+        // Windows line endings and trailing blank lines are fine
+        assert_eq!(parse_hack(&text.replace('\n', "\r\n\n")).unwrap(), rom);
+    }
+
+    #[test]
+    fn test_rejects_malformed_hack() {
+        let err = parse_hack("0100000000000000\n\n010000000000000\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 3: \"010000000000000\" is not 16 binary digits"
+        );
+        assert!(parse_hack("0100000000000002").is_err());
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let rom = ROM.map(Instruction::from);
+        let le = to_bytes(&rom, RomFormat::LittleEndian);
+        let be = to_bytes(&rom, RomFormat::BigEndian);
+        assert_eq!(&le[..4], [0x00, 0x40, 0x10, 0xEA]);
+        assert_eq!(&be[..4], [0x40, 0x00, 0xEA, 0x10]);
+        assert_eq!(from_bytes(&le, RomFormat::LittleEndian).unwrap(), rom);
+        assert_eq!(from_bytes(&be, RomFormat::BigEndian).unwrap(), rom);
+        assert!(from_bytes(&le[..3], RomFormat::LittleEndian).is_err());
+    }
+}
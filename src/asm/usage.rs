@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::iter::zip;
+
+use anyhow::{bail, Result};
+
+use super::diagnostic::Location;
+use super::{Asm, ROM_SIZE};
+
+/// How much of ROM a program takes up, and which of its parts take the most.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomUsage {
+    pub total: usize,
+    /// Each part with its instruction count, largest first
+    pub parts: Vec<(String, usize)>,
+}
+
+impl RomUsage {
+    /// Adds up `(part, instructions)` pairs, where the same part may come up more than once.
+    pub fn new(sizes: impl IntoIterator<Item = (String, usize)>) -> Self {
+        let mut totals: HashMap<String, usize> = HashMap::new();
+        for (part, instructions) in sizes {
+            *totals.entry(part).or_default() += instructions;
+        }
+        let mut parts: Vec<_> = totals.into_iter().collect();
+        parts.sort_by(|(a, a_size), (b, b_size)| b_size.cmp(a_size).then(a.cmp(b)));
+        Self {
+            total: parts.iter().map(|(_, size)| size).sum(),
+            parts,
+        }
+    }
+
+    /// How many instructions of `asm` came from each source file.
+    pub fn by_file(asm: &[Asm], locations: &[Location]) -> Self {
+        Self::new(
+            zip(asm, locations)
+                .filter(|(line, _)| matches!(line, Asm::At(_) | Asm::Asm(_)))
+                .map(|(_, location)| (location.file.to_string(), 1)),
+        )
+    }
+
+    /// Fails if the program does not fit in ROM, naming the parts most worth making smaller.
+    pub fn check(&self) -> Result<()> {
+        if self.total <= ROM_SIZE {
+            return Ok(());
+        }
+        let largest: Vec<_> = self
+            .parts
+            .iter()
+            .take(3)
+            .map(|(part, size)| format!("{part} ({size})"))
+            .collect();
+        bail!(
+            "the program is {} instructions long, but ROM only holds {ROM_SIZE}; the largest parts are {}",
+            self.total,
+            largest.join(", ")
+        )
+    }
+
+    /// Writes a table of the parts, largest first, under a line with the total.
+    pub fn write(&self, mut out: impl Write, part: &str) -> std::io::Result<()> {
+        let percent = |size: usize| size as f64 * 100.0 / ROM_SIZE as f64;
+        writeln!(
+            out,
+            "{} of {ROM_SIZE} instructions used ({:.1}%)",
+            self.total,
+            percent(self.total)
+        )?;
+        writeln!(out, "  Instructions    ROM  {part}")?;
+        for (name, size) in &self.parts {
+            writeln!(out, "  {size:>12}  {:>4.1}%  {name}", percent(*size))?;
+        }
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::AsmSource;
+
+    #[test]
+    fn test_usage() {
+        let mut source = AsmSource::parse("Main.asm", "@1\nD=A\n(LOOP)\n@LOOP\n0;JMP").unwrap();
+        source.extend(AsmSource::parse("Lib.asm", "// nothing but a comment\n@2\nD=A").unwrap());
+        let usage = RomUsage::by_file(&source.asm, &source.locations);
+        assert_eq!(usage.total, 6);
+        assert_eq!(
+            usage.parts,
+            [("Main.asm".to_string(), 4), ("Lib.asm".to_string(), 2)]
+        );
+        assert!(usage.check().is_ok());
+
+        let mut text = vec![];
+        usage.write(&mut text, "File").unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "6 of 32768 instructions used (0.0%)
+  Instructions    ROM  File
+             4   0.0%  Main.asm
+             2   0.0%  Lib.asm
+"
+        );
+
+        let usage = RomUsage::new([
+            ("Main.main".to_string(), 30000),
+            ("Sys.init".to_string(), 3000),
+        ]);
+        let error = usage.check().unwrap_err().to_string();
+        assert!(error.contains("33000 instructions long"));
+        assert!(error.contains("Main.main (30000), Sys.init (3000)"));
+    }
+}
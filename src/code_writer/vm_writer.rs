@@ -1,43 +1,35 @@
-use std::{
-    fmt::Display,
-    fs::File,
-    io::{BufWriter, Write},
-    path::Path,
-};
+use std::fmt::{Display, Write};
 
 use super::CodeWriter;
 use crate::tokens::jack_tokens::{Keyword::*, Token};
-use crate::tokens::vm_commands::{MemSegment as Seg, VmCommand};
+use crate::vm::{MemSegment as Seg, VmCommand};
 
+/// Collects the VM code for a single class, one command per line.
 #[derive(Default)]
 pub struct VmWriter {
-    writer: Option<BufWriter<File>>,
+    output: String,
     if_counter: u16,
     while_counter: u16,
+    /// The Jack line the commands being written come from
+    pub line: usize,
+    /// The Jack line of each command written so far
+    lines: Vec<usize>,
 }
 
 impl CodeWriter for VmWriter {
-    fn new(filename: &str) -> Self {
-        let file =
-            File::create(Path::new(filename).with_extension("vm")).expect("could not create file");
-        let writer = BufWriter::new(file);
-        VmWriter {
-            writer: Some(writer),
-            if_counter: 0,
-            while_counter: 0,
-        }
-    }
-
     fn write(&mut self, contents: impl Display) {
-        writeln!(self.writer.as_mut().expect("no writer"), "{contents}").expect("failed to write");
-    }
-
-    fn flush(&mut self) {
-        self.writer.as_mut().expect("no writer").flush().unwrap();
+        // Writing to a String cannot fail
+        let _ = writeln!(self.output, "{contents}");
+        self.lines.push(self.line);
     }
 }
 
 impl VmWriter {
+    /// The VM code, and the Jack line each line of it came from
+    pub fn finish(self) -> (String, Vec<usize>) {
+        (self.output, self.lines)
+    }
+
     pub fn generate_label(&mut self, label: &str) -> String {
         let counter = if label == "if" {
             &mut self.if_counter
@@ -61,7 +53,8 @@ impl VmWriter {
             Token::Keyword(This) => self.write(VmCommand::Push(Seg::Pointer, 0)),
             Token::IntConstant(i) => self.write(VmCommand::Push(Seg::Constant, i)),
             Token::StringConstant(s) => {
-                self.write(VmCommand::Push(Seg::Constant, s.len() as i16));
+                // One character is appended at a time, however many bytes each takes up
+                self.write(VmCommand::Push(Seg::Constant, s.chars().count() as i16));
                 self.write(VmCommand::Call("String.new", 1));
                 for c in s.chars() {
                     self.write(VmCommand::Push(Seg::Constant, c as i16));
@@ -35,7 +35,14 @@ pub struct XMLWriter {
     writer: Option<BufWriter<File>>,
 }
 impl CodeWriter for XMLWriter {
-    fn new(filename: &str) -> Self {
+    fn write(&mut self, contents: impl Display) {
+        writeln!(self.writer.as_mut().unwrap(), "{contents}").expect("failed to write");
+        self.flush();
+    }
+}
+
+impl XMLWriter {
+    pub fn new(filename: &str) -> Self {
         let file =
             File::create(Path::new(filename).with_extension("xml")).expect("could not create file");
         let writer = BufWriter::new(file);
@@ -44,14 +51,7 @@ impl CodeWriter for XMLWriter {
         }
     }
 
-    fn write(&mut self, contents: impl Display) {
-        writeln!(self.writer.as_mut().unwrap(), "{contents}").expect("failed to write");
-        self.flush();
-    }
-
-    fn flush(&mut self) {
+    pub fn flush(&mut self) {
         self.writer.as_mut().unwrap().flush().unwrap();
     }
 }
-
-impl XMLWriter {}
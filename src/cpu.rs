@@ -1,12 +1,18 @@
-use std::ops::{Index, RangeInclusive};
+pub mod debugger;
+pub mod headless;
+pub mod history;
+pub mod recording;
+pub mod snapshot;
+pub mod test_script;
 
-use anyhow::{anyhow, Result};
+use std::ops::RangeInclusive;
+
+use anyhow::{anyhow, bail, Result};
 
 use crate::{
     asm::*,
-    io::ScreenUpdate,
     //code_writer::assembler::{Comp, Instruction},
-    vm::{MemSegment as Seg, VmCommand},
+    vm::{emulator::Flow, Comparison as Cmp, MemSegment as Seg, VmCommand},
 };
 
 const KBD: i16 = 0x6000;
@@ -28,18 +34,28 @@ const THIS: i16 = 3;
 /// This is `pointer 1` in the VM abstraction.
 const THAT: i16 = 4;
 
-struct Ram([i16; 0xFFFF]);
+/// One word for every address A can hold, so that M is always in RAM even past the memory map
+pub const RAM_SIZE: usize = 0x10000;
 
-impl Index<i16> for Ram {
-    type Output = i16;
+/// What a single tick changed, which is all it takes to undo it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Undo {
+    pc: u16,
+    a: i16,
+    d: i16,
+    /// The RAM address written to and what it held before
+    write: Option<(u16, i16)>,
+}
 
-    fn index(&self, index: i16) -> &Self::Output {
-        todo!()
+impl Undo {
+    /// The RAM address the tick wrote to and what it held before, if it wrote to RAM
+    pub fn write(&self) -> Option<(usize, i16)> {
+        self.write.map(|(addr, old)| (addr as usize, old))
     }
 }
 
 pub struct Cpu<'a> {
-    pub ram: [i16; 0xFFFF],
+    pub ram: [i16; RAM_SIZE],
     rom: &'a [Instruction],
     pub(crate) pc: usize,
     d: i16,
@@ -50,7 +66,7 @@ pub struct Cpu<'a> {
 impl<'a> Cpu<'a> {
     pub fn new(asm: &'a [Instruction]) -> Self {
         Self {
-            ram: [0; 0xFFFF],
+            ram: [0; RAM_SIZE],
             rom: asm,
             pc: 0,
             d: 0,
@@ -58,10 +74,84 @@ impl<'a> Cpu<'a> {
         }
     }
 
+    /// Pixels are displayed least significant bit first, so the leftmost pixel of each word is bit 0.
     pub const fn get_pixel(&self, x: usize, y: usize) -> bool {
         let offset = x & 15;
         let addr = SCREEN_START as usize + (x / 16) + (y * 32);
-        self.ram[addr] & (1 << (15 - offset)) != 0
+        self.ram[addr] & (1 << offset) != 0
+    }
+
+    pub const fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub const fn a(&self) -> i16 {
+        self.a
+    }
+
+    pub const fn d(&self) -> i16 {
+        self.d
+    }
+
+    /// Whether the program is stuck in the conventional Hack halt loop:
+    ///
+    /// ```text
+    /// (END)
+    ///     @END
+    ///     0;JMP
+    /// ```
+    ///
+    /// Any unconditional jump without a destination counts, since none of them can change the machine state.
+    pub fn is_halted(&self) -> bool {
+        let next = self.pc.checked_add(1).and_then(|next| self.rom.get(next));
+        let (Some(at), Some(jump)) = (self.rom.get(self.pc), next) else {
+            return false;
+        };
+        match (at.get(), jump.get()) {
+            (Ok(InstructionType::A(addr)), Ok(InstructionType::C(c))) => {
+                addr.value() as usize == self.pc
+                    && c.jump() == Jump::JMP
+                    && c.dest().get() == Dest::None
+            }
+            _ => false,
+        }
+    }
+
+    /// The RAM address the next instruction will write to, if it writes to RAM at all
+    pub fn next_write(&self) -> Option<usize> {
+        match self.rom.get(self.pc)?.get() {
+            Ok(InstructionType::C(c)) if c.dest().m() && self.a != KBD => {
+                Some(self.a as u16 as usize)
+            }
+            _ => None,
+        }
+    }
+
+    /// Executes one instruction, returning what it takes to undo it with [`Cpu::undo`].
+    pub fn tick_undoable(&mut self) -> Result<Undo> {
+        let write = self
+            .next_write()
+            .and_then(|addr| Some((addr as u16, *self.ram.get(addr)?)));
+        let undo = Undo {
+            pc: self.pc as u16,
+            a: self.a,
+            d: self.d,
+            write,
+        };
+        self.tick()?;
+        Ok(undo)
+    }
+
+    /// Puts the registers and RAM back the way they were before the tick `undo` came from.
+    ///
+    /// Ticks have to be undone in the reverse of the order they were made.
+    pub fn undo(&mut self, undo: &Undo) {
+        self.pc = undo.pc as usize;
+        self.a = undo.a;
+        self.d = undo.d;
+        if let Some((addr, old)) = undo.write {
+            self.ram[addr as usize] = old;
+        }
     }
 
     const fn m(&self) -> i16 {
@@ -73,7 +163,7 @@ impl<'a> Cpu<'a> {
     }
 
     const fn at(&self, addr: i16) -> i16 {
-        self.ram[addr as usize]
+        self.ram[addr as u16 as usize]
     }
 
     fn at_mut(&mut self, addr: i16) -> &mut i16 {
@@ -91,9 +181,10 @@ impl<'a> Cpu<'a> {
         self.at_mut(0)
     }
 
+    /// The stack pointer points one past the top of the stack, as in the translated assembly.
     fn stack_top(&mut self) -> &mut i16 {
         let sp = *self.sp();
-        self.at_mut(sp)
+        self.at_mut(sp.wrapping_sub(1))
     }
 
     pub fn set_kbd(&mut self, kbd: i16) {
@@ -114,8 +205,8 @@ impl<'a> Cpu<'a> {
             CBits::A => a_comp,
             CBits::NotD => !self.d,
             CBits::NotA => !a_comp,
-            CBits::NegD => -self.d,
-            CBits::NegA => -a_comp,
+            CBits::NegD => self.d.wrapping_neg(),
+            CBits::NegA => a_comp.wrapping_neg(),
             CBits::DPlusOne => self.d.wrapping_add(1),
             CBits::APlusOne => a_comp.wrapping_add(1),
             CBits::DMinusOne => self.d.wrapping_sub(1),
@@ -125,7 +216,7 @@ impl<'a> Cpu<'a> {
             CBits::AMinusD => a_comp.wrapping_sub(self.d),
             CBits::DAndA => self.d & a_comp,
             CBits::DOrA => self.d | a_comp,
-            // Unofficial
+            // Unofficial, which read `M` instead of `A` in the same way
             CBits::Zero0
             | CBits::Zero1
             | CBits::Zero2
@@ -147,29 +238,32 @@ impl<'a> Cpu<'a> {
             | CBits::NegOne8
             | CBits::NegOne9 => -1,
             CBits::D0 | CBits::D1 | CBits::D2 => self.d,
-            CBits::A0 | CBits::A1 | CBits::A2 => self.a,
+            CBits::A0 | CBits::A1 | CBits::A2 => a_comp,
             CBits::NotD0 | CBits::NotD1 | CBits::NotD2 => !self.d,
-            CBits::NotA0 | CBits::NotA1 | CBits::NotA2 => !self.a,
-            CBits::DNandA => !self.d | !self.a,
-            CBits::NotOfDPlusA => !(self.d.wrapping_add(self.a)),
-            CBits::DAndNotA => self.d & !self.a,
-            CBits::NotDOrA => !self.d | self.a,
-            CBits::DPlusNotA => self.d.wrapping_add(!self.a),
-            CBits::NotDAndA => !self.d & self.a,
-            CBits::DOrNotA => self.d | !self.a,
-            CBits::NotDPlusA => (!self.d).wrapping_add(self.a),
-            CBits::DNorA => !self.d & !self.a,
-            CBits::NotDPlusNotA => (!self.d).wrapping_add(!self.a),
+            CBits::NotA0 | CBits::NotA1 | CBits::NotA2 => !a_comp,
+            CBits::DNandA => !self.d | !a_comp,
+            CBits::NotOfDPlusA => !(self.d.wrapping_add(a_comp)),
+            CBits::DAndNotA => self.d & !a_comp,
+            CBits::NotDOrA => !self.d | a_comp,
+            CBits::DPlusNotA => self.d.wrapping_add(!a_comp),
+            CBits::NotDAndA => !self.d & a_comp,
+            CBits::DOrNotA => self.d | !a_comp,
+            CBits::NotDPlusA => (!self.d).wrapping_add(a_comp),
+            CBits::DNorA => !self.d & !a_comp,
+            CBits::NotDPlusNotA => (!self.d).wrapping_add(!a_comp),
             CBits::NotDMinus1 => (!self.d).wrapping_sub(1),
-            CBits::NotNotDPlusNotA => !((!self.d).wrapping_add(!self.a)),
-            CBits::NotAMinus1 => (!self.a).wrapping_sub(1),
+            CBits::NotNotDPlusNotA => !((!self.d).wrapping_add(!a_comp)),
+            CBits::NotAMinus1 => (!a_comp).wrapping_sub(1),
             CBits::NegTwo => -2,
         }
     }
 
     pub fn tick(&mut self) -> Result<()> {
         use InstructionType as Inst;
-        let inst = self.rom[self.pc];
+        let inst = *self
+            .rom
+            .get(self.pc)
+            .ok_or_else(|| anyhow!("program counter {} is past the end of ROM", self.pc))?;
         self.pc += 1;
         match inst.get().map_err(|i| anyhow!("{i} is not a valid instruction"))? {
             // an address will always be an unsigned 15 bit integer, so can never overflow an i16.
@@ -187,7 +281,8 @@ impl<'a> Cpu<'a> {
                     || (c.jgt() && comp > 0)
                     || (c.jlt() && comp < 0)
                 {
-                    self.pc = self.a as usize;
+                    // A is an unsigned address here, so a negative A jumps past the end of ROM
+                    self.pc = self.a as u16 as usize;
                 }
                 
                 // Do not allow writing to the KBD register
@@ -217,22 +312,66 @@ impl<'a> Cpu<'a> {
         *self.sp() -= 1;
     }
 
+    /// Pushes the D register onto the stack, and increments the stack pointer
+    fn push(&mut self) {
+        *self.sp() += 1;
+        *self.stack_top() = self.d;
+    }
+
+    /// The RAM address a VM memory segment access refers to.
+    ///
+    /// Statics are allocated per file by the caller, so their address is passed in already resolved.
+    fn segment_addr(&self, seg: Seg, i: i16, static_addr: i16) -> i16 {
+        match seg {
+            Seg::Argument => self.at(ARG).wrapping_add(i),
+            Seg::Local => self.at(LCL).wrapping_add(i),
+            Seg::Static => static_addr,
+            Seg::This => self.at(THIS).wrapping_add(i),
+            Seg::That => self.at(THAT).wrapping_add(i),
+            Seg::Pointer => THIS.wrapping_add(i),
+            Seg::Temp => 5i16.wrapping_add(i),
+            // Constants have no address
+            Seg::Constant => unreachable!(),
+        }
+    }
+
     /// Emulates the execution a VM command on the CPU level
     ///
     /// Each instruction *should* leave the CPU registers (and hopefully memory) in the same state
     /// as compiling to a series of assembly instructions and executing each in turn.
-    fn execute_vm(&mut self, vm: VmCommand) {
+    ///
+    /// Control flow is left to the caller through the returned [`Flow`]; `static_addr` is the
+    /// address of the static variable the command refers to (if any), and `return_addr`
+    /// is what a `call` saves in its frame.
+    pub(crate) fn execute_vm<'v>(
+        &mut self,
+        vm: VmCommand<'v>,
+        static_addr: i16,
+        return_addr: i16,
+    ) -> Result<Flow<'v>> {
         match vm {
             VmCommand::Add => {
                 self.pop();
-                *self.stack_top() += self.d;
+                *self.stack_top() = self.stack_top().wrapping_add(self.d);
             }
             VmCommand::Sub => {
                 self.pop();
-                *self.stack_top() -= self.d;
+                *self.stack_top() = self.stack_top().wrapping_sub(self.d);
+            }
+            VmCommand::Neg => *self.stack_top() = self.stack_top().wrapping_neg(),
+            VmCommand::Compare(cmp) => {
+                self.pop();
+                let (x, y) = (*self.stack_top(), self.d);
+                let result = match cmp {
+                    Cmp::EQ => x == y,
+                    Cmp::GT => x > y,
+                    Cmp::LT => x < y,
+                    Cmp::LE => x <= y,
+                    Cmp::GE => x >= y,
+                    Cmp::NE => x != y,
+                };
+                *self.stack_top() = if result { -1 } else { 0 };
             }
-            VmCommand::Neg => *self.stack_top() = -*self.stack_top(),
-            VmCommand::Compare(_) => todo!(),
             VmCommand::And => {
                 self.pop();
                 *self.stack_top() &= self.d;
@@ -242,35 +381,115 @@ impl<'a> Cpu<'a> {
                 *self.stack_top() |= self.d;
             }
             VmCommand::Not => *self.stack_top() = !*self.stack_top(),
-            VmCommand::Push(_, _) => todo!(),
+            VmCommand::Push(seg, i) => {
+                self.d = match seg {
+                    Seg::Constant => i,
+                    _ => self.at(self.segment_addr(seg, i, static_addr)),
+                };
+                self.push();
+            }
             VmCommand::Pop(seg, i) => {
+                if seg == Seg::Constant {
+                    bail!("cannot pop to the constant segment");
+                }
                 self.pop();
-                let addr = match seg {
-                    Seg::Argument => self.at(ARG).wrapping_add(i),
-                    Seg::Local => self.at(LCL).wrapping_add(i),
-                    Seg::Static => 16i16.wrapping_add(i),
-                    Seg::This => self.at(THIS).wrapping_add(i),
-                    Seg::That => self.at(THAT).wrapping_add(i),
-                    Seg::Pointer => THIS.wrapping_add(i),
-                    Seg::Temp => 5i16.wrapping_add(i),
-                    // Cannot pop to constants
-                    Seg::Constant => unreachable!(),
+                let addr = self.segment_addr(seg, i, static_addr);
+                *self.at_mut(addr) = self.d;
+            }
+            VmCommand::Immediate(op, n) => {
+                self.d = n;
+                *self.stack_top() = op.apply(*self.stack_top(), n);
+            }
+            VmCommand::Move(from, i, to, j) => {
+                if to == Seg::Constant {
+                    bail!("cannot move to the constant segment");
+                }
+                self.d = match from {
+                    Seg::Constant => i,
+                    _ => self.at(self.segment_addr(from, i, static_addr)),
                 };
+                let addr = self.segment_addr(to, j, static_addr);
                 *self.at_mut(addr) = self.d;
             }
-            VmCommand::Label(_) => todo!(),
-            VmCommand::Goto(_) => todo!(),
-            VmCommand::IfGoto(_) => todo!(),
-            VmCommand::Function(_, _) => todo!(),
-            VmCommand::Call(fun, n) => {
-                *self.sp() += 5;
-                let sp = *self.sp() as usize;
-                //&mut self.ram.copy_within();
-            },
-            VmCommand::Return => todo!(),
+            VmCommand::Label(_) => {}
+            VmCommand::Goto(label) => return Ok(Flow::Goto(label)),
+            VmCommand::IfGoto(label) => {
+                self.pop();
+                if self.d != 0 {
+                    return Ok(Flow::Goto(label));
+                }
+            }
+            VmCommand::Function(_, n_locals) => {
+                self.d = 0;
+                for _ in 0..n_locals {
+                    self.push();
+                }
+            }
+            VmCommand::Call(func, n_args) => {
+                self.d = return_addr;
+                self.push();
+                for saved in [LCL, ARG, THIS, THAT] {
+                    self.d = self.at(saved);
+                    self.push();
+                }
+                let sp = *self.sp();
+                *self.at_mut(ARG) = sp.wrapping_sub(n_args).wrapping_sub(5);
+                *self.at_mut(LCL) = sp;
+                return Ok(Flow::Call(func));
+            }
+            VmCommand::Return => {
+                let frame = self.at(LCL);
+                let return_addr = self.at(frame.wrapping_sub(5));
+                self.pop();
+                let arg = self.at(ARG);
+                *self.at_mut(arg) = self.d;
+                *self.sp() = arg.wrapping_add(1);
+                for (offset, saved) in [THAT, THIS, ARG, LCL].into_iter().enumerate() {
+                    *self.at_mut(saved) = self.at(frame.wrapping_sub(offset as i16 + 1));
+                }
+                return Ok(Flow::Return(return_addr));
+            }
         }
+        Ok(Flow::Next)
     }
+
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    /// The ALU as described by its control bits, `zx nx zy ny f no` from most significant to least
+    fn alu(x: i16, y: i16, bits: u16) -> i16 {
+        let bit = |n: u16| (bits >> (5 - n)) & 1 == 1;
+        let x = if bit(0) { 0 } else { x };
+        let x = if bit(1) { !x } else { x };
+        let y = if bit(2) { 0 } else { y };
+        let y = if bit(3) { !y } else { y };
+        let out = if bit(4) { x.wrapping_add(y) } else { x & y };
+        if bit(5) {
+            !out
+        } else {
+            out
+        }
+    }
+
+    #[test]
+    fn test_every_computation() {
+        // D=comp for every one of the 128 configurations, official or not
+        let rom: Vec<_> = (0..0x80)
+            .map(|comp| Instruction::from(-8192 | (comp << 6) | 0b010_000))
+            .collect();
+        for (d, a, m) in [(0, 0, 0), (5, 3, -7), (-1, 100, 12345), (i16::MIN, 4, i16::MAX)] {
+            for (comp, _) in rom.iter().enumerate() {
+                let mut cpu = Cpu::new(&rom);
+                cpu.pc = comp;
+                (cpu.d, cpu.a, cpu.ram[a as usize]) = (d, a, m);
+                cpu.tick().unwrap();
+                let y = if comp & 0x40 == 0 { a } else { m };
+                let expected = alu(d, y, comp as u16 & 0x3F);
+                assert_eq!(cpu.d, expected, "X{comp:02X} with D={d} A={a} M={m}");
+            }
+        }
+    }
+}
@@ -0,0 +1,858 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{BufRead, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+
+use super::headless::parse_ram_range;
+use super::history::{History, DEFAULT_LIMIT};
+use super::snapshot::Snapshot;
+use super::{Cpu, ARG, LCL};
+use crate::asm::listing::{Listing, SymbolKind};
+use crate::asm::{parse_constant, Assembler, Instruction, InstructionType, Jump};
+use crate::vm::debug_info::DebugInfo;
+
+/// How many instructions `continue` and the other commands that run freely may execute before giving up.
+const DEFAULT_MAX_TICKS: u64 = 100_000_000;
+
+/// How deep a call stack is followed, in case the frames saved in RAM have been overwritten into a loop
+const MAX_FRAMES: usize = 1000;
+
+/// Why the debugger handed control back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pause {
+    /// Reached a breakpoint, before executing the instruction there
+    Breakpoint(usize),
+    /// An instruction wrote to a watched address, which held `old` before
+    Watchpoint { addr: usize, old: i16, new: i16 },
+    /// Did what was asked, such as a single step or running to a return
+    Done,
+    /// The program reached the conventional `(END) @END 0;JMP` loop
+    Halted,
+    /// Ran out of ticks before anything else happened
+    TickLimit,
+    /// Ran back to the instruction that is about to write `new` over `old`
+    Written { addr: usize, old: i16, new: i16 },
+    /// Ran back as far as the history goes
+    OutOfHistory,
+}
+
+/// A VM function on the call stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// Where the function is now, which for a caller is the address it will return to
+    pub addr: usize,
+    pub lcl: i16,
+    pub arg: i16,
+}
+
+/// Runs a program on a [`Cpu`] under control of breakpoints and watchpoints,
+/// using the listing it was assembled with to name addresses.
+pub struct Debugger<'a> {
+    pub cpu: Cpu<'a>,
+    listing: &'a Listing,
+    /// Where a program translated from VM code came from
+    pub debug_info: Option<&'a DebugInfo>,
+    /// ROM addresses by label
+    labels: HashMap<&'a str, usize>,
+    /// RAM addresses by variable name
+    variables: HashMap<&'a str, usize>,
+    pub breakpoints: BTreeSet<usize>,
+    pub watchpoints: BTreeSet<usize>,
+    pub max_ticks: u64,
+    /// How many instructions have been executed in total, less any stepped back over
+    pub ticks: u64,
+    pub history: History,
+    /// The tick counts saved by `checkpoint`, to `rewind` to
+    checkpoints: Vec<u64>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(rom: &'a [Instruction], listing: &'a Listing) -> Self {
+        let mut labels = HashMap::new();
+        let mut variables = HashMap::new();
+        for symbol in &listing.symbols {
+            let names = match symbol.kind {
+                SymbolKind::Label => &mut labels,
+                SymbolKind::Variable => &mut variables,
+            };
+            names.insert(symbol.name.as_str(), symbol.value as u16 as usize);
+        }
+        Self {
+            cpu: Cpu::new(rom),
+            listing,
+            debug_info: None,
+            labels,
+            variables,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+            max_ticks: DEFAULT_MAX_TICKS,
+            ticks: 0,
+            history: History::new(DEFAULT_LIMIT),
+            checkpoints: vec![],
+        }
+    }
+
+    /// Executes one instruction, reporting a write to a watched address.
+    fn tick(&mut self) -> Result<Option<Pause>> {
+        let undo = self.history.tick(&mut self.cpu)?;
+        self.ticks += 1;
+        Ok(undo
+            .write()
+            .filter(|(addr, _)| self.watchpoints.contains(addr))
+            .map(|(addr, old)| Pause::Watchpoint {
+                addr,
+                old,
+                new: self.cpu.ram[addr],
+            }))
+    }
+
+    /// Undoes up to `count` ticks, stopping early if the history runs out.
+    pub fn step_back(&mut self, count: u64) -> Pause {
+        for _ in 0..count {
+            if self.history.step_back(&mut self.cpu).is_none() {
+                return Pause::OutOfHistory;
+            }
+            self.ticks -= 1;
+        }
+        Pause::Done
+    }
+
+    /// Runs backwards to the last instruction that wrote to any of `addrs`, leaving it about to write again.
+    pub fn last_write(&mut self, addrs: Range<usize>) -> Pause {
+        loop {
+            let Some(write) = self.history.last().map(|undo| undo.write()) else {
+                return Pause::OutOfHistory;
+            };
+            let written = write.filter(|(addr, _)| addrs.contains(addr));
+            let new = written.map(|(addr, _)| self.cpu.ram[addr]);
+            self.history.step_back(&mut self.cpu);
+            self.ticks -= 1;
+            if let Some(((addr, old), new)) = written.zip(new) {
+                return Pause::Written { addr, old, new };
+            }
+        }
+    }
+
+    /// Steps back to a tick count saved by `checkpoint`.
+    pub fn rewind(&mut self, ticks: u64) -> Result<Pause> {
+        if ticks > self.ticks {
+            bail!(
+                "that checkpoint is {} ticks ahead, use `step` or `continue` to get there",
+                ticks - self.ticks
+            );
+        }
+        let back = self.ticks - ticks;
+        if back > self.history.len() as u64 {
+            bail!(
+                "that checkpoint is {back} ticks back, but only the last {} are remembered",
+                self.history.len()
+            );
+        }
+        Ok(self.step_back(back))
+    }
+
+    /// Executes a single instruction, whether or not there is a breakpoint on it.
+    pub fn step(&mut self) -> Result<Pause> {
+        Ok(match self.tick()? {
+            Some(pause) => pause,
+            None if self.cpu.is_halted() => Pause::Halted,
+            None => Pause::Done,
+        })
+    }
+
+    /// Runs until `done` says so, or something else stops the program first.
+    ///
+    /// At least one instruction is executed, so that resuming from a breakpoint gets past it.
+    fn run_until(&mut self, done: impl Fn(&Cpu) -> bool) -> Result<Pause> {
+        for _ in 0..self.max_ticks {
+            if let Some(pause) = self.tick()? {
+                return Ok(pause);
+            }
+            if done(&self.cpu) {
+                return Ok(Pause::Done);
+            }
+            if self.breakpoints.contains(&self.cpu.pc) {
+                return Ok(Pause::Breakpoint(self.cpu.pc));
+            }
+            if self.cpu.is_halted() {
+                return Ok(Pause::Halted);
+            }
+        }
+        Ok(Pause::TickLimit)
+    }
+
+    /// Runs until a breakpoint, watchpoint or halt.
+    pub fn resume(&mut self) -> Result<Pause> {
+        self.run_until(|_| false)
+    }
+
+    /// Steps, but runs all the way through a jump that comes back to the following instruction,
+    /// as a call to a function or shared routine does.
+    ///
+    /// A recursive call coming back to the same place from further down the stack is told apart
+    /// by its `LCL`, which is higher than the caller's.
+    pub fn step_over(&mut self) -> Result<Pause> {
+        let pc = self.cpu.pc;
+        let jumps = match self.cpu.rom.get(pc).map(Instruction::get) {
+            Some(Ok(InstructionType::C(c))) => c.jump() != Jump::Never,
+            _ => false,
+        };
+        if !jumps {
+            return self.step();
+        }
+        let lcl = self.cpu.ram[LCL as usize];
+        self.run_until(|cpu| Some(cpu.pc) == pc.checked_add(1) && cpu.ram[LCL as usize] <= lcl)
+    }
+
+    /// Runs until the current VM function returns, to the address saved in its frame.
+    pub fn finish(&mut self) -> Result<Pause> {
+        let frame = self.cpu.ram[LCL as usize] as u16 as usize;
+        if !(5..self.cpu.ram.len()).contains(&frame) {
+            bail!("LCL ({frame}) does not point at a VM function's frame");
+        }
+        let return_addr = self.cpu.ram[frame - 5] as u16 as usize;
+        let caller_lcl = self.cpu.ram[frame - 4];
+        self.run_until(|cpu| cpu.pc == return_addr && cpu.ram[LCL as usize] == caller_lcl)
+    }
+
+    /// A ROM address, given as a number or a label
+    ///
+    /// A ROM read without its sources has no labels, but its debug info still knows where each function starts.
+    pub fn rom_addr(&self, text: &str) -> Result<usize> {
+        if let Some(addr) = parse_constant(text) {
+            return Ok(addr.map_err(|e| anyhow!(e))? as usize);
+        }
+        let function = || {
+            let entries = &self.debug_info?.entries;
+            entries.iter().find(|e| e.function == text).map(|e| e.addr)
+        };
+        self.labels
+            .get(text)
+            .copied()
+            .or_else(function)
+            .ok_or_else(|| anyhow!("there is no label `{text}`"))
+    }
+
+    /// RAM addresses, given as a number, a range or a built in symbol or variable
+    pub fn ram_range(&self, text: &str) -> Result<Range<usize>> {
+        let symbol = Assembler::builtin(text)
+            .map(|addr| addr as u16 as usize)
+            .or_else(|| self.variables.get(text).copied());
+        match symbol {
+            Some(addr) => Ok(addr..addr + 1),
+            None => parse_ram_range(text).map_err(|e| anyhow!(e)),
+        }
+    }
+
+    /// The label at a ROM address, if there is one
+    fn label(&self, addr: usize) -> Option<&'a str> {
+        self.listing
+            .symbols
+            .iter()
+            .find(|s| s.kind == SymbolKind::Label && s.value as u16 as usize == addr)
+            .map(|s| s.name.as_str())
+    }
+
+    /// Where the code at a ROM address came from, or else how far it is past a label.
+    ///
+    /// A `return_addr` is described by the call that returns to it.
+    fn describe(&self, addr: usize, return_addr: bool) -> String {
+        let entry = self.debug_info.and_then(|info| {
+            if return_addr {
+                info.returning_to(addr)
+            } else {
+                info.at(addr)
+            }
+        });
+        if let Some(entry) = entry {
+            return entry.to_string();
+        }
+        let label = self
+            .listing
+            .symbols
+            .iter()
+            .filter(|s| s.kind == SymbolKind::Label && s.value as u16 as usize <= addr)
+            .max_by_key(|s| s.value as u16);
+        match label {
+            Some(label) if label.value as u16 as usize == addr => label.name.clone(),
+            Some(label) => format!("{}+{}", label.name, addr - label.value as u16 as usize),
+            None => format!("ROM[{addr}]"),
+        }
+    }
+
+    /// The VM functions that have been called and not yet returned, innermost first,
+    /// found by following the frames `call` saves below each function's locals.
+    pub fn call_stack(&self) -> Vec<Frame> {
+        let ram = &self.cpu.ram;
+        let mut frames = vec![Frame {
+            addr: self.cpu.pc,
+            lcl: ram[LCL as usize],
+            arg: ram[ARG as usize],
+        }];
+        while frames.len() < MAX_FRAMES {
+            let lcl = frames[frames.len() - 1].lcl as u16 as usize;
+            if !(5..ram.len()).contains(&lcl) {
+                break;
+            }
+            let addr = ram[lcl - 5] as u16 as usize;
+            if addr >= self.cpu.rom.len() {
+                break;
+            }
+            frames.push(Frame {
+                addr,
+                lcl: ram[lcl - 4],
+                arg: ram[lcl - 3],
+            });
+        }
+        frames
+    }
+
+    /// A RAM address along with the symbol for it, if there is one
+    fn ram_name(&self, addr: usize) -> String {
+        let name = ["SP", "LCL", "ARG", "THIS", "THAT"]
+            .get(addr)
+            .copied()
+            .or_else(|| {
+                self.variables
+                    .iter()
+                    .find(|(_, &a)| a == addr)
+                    .map(|(name, _)| *name)
+            });
+        match name {
+            Some(name) => format!("RAM[{addr}] ({name})"),
+            None => format!("RAM[{addr}]"),
+        }
+    }
+
+    /// Writes where the program is, in its sources if there is debug info, then the current instruction.
+    fn write_position(&self, mut out: impl Write) -> std::io::Result<()> {
+        if let Some(entry) = self.debug_info.and_then(|info| info.at(self.cpu.pc)) {
+            writeln!(out, "in {entry}")?;
+        }
+        self.write_instruction(self.cpu.pc, out)
+    }
+
+    /// Writes the instruction at `addr` as it appears in the listing, marking the current one and any breakpoint.
+    pub fn write_instruction(&self, addr: usize, mut out: impl Write) -> std::io::Result<()> {
+        let Some(line) = self.listing.lines.get(addr) else {
+            return writeln!(out, "ROM[{addr}] is past the end of the program");
+        };
+        if let Some(label) = self.label(addr) {
+            writeln!(out, "       ({label})")?;
+        }
+        let current = if addr == self.cpu.pc { "=>" } else { "  " };
+        let breakpoint = if self.breakpoints.contains(&addr) {
+            '*'
+        } else {
+            ' '
+        };
+        write!(out, "{current}{breakpoint}{addr:>5}  {}", line.source)?;
+        if let Some(comment) = &line.comment {
+            write!(out, "  // {comment}")?;
+        }
+        if !line.location.file.is_empty() {
+            write!(out, "  ({}:{})", line.location.file, line.location.line)?;
+        }
+        writeln!(out)
+    }
+
+    pub fn write_registers(&self, mut out: impl Write) -> std::io::Result<()> {
+        let ram = &self.cpu.ram;
+        writeln!(
+            out,
+            "PC {}  A {}  D {}  M {}",
+            self.cpu.pc,
+            self.cpu.a,
+            self.cpu.d,
+            self.cpu.m()
+        )?;
+        writeln!(
+            out,
+            "SP {}  LCL {}  ARG {}  THIS {}  THAT {}",
+            ram[0], ram[1], ram[2], ram[3], ram[4]
+        )?;
+        self.write_position(out)
+    }
+
+    fn write_pause(&self, pause: Pause, mut out: impl Write) -> std::io::Result<()> {
+        match pause {
+            Pause::Breakpoint(addr) => writeln!(out, "Breakpoint at ROM[{addr}]")?,
+            Pause::Watchpoint { addr, old, new } => {
+                writeln!(out, "{} changed from {old} to {new}", self.ram_name(addr))?
+            }
+            Pause::Done => {}
+            Pause::Halted => writeln!(out, "Halted after {} ticks", self.ticks)?,
+            Pause::TickLimit => writeln!(
+                out,
+                "Still running after {} more ticks, stopped here",
+                self.max_ticks
+            )?,
+            Pause::Written { addr, old, new } => writeln!(
+                out,
+                "This changes {} from {old} to {new}",
+                self.ram_name(addr)
+            )?,
+            Pause::OutOfHistory => writeln!(
+                out,
+                "Back at the oldest tick remembered, after {} ticks",
+                self.ticks
+            )?,
+        }
+        self.write_position(out)
+    }
+
+    /// Reads commands from `input` until it runs out or says `quit`.
+    ///
+    /// An empty line repeats the last command, which makes stepping through code quicker.
+    pub fn repl(&mut self, input: impl BufRead, mut out: impl Write) -> Result<()> {
+        writeln!(out, "Type `help` for a list of commands")?;
+        self.write_position(&mut out)?;
+        let mut last = String::new();
+        let mut lines = input.lines();
+        loop {
+            write!(out, "(hdb) ")?;
+            out.flush()?;
+            let Some(line) = lines.next().transpose()? else {
+                return Ok(());
+            };
+            let line = match line.trim() {
+                "" => last.clone(),
+                line => line.to_string(),
+            };
+            match self.command(&line, &mut out) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) => writeln!(out, "error: {e:#}")?,
+            }
+            last = line;
+        }
+    }
+
+    /// Carries out one command, returning whether it was `quit`.
+    fn command(&mut self, line: &str, mut out: impl Write) -> Result<bool> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(false);
+        };
+        let arg = words.next();
+        let required = || arg.ok_or_else(|| anyhow!("`{command}` needs an argument"));
+        match command {
+            "help" | "h" => out.write_all(HELP.as_bytes())?,
+            "quit" | "q" => return Ok(true),
+            "break" | "b" => {
+                let addr = self.rom_addr(required()?)?;
+                self.breakpoints.insert(addr);
+                writeln!(out, "Breakpoint set at ROM[{addr}]")?;
+            }
+            "delete" | "d" => {
+                let addr = self.rom_addr(required()?)?;
+                if !self.breakpoints.remove(&addr) {
+                    bail!("there is no breakpoint at ROM[{addr}]");
+                }
+            }
+            "watch" | "w" => {
+                for addr in self.ram_range(required()?)? {
+                    self.watchpoints.insert(addr);
+                    writeln!(out, "Watching {}", self.ram_name(addr))?;
+                }
+            }
+            "unwatch" => {
+                for addr in self.ram_range(required()?)? {
+                    self.watchpoints.remove(&addr);
+                }
+            }
+            "info" | "i" => {
+                for addr in &self.breakpoints {
+                    match self.label(*addr) {
+                        Some(label) => writeln!(out, "Breakpoint at ROM[{addr}] ({label})")?,
+                        None => writeln!(out, "Breakpoint at ROM[{addr}]")?,
+                    }
+                }
+                for addr in &self.watchpoints {
+                    writeln!(out, "Watching {}", self.ram_name(*addr))?;
+                }
+                for (i, ticks) in self.checkpoints.iter().enumerate() {
+                    writeln!(out, "Checkpoint {} at tick {ticks}", i + 1)?;
+                }
+                writeln!(
+                    out,
+                    "{} ticks run, the last {} can be stepped back over",
+                    self.ticks,
+                    self.history.len()
+                )?;
+            }
+            "step" | "s" => {
+                let mut pause = Pause::Done;
+                for _ in 0..count(arg)? {
+                    pause = self.step()?;
+                    if pause != Pause::Done {
+                        break;
+                    }
+                }
+                self.write_pause(pause, &mut out)?;
+            }
+            "next" | "n" => {
+                let pause = self.step_over()?;
+                self.write_pause(pause, &mut out)?;
+            }
+            "finish" | "f" => {
+                let pause = self.finish()?;
+                self.write_pause(pause, &mut out)?;
+            }
+            "continue" | "c" => {
+                let pause = self.resume()?;
+                self.write_pause(pause, &mut out)?;
+            }
+            "registers" | "r" => self.write_registers(&mut out)?,
+            "backtrace" | "bt" => {
+                for (i, frame) in self.call_stack().iter().enumerate() {
+                    writeln!(
+                        out,
+                        "#{i:<3} {}  LCL {}  ARG {}",
+                        self.describe(frame.addr, i > 0),
+                        frame.lcl,
+                        frame.arg
+                    )?;
+                }
+            }
+            "print" | "p" => {
+                for addr in self.ram_range(required()?)? {
+                    writeln!(out, "{}: {}", self.ram_name(addr), self.cpu.ram[addr])?;
+                }
+            }
+            "set" => {
+                let (addr, value) = required()?
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("expected ADDR=VALUE"))?;
+                let value = value
+                    .parse()
+                    .map_err(|_| anyhow!("`{value}` is not a 16 bit integer"))?;
+                for addr in self.ram_range(addr)? {
+                    self.cpu.ram[addr] = value;
+                }
+                // Undoing ticks or rewinding to a checkpoint from before the change would leave
+                // RAM in a state that never happened
+                if !self.history.is_empty() || !self.checkpoints.is_empty() {
+                    self.history.clear();
+                    self.checkpoints.clear();
+                    writeln!(
+                        out,
+                        "Forgot the history and checkpoints, as they no longer lead here"
+                    )?;
+                }
+            }
+            "back" | "rs" => {
+                let pause = self.step_back(count(arg)?);
+                self.write_pause(pause, &mut out)?;
+            }
+            "lastwrite" | "lw" => {
+                let pause = self.last_write(self.ram_range(required()?)?);
+                self.write_pause(pause, &mut out)?;
+            }
+            "checkpoint" | "cp" => {
+                self.checkpoints.push(self.ticks);
+                writeln!(
+                    out,
+                    "Checkpoint {} at tick {}",
+                    self.checkpoints.len(),
+                    self.ticks
+                )?;
+            }
+            "rewind" => {
+                let n = required()?;
+                let ticks = n
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| self.checkpoints.get(n.checked_sub(1)?))
+                    .copied()
+                    .ok_or_else(|| anyhow!("there is no checkpoint {n}"))?;
+                let pause = self.rewind(ticks)?;
+                self.write_pause(pause, &mut out)?;
+            }
+            "save" => {
+                let path = Path::new(required()?);
+                Snapshot::take(&self.cpu).write(path)?;
+                writeln!(out, "Saved the state to {}", path.display())?;
+            }
+            "load" => {
+                let path = Path::new(required()?);
+                Snapshot::read(path)?.restore(&mut self.cpu)?;
+                // Like `set`, the history and checkpoints no longer lead to where the program now is,
+                // and ticks count from the load as they do for `--load-state`
+                self.history.clear();
+                self.checkpoints.clear();
+                self.ticks = 0;
+                writeln!(out, "Loaded the state from {}", path.display())?;
+                self.write_position(&mut out)?;
+            }
+            "list" | "l" => {
+                let start = match arg {
+                    Some(loc) => self.rom_addr(loc)?,
+                    None => self.cpu.pc.saturating_sub(3),
+                };
+                for addr in start..(start + 10).min(self.listing.lines.len()) {
+                    self.write_instruction(addr, &mut out)?;
+                }
+            }
+            _ => bail!("unknown command `{command}`, try `help`"),
+        }
+        Ok(false)
+    }
+}
+
+/// A count given to a command, 1 if none was given
+fn count(arg: Option<&str>) -> Result<u64> {
+    match arg {
+        Some(n) => n.parse().map_err(|_| anyhow!("`{n}` is not a count")),
+        None => Ok(1),
+    }
+}
+
+const HELP: &str = "\
+  break LOC       Stop before executing the instruction at a ROM address or label (b)
+  delete LOC      Remove a breakpoint (d)
+  watch ADDR      Stop after any write to a RAM address, range, register or variable (w)
+  unwatch ADDR    Remove a watchpoint
+  info            List breakpoints and watchpoints (i)
+  step [N]        Execute one instruction, or N (s)
+  next            Step, running through any call that comes back to the next instruction (n)
+  finish          Run until the current VM function returns (f)
+  continue        Run until a breakpoint, a watchpoint or the program halts (c)
+  back [N]        Undo the last instruction executed, or the last N (rs)
+  lastwrite ADDR  Run backwards to the last instruction that wrote to ADDR (lw)
+  checkpoint      Remember this point in the program's run (cp)
+  rewind N        Run backwards to checkpoint N
+  registers       Show PC, A, D, M and the VM pointers (r)
+  backtrace       Show the VM functions on the call stack, from the saved LCL and ARG frames (bt)
+  print ADDR      Show RAM at an address, range, register or variable (p)
+  set ADDR=VALUE  Change RAM while paused
+  list [LOC]      Show the instructions around PC, or from LOC (l)
+  save FILE       Save the machine state to a file
+  load FILE       Restore the machine state from a file saved by this program
+  quit            Stop debugging (q)
+An empty line repeats the last command.
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::diagnostic::Location;
+    use crate::vm::translator::translate_sources;
+
+    const COUNT: &str = "@3
+D=A
+@count
+M=D
+(LOOP)
+@count
+MD=M-1
+@LOOP
+D;JGT
+(END)
+@END
+0;JMP";
+
+    fn assemble(asm: &[crate::asm::Asm]) -> (Vec<Instruction>, Listing) {
+        let mut assembler = Assembler::new();
+        let rom = assembler.assemble(asm).unwrap();
+        let listing = assembler.listing(asm, &Location::generated(asm.len()), &rom);
+        (rom, listing)
+    }
+
+    fn count() -> (Vec<Instruction>, Listing) {
+        let source = crate::asm::AsmSource::parse("Count.asm", COUNT).unwrap();
+        assemble(&source.asm)
+    }
+
+    #[test]
+    fn test_breakpoints_and_watchpoints() {
+        let (rom, listing) = count();
+        let mut debugger = Debugger::new(&rom, &listing);
+        debugger
+            .breakpoints
+            .insert(debugger.rom_addr("LOOP").unwrap());
+        assert_eq!(debugger.resume().unwrap(), Pause::Breakpoint(4));
+        assert_eq!(debugger.cpu.ram[16], 3);
+
+        debugger
+            .watchpoints
+            .extend(debugger.ram_range("count").unwrap());
+        let watch = Pause::Watchpoint {
+            addr: 16,
+            old: 3,
+            new: 2,
+        };
+        assert_eq!(debugger.resume().unwrap(), watch);
+        assert_eq!(debugger.cpu.pc, 6);
+
+        debugger.watchpoints.clear();
+        debugger.breakpoints.clear();
+        assert_eq!(debugger.resume().unwrap(), Pause::Halted);
+        assert_eq!(debugger.cpu.ram[16], 0);
+        assert!(debugger.ram_range("nothing").is_err());
+    }
+
+    #[test]
+    fn test_step_over_and_finish() {
+        let src = [(
+            "Sys".to_string(),
+            "function Sys.init 0
+            push constant 4
+            call Sys.double 1
+            pop static 0
+            label END
+            goto END
+            function Sys.double 0
+            push argument 0
+            push argument 0
+            add
+            return"
+                .to_string(),
+        )];
+        let translation = translate_sources(&src, true, None).unwrap();
+        let (rom, listing) = assemble(&translation.asm);
+        let mut debugger = Debugger::new(&rom, &listing);
+
+        // Into the middle of Sys.double, then back out to the pop
+        debugger
+            .breakpoints
+            .insert(debugger.rom_addr("Sys.double").unwrap());
+        assert!(matches!(debugger.resume().unwrap(), Pause::Breakpoint(_)));
+        debugger.breakpoints.clear();
+        let stack: Vec<_> = debugger
+            .call_stack()
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| debugger.describe(frame.addr, i > 0))
+            .collect();
+        assert_eq!(stack, ["Sys.double", "Sys.ret$1", "Bootstrap.ret$0"]);
+        assert_eq!(debugger.finish().unwrap(), Pause::Done);
+        assert_eq!(debugger.cpu.pc, debugger.rom_addr("Sys.ret$1").unwrap());
+        assert_eq!(debugger.cpu.ram[256 + 5], 8);
+
+        // Stepping over the bootstrap's jump into the call routine runs the whole program
+        let mut debugger = Debugger::new(&rom, &listing);
+        while debugger.cpu.rom[debugger.cpu.pc]
+            .get()
+            .is_ok_and(|i| match i {
+                InstructionType::C(c) => c.jump() == Jump::Never,
+                InstructionType::A(_) => true,
+            })
+        {
+            debugger.step().unwrap();
+        }
+        let pause = debugger.step_over().unwrap();
+        assert_eq!(pause, Pause::Halted);
+        assert_eq!(debugger.cpu.ram[16], 8);
+    }
+
+    #[test]
+    fn test_source_positions() {
+        let src = [(
+            "Sys".to_string(),
+            "function Sys.init 0
+            push constant 4
+            call Sys.double 1
+            label END
+            goto END
+            function Sys.double 0
+            push argument 0
+            push argument 0
+            add
+            return"
+                .to_string(),
+        )];
+        let translation = translate_sources(&src, true, None).unwrap();
+        let (rom, listing) = assemble(&translation.asm);
+        let info = DebugInfo::new(&translation.asm, translation.marks);
+        let mut debugger = Debugger::new(&rom, &listing);
+        debugger.debug_info = Some(&info);
+
+        let input = "b Sys.double\nc\ns 6\nbt\nq";
+        let mut out = vec![];
+        debugger.repl(input.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("=>     0  @256  // bootstrap"));
+        assert!(out.contains("in Sys.double (Sys.vm:6: function Sys.double 0)"));
+        assert!(out.contains("in Sys.double (Sys.vm:7: push argument 0)"));
+        assert!(out.contains("#0   Sys.double (Sys.vm:7: push argument 0)  LCL 267  ARG 261"));
+        assert!(out.contains("#1   Sys.init (Sys.vm:3: call Sys.double 1)  LCL 261  ARG 256"));
+        assert!(out.contains("#2   (bootstrap)  LCL 0  ARG 0"));
+    }
+
+    #[test]
+    fn test_reverse_execution() {
+        let (rom, listing) = count();
+        let mut debugger = Debugger::new(&rom, &listing);
+        let input = "s 4\ncp\nc\nlw count\nr\nback 2\nrewind 1\nrewind 2\nback 5\ns 2\nset count=7\nrewind 1\nback\ninfo";
+        let mut out = vec![];
+        debugger.repl(input.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Checkpoint 1 at tick 4"));
+        assert!(out.contains("Halted after 16 ticks"));
+        // The last write is the final decrement, about to happen again
+        assert!(out.contains("This changes RAM[16] (count) from 1 to 0\n=>     5  MD=M-1"));
+        assert!(out.contains("PC 5  A 16  D 1  M 1"));
+        assert!(out.contains("(hdb) =>     7  D;JGT"));
+        assert!(out.contains("error: there is no checkpoint 2"));
+        assert!(out.contains("Back at the oldest tick remembered, after 0 ticks"));
+        assert!(out.contains("Forgot the history and checkpoints"));
+        assert!(out.contains("error: there is no checkpoint 1"));
+        assert!(debugger.checkpoints.is_empty());
+        assert!(out.contains("Back at the oldest tick remembered, after 2 ticks"));
+        assert!(out.contains("2 ticks run, the last 0 can be stepped back over"));
+        assert_eq!(debugger.cpu.ram[16], 7);
+
+        // The poke is overwritten before it is read, so the run ends the same as before
+        assert_eq!(debugger.resume().unwrap(), Pause::Halted);
+        assert_eq!(debugger.ticks, 16);
+        let error = debugger.rewind(25).unwrap_err().to_string();
+        assert!(error.contains("9 ticks ahead"));
+        let error = debugger.rewind(1).unwrap_err().to_string();
+        assert!(error.contains("15 ticks back, but only the last 14"));
+    }
+
+    #[test]
+    fn test_repl() {
+        let (rom, listing) = count();
+        let mut debugger = Debugger::new(&rom, &listing);
+        let input =
+            "break LOOP\nc\nset count=1\n\nprint count\nwatch 16..18\nstep 2\nbogus\nr\nq\nc";
+        let mut out = vec![];
+        debugger.repl(input.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Breakpoint set at ROM[4]"));
+        assert!(out.contains("Breakpoint at ROM[4]\n       (LOOP)\n=>*    4  @count"));
+        // The empty line repeated the `set`
+        assert!(out.contains("RAM[16] (count): 1"));
+        assert!(out.contains("Watching RAM[17]"));
+        assert!(out.contains("RAM[16] (count) changed from 1 to 0"));
+        assert!(out.contains("error: unknown command `bogus`"));
+        assert!(out.contains("PC 6  A 16  D 0  M 0"));
+        // Nothing after `quit` runs
+        assert_eq!(debugger.cpu.pc, 6);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let (rom, listing) = count();
+        let mut debugger = Debugger::new(&rom, &listing);
+        let path = std::env::temp_dir().join(format!("debugger_{}.state", std::process::id()));
+        let input = format!(
+            "break LOOP\nc\nsave {0}\ncheckpoint\ns 3\nload {0}\ninfo\nprint count",
+            path.display()
+        );
+        let mut out = vec![];
+        debugger.repl(input.as_bytes(), &mut out).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Loaded the state from"), "{out}");
+        assert!(out.contains("RAM[16] (count): 3"));
+        assert!(out.contains("0 ticks run, the last 0 can be stepped back over"));
+        assert!(debugger.checkpoints.is_empty());
+        assert_eq!(debugger.ticks, 0);
+        assert_eq!(debugger.cpu.pc, 4);
+    }
+}
@@ -0,0 +1,217 @@
+use std::io::Write;
+use std::ops::Range;
+
+use anyhow::Result;
+
+use super::recording::KeyRecording;
+use super::snapshot::fnv1a;
+use super::{Cpu, RAM_SIZE, SCREEN_START};
+
+/// Why a headless run returned control to the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The program reached the conventional `(END) @END 0;JMP` loop
+    Halted,
+    /// The tick budget ran out before the program halted
+    TickLimit,
+}
+
+impl std::fmt::Display for Stop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stop::Halted => write!(f, "halted"),
+            Stop::TickLimit => write!(f, "tick limit reached"),
+        }
+    }
+}
+
+impl Cpu<'_> {
+    /// Ticks the CPU without any display until it halts, or until `max_ticks` cycles have run if given.
+    ///
+    /// Returns the reason for stopping along with the number of cycles executed.
+    pub fn run_headless(&mut self, max_ticks: Option<u64>) -> Result<(Stop, u64)> {
+        self.run_replaying(max_ticks, &KeyRecording::default())
+    }
+
+    /// Runs headless like [`Cpu::run_headless`], pressing the recorded keys as it goes.
+    pub fn run_replaying(
+        &mut self,
+        max_ticks: Option<u64>,
+        keys: &KeyRecording,
+    ) -> Result<(Stop, u64)> {
+        let mut replay = keys.replay();
+        let mut ticks = 0;
+        loop {
+            if self.is_halted() {
+                return Ok((Stop::Halted, ticks));
+            }
+            if max_ticks.is_some_and(|max| ticks >= max) {
+                return Ok((Stop::TickLimit, ticks));
+            }
+            replay.apply(self, ticks);
+            self.tick()?;
+            ticks += 1;
+        }
+    }
+
+    pub fn dump_registers(&self, mut out: impl Write) -> std::io::Result<()> {
+        writeln!(out, "PC: {}", self.pc)?;
+        writeln!(out, "A: {}", self.a)?;
+        writeln!(out, "D: {}", self.d)
+    }
+
+    pub fn dump_ram(&self, range: Range<usize>, mut out: impl Write) -> std::io::Result<()> {
+        for addr in range {
+            writeln!(out, "RAM[{addr}]: {}", self.ram[addr])?;
+        }
+        Ok(())
+    }
+
+    /// A hash of the screen, for checking that a run ended up showing what it did before
+    pub fn screen_hash(&self) -> u64 {
+        let screen = &self.ram[SCREEN_START as usize..SCREEN_START as usize + 0x2000];
+        fnv1a(screen.iter().flat_map(|word| word.to_le_bytes()))
+    }
+
+    /// Writes the screen as a binary (P4) PBM image, which like the Hack screen uses 1 for black.
+    pub fn write_pbm(&self, mut out: impl Write) -> std::io::Result<()> {
+        write!(out, "P4\n512 256\n")?;
+        for word in &self.ram[SCREEN_START as usize..SCREEN_START as usize + 0x2000] {
+            // The Hack screen is least significant bit first, PBM is most significant bit first
+            out.write_all(&(*word as u16).reverse_bits().to_be_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a RAM range from the command line, either a single address (`256`),
+/// an exclusive range (`0..16`) or an inclusive range (`16384..=16415`).
+pub fn parse_ram_range(input: &str) -> Result<Range<usize>, String> {
+    let addr = |s: &str| {
+        let s = s.trim();
+        let parsed = match s.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => s.parse(),
+        };
+        match parsed {
+            Ok(a) if a < RAM_SIZE => Ok(a),
+            _ => Err(format!("\"{s}\" is not a valid RAM address")),
+        }
+    };
+
+    if let Some((start, end)) = input.split_once("..=") {
+        Ok(addr(start)?..addr(end)? + 1)
+    } else if let Some((start, end)) = input.split_once("..") {
+        Ok(addr(start)?..addr(end)?)
+    } else {
+        let a = addr(input)?;
+        Ok(a..a + 1)
+    }
+}
+
+/// Parses an initial RAM value from the command line, as `ADDR=VALUE` (`0=256`, `0x4000=-1`).
+pub fn parse_ram_set(input: &str) -> Result<(usize, i16), String> {
+    let Some((addr, value)) = input.split_once('=') else {
+        return Err(format!("expected ADDR=VALUE, got \"{input}\""));
+    };
+    let addr = parse_ram_range(addr)?.start;
+    let value = value
+        .trim()
+        .parse()
+        .map_err(|_| format!("\"{value}\" is not a valid 16 bit integer"))?;
+    Ok((addr, value))
+}
+
+/// Parses a screen hash as printed after a headless run, in hex with or without `0x`.
+pub fn parse_screen_hash(input: &str) -> Result<u64, String> {
+    let digits = input.strip_prefix("0x").unwrap_or(input);
+    u64::from_str_radix(digits, 16).map_err(|_| format!("\"{input}\" is not a hex screen hash"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use asm_macro::asm;
+
+    #[test]
+    fn test_runs_until_halt() {
+        let hack = Assembler::new().assemble(&asm![
+            "R0 = 2 + 3"
+            @2
+            D=A
+            @3
+            D=D+A
+            @0
+            M=D
+        ("END")
+            @"END"
+            0;JMP
+        ])
+        .unwrap();
+        let mut cpu = Cpu::new(&hack);
+        assert_eq!(cpu.run_headless(Some(100)).unwrap(), (Stop::Halted, 6));
+        assert_eq!(cpu.ram[0], 5);
+    }
+
+    #[test]
+    fn test_tick_limit() {
+        let hack = Assembler::new().assemble(&asm![
+        ("LOOP")
+            @0
+            M=M+1
+            @"LOOP"
+            0;JMP
+        ])
+        .unwrap();
+        let mut cpu = Cpu::new(&hack);
+        assert_eq!(cpu.run_headless(Some(40)).unwrap(), (Stop::TickLimit, 40));
+        assert_eq!(cpu.ram[0], 10);
+    }
+
+    #[test]
+    fn test_top_of_ram() {
+        // A=-1 is address 0xFFFF, which M has to reach like any other
+        let hack = Assembler::new().assemble(&asm![
+            A=-1
+            M=1
+            D=M+1
+            @0
+            M=D
+        ("END")
+            @"END"
+            0;JMP
+        ])
+        .unwrap();
+        let mut cpu = Cpu::new(&hack);
+        assert_eq!(cpu.run_headless(Some(100)).unwrap(), (Stop::Halted, 5));
+        assert_eq!((cpu.ram[0], cpu.ram[0xFFFF]), (2, 1));
+        assert_eq!(parse_ram_range("0xFFFF"), Ok(0xFFFF..0x10000));
+    }
+
+    #[test]
+    fn test_jump_to_negative_a() {
+        // A=-1 is ROM address 0xFFFF, which is past the end rather than wrapping around
+        let hack = Assembler::new().assemble(&asm![
+            A=-1
+            0;JMP
+        ])
+        .unwrap();
+        let mut cpu = Cpu::new(&hack);
+        let error = cpu.run_headless(Some(10)).unwrap_err().to_string();
+        assert!(error.contains("program counter 65535 is past the end of ROM"), "{error}");
+        assert!(!cpu.is_halted());
+    }
+
+    #[test]
+    fn test_ram_ranges() {
+        assert_eq!(parse_ram_range("256"), Ok(256..257));
+        assert_eq!(parse_ram_range("0..16"), Ok(0..16));
+        assert_eq!(parse_ram_range("0x4000..=0x4001"), Ok(0x4000..0x4002));
+        assert!(parse_ram_range("SP").is_err());
+        assert_eq!(parse_ram_set("0=256"), Ok((0, 256)));
+        assert_eq!(parse_ram_set("0x4000=-1"), Ok((0x4000, -1)));
+        assert!(parse_ram_set("0..2=1").is_ok_and(|(addr, _)| addr == 0));
+        assert!(parse_ram_set("256").is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::VecDeque;
+
+use anyhow::Result;
+
+use super::{Cpu, Undo};
+
+/// How many ticks are kept by default, which takes around 12 MB
+pub const DEFAULT_LIMIT: usize = 1_000_000;
+
+/// The most recent ticks of a [`Cpu`], kept so that they can be undone.
+///
+/// Once there are `limit` of them the oldest are forgotten.
+pub struct History {
+    undo: VecDeque<Undo>,
+    pub limit: usize,
+}
+
+impl History {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            limit,
+        }
+    }
+
+    /// Executes one instruction, remembering how to undo it.
+    pub fn tick(&mut self, cpu: &mut Cpu) -> Result<Undo> {
+        let undo = cpu.tick_undoable()?;
+        if self.undo.len() >= self.limit {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(undo);
+        Ok(undo)
+    }
+
+    /// Undoes the most recent tick, if there is one left to undo.
+    pub fn step_back(&mut self, cpu: &mut Cpu) -> Option<Undo> {
+        let undo = self.undo.pop_back()?;
+        cpu.undo(&undo);
+        Some(undo)
+    }
+
+    /// What undoing the most recent tick would do
+    pub fn last(&self) -> Option<&Undo> {
+        self.undo.back()
+    }
+
+    /// How many ticks can be undone
+    pub fn len(&self) -> usize {
+        self.undo.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.undo.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{AsmSource, Assembler};
+
+    #[test]
+    fn test_undo() {
+        let source = AsmSource::parse(
+            "Count.asm",
+            "@3\nD=A\n@16\nM=D\n(LOOP)\n@16\nMD=M-1\n@LOOP\nD;JGT\n(END)\n@END\n0;JMP",
+        )
+        .unwrap();
+        let rom = Assembler::new().assemble(&source.asm).unwrap();
+        let mut cpu = Cpu::new(&rom);
+        let mut history = History::new(100);
+        let mut states = vec![];
+        while !cpu.is_halted() {
+            states.push((cpu.pc(), cpu.a(), cpu.d(), cpu.ram[16]));
+            history.tick(&mut cpu).unwrap();
+        }
+        assert_eq!(cpu.ram[16], 0);
+        assert_eq!(history.len(), states.len());
+
+        // Every state comes back, newest first
+        while let Some(state) = states.pop() {
+            let undo = history.step_back(&mut cpu).unwrap();
+            assert_eq!((cpu.pc(), cpu.a(), cpu.d(), cpu.ram[16]), state);
+            if cpu.pc() == 5 {
+                assert_eq!(undo.write(), Some((16, state.3)));
+            }
+        }
+        assert!(history.step_back(&mut cpu).is_none());
+
+        // Only the last few are kept
+        let mut history = History::new(3);
+        for _ in 0..10 {
+            history.tick(&mut cpu).unwrap();
+        }
+        assert_eq!(history.len(), 3);
+        for _ in 0..3 {
+            history.step_back(&mut cpu).unwrap();
+        }
+        assert_eq!(cpu.pc(), 7);
+    }
+}
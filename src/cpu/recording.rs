@@ -0,0 +1,148 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::Cpu;
+
+/// The first line of a recording file
+const HEADER: &str = "# hack keyboard recording: tick, key code";
+
+/// A change of the key held down, made just before the CPU ran tick number `tick`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyChange {
+    pub tick: u64,
+    pub key: i16,
+}
+
+/// The keys a program saw and when, so that a run can be played back exactly.
+///
+/// Ticks count from the start of the run, so a recording made after `--load-state`
+/// only replays the same way from that state. Saved as text, one `TICK KEY` change per line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyRecording {
+    /// In order of tick
+    pub changes: Vec<KeyChange>,
+}
+
+impl KeyRecording {
+    /// Notes the key held down before `tick`, if it is not the one already held.
+    pub fn record(&mut self, tick: u64, key: i16) {
+        let held = self.changes.last().map_or(0, |change| change.key);
+        if key != held {
+            self.changes.push(KeyChange { tick, key });
+        }
+    }
+
+    pub fn replay(&self) -> Replay<'_> {
+        Replay {
+            changes: &self.changes,
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut text = format!("{HEADER}\n");
+        for change in &self.changes {
+            text.push_str(&format!("{} {}\n", change.tick, change.key));
+        }
+        std::fs::write(path, text).with_context(|| format!("could not write {}", path.display()))
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("{} is not a key recording", path.display()))
+    }
+
+    /// Reads the changes, skipping blank lines and `#` comments.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut changes: Vec<KeyChange> = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (tick, key) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow!("line {} is not `TICK KEY`", i + 1))?;
+            let tick: u64 = tick
+                .parse()
+                .map_err(|_| anyhow!("line {}: `{tick}` is not a tick", i + 1))?;
+            let key = key
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("line {}: `{key}` is not a key code", i + 1))?;
+            if changes.last().is_some_and(|last| last.tick >= tick) {
+                bail!("line {}: tick {tick} is not after the one before", i + 1);
+            }
+            changes.push(KeyChange { tick, key });
+        }
+        Ok(Self { changes })
+    }
+}
+
+/// Plays a [`KeyRecording`] back into a CPU as it runs.
+pub struct Replay<'a> {
+    /// The changes still to come
+    changes: &'a [KeyChange],
+}
+
+impl Replay<'_> {
+    /// Presses whatever key the recording held down before `tick`, to be called before every tick.
+    pub fn apply(&mut self, cpu: &mut Cpu, tick: u64) {
+        while let Some((change, rest)) = self.changes.split_first() {
+            if change.tick > tick {
+                break;
+            }
+            cpu.set_kbd(change.key);
+            self.changes = rest;
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{AsmSource, Assembler};
+    use crate::cpu::headless::Stop;
+
+    #[test]
+    fn test_record_and_replay() {
+        // Adds up the keyboard every tick round the loop, until it sees a key of 100 or more
+        let source = AsmSource::parse(
+            "Keys.asm",
+            "(LOOP)\n@KBD\nD=M\n@16\nM=D+M\n@100\nD=D-A\n@LOOP\nD;JLT\n(END)\n@END\n0;JMP",
+        )
+        .unwrap();
+        let rom = Assembler::new().assemble(&source.asm).unwrap();
+
+        let mut recording = KeyRecording::default();
+        for (tick, key) in [(0, 0), (8, 1), (9, 1), (24, 0), (40, 2), (56, 100)] {
+            recording.record(tick, key);
+        }
+        assert_eq!(recording.changes.len(), 4);
+        let text = "# keys\n8 1\n24 0\n\n40 2\n56 100\n";
+        assert_eq!(KeyRecording::parse(text).unwrap(), recording);
+
+        let run = || {
+            let mut cpu = Cpu::new(&rom);
+            let result = cpu.run_replaying(Some(1000), &recording).unwrap();
+            (result, cpu.ram[16], cpu.screen_hash())
+        };
+        let ((stop, ticks), total, hash) = run();
+        assert_eq!(stop, Stop::Halted);
+        // Each key is seen twice, once per 8 tick loop
+        assert_eq!((ticks, total), (64, 1 + 1 + 2 + 2 + 100));
+        assert_eq!(run(), ((stop, ticks), total, hash));
+
+        let path = std::env::temp_dir().join(format!("keys_{}.txt", std::process::id()));
+        recording.write(&path).unwrap();
+        assert_eq!(KeyRecording::read(&path).unwrap(), recording);
+        std::fs::remove_file(&path).unwrap();
+        assert!(KeyRecording::parse("5 1\n5 2").is_err());
+        assert!(KeyRecording::parse("5").is_err());
+    }
+}
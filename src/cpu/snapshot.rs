@@ -0,0 +1,161 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use super::Cpu;
+use crate::asm::Instruction;
+
+/// What a save state file starts with, followed by the format's version
+const MAGIC: &[u8; 7] = b"HACKSAV";
+const VERSION: u8 = 1;
+/// The magic, ROM hash, PC, A and D that come before RAM
+const HEADER_LEN: usize = 8 + 8 + 2 + 2 + 2;
+
+/// The whole state of a [`Cpu`], along with a hash of the ROM it was running so that
+/// it is only ever restored into the same program.
+///
+/// Saved as the header then RAM from address 0, all as little-endian words like a RAM image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub rom_hash: u64,
+    pub pc: u16,
+    pub a: i16,
+    pub d: i16,
+    pub ram: Vec<i16>,
+}
+
+impl Snapshot {
+    pub fn take(cpu: &Cpu) -> Self {
+        Self {
+            rom_hash: rom_hash(cpu.rom),
+            pc: cpu.pc as u16,
+            a: cpu.a,
+            d: cpu.d,
+            ram: cpu.ram.to_vec(),
+        }
+    }
+
+    /// Puts the CPU back in this state, unless it is running a different program.
+    pub fn restore(&self, cpu: &mut Cpu) -> Result<()> {
+        if self.rom_hash != rom_hash(cpu.rom) {
+            bail!("the save state is from a different program");
+        }
+        if self.ram.len() != cpu.ram.len() {
+            bail!(
+                "the save state has {} words of RAM, not {}",
+                self.ram.len(),
+                cpu.ram.len()
+            );
+        }
+        cpu.pc = self.pc as usize;
+        cpu.a = self.a;
+        cpu.d = self.d;
+        cpu.ram.copy_from_slice(&self.ram);
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.ram.len() * 2);
+        bytes.extend(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend(self.rom_hash.to_le_bytes());
+        bytes.extend(self.pc.to_le_bytes());
+        bytes.extend(self.a.to_le_bytes());
+        bytes.extend(self.d.to_le_bytes());
+        bytes.extend(self.ram.iter().flat_map(|word| word.to_le_bytes()));
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_LEN || &bytes[..7] != MAGIC {
+            bail!("not a save state");
+        }
+        if bytes[7] != VERSION {
+            bail!("save state version {} is not supported", bytes[7]);
+        }
+        if !bytes.len().is_multiple_of(2) {
+            bail!("the save state ends part way through a word");
+        }
+        let words: Vec<[u8; 2]> = bytes[16..]
+            .chunks_exact(2)
+            .map(|word| [word[0], word[1]])
+            .collect();
+        Ok(Self {
+            rom_hash: u64::from_le_bytes(bytes[8..16].try_into()?),
+            pc: u16::from_le_bytes(words[0]),
+            a: i16::from_le_bytes(words[1]),
+            d: i16::from_le_bytes(words[2]),
+            ram: words[3..]
+                .iter()
+                .map(|&word| i16::from_le_bytes(word))
+                .collect(),
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_bytes())
+            .with_context(|| format!("could not write {}", path.display()))
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let bytes =
+            std::fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+        Self::from_bytes(&bytes).with_context(|| format!("could not load {}", path.display()))
+    }
+}
+
+/// A hash of the instructions, which stays the same across builds and platforms
+pub fn rom_hash(rom: &[Instruction]) -> u64 {
+    fnv1a(rom.iter().flat_map(|inst| inst.raw_value().to_le_bytes()))
+}
+
+/// The 64 bit FNV-1a hash of some bytes
+pub fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{AsmSource, Assembler};
+
+    fn assemble(source: &str) -> Vec<Instruction> {
+        let source = AsmSource::parse("Test.asm", source).unwrap();
+        Assembler::new().assemble(&source.asm).unwrap()
+    }
+
+    #[test]
+    fn test_save_and_restore() {
+        let rom =
+            assemble("@3\nD=A\n@16\nM=D\n(LOOP)\n@16\nMD=M-1\n@LOOP\nD;JGT\n(END)\n@END\n0;JMP");
+        let mut cpu = Cpu::new(&rom);
+        for _ in 0..6 {
+            cpu.tick().unwrap();
+        }
+        let snapshot = Snapshot::from_bytes(&Snapshot::take(&cpu).to_bytes()).unwrap();
+        assert_eq!(snapshot, Snapshot::take(&cpu));
+        assert_eq!((snapshot.pc, snapshot.a, snapshot.d), (6, 16, 2));
+
+        let (stop, _) = cpu.run_headless(None).unwrap();
+        let finished = Snapshot::take(&cpu);
+
+        // Restoring goes back to part way through, from where the run ends the same way
+        let mut restored = Cpu::new(&rom);
+        snapshot.restore(&mut restored).unwrap();
+        assert_eq!(restored.ram[16], 2);
+        assert_eq!(restored.run_headless(None).unwrap().0, stop);
+        assert_eq!(Snapshot::take(&restored), finished);
+
+        let other = assemble("@3\nD=A");
+        let error = snapshot.restore(&mut Cpu::new(&other)).unwrap_err();
+        assert!(error.to_string().contains("different program"));
+        assert_ne!(rom_hash(&rom), rom_hash(&rom[1..]));
+
+        let mut bytes = snapshot.to_bytes();
+        bytes[7] = 2;
+        assert!(Snapshot::from_bytes(&bytes).is_err());
+        assert!(Snapshot::from_bytes(b"HACKSAV").is_err());
+    }
+}
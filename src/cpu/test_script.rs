@@ -0,0 +1,634 @@
+//! An interpreter for the `.tst` scripts that the Nand2Tetris course ships for the CPU emulator.
+//!
+//! Only the subset of the scripting language that applies to the CPU emulator is supported:
+//! `load`, `output-file`, `compare-to`, `output-list`, `set`, `repeat`, `while`,
+//! `tick`, `tock`, `ticktock`, `output`, `echo` and `clear-echo`.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::Cpu;
+use crate::asm::{parse_asm, rom::read_hack, Assembler, Instruction};
+
+/// A location in the emulator that a script can read or write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variable {
+    A,
+    D,
+    PC,
+    Ram(u16),
+    Time,
+}
+
+impl std::str::FromStr for Variable {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "A" => Ok(Variable::A),
+            "D" => Ok(Variable::D),
+            "PC" => Ok(Variable::PC),
+            "time" => Ok(Variable::Time),
+            _ => {
+                let addr = s
+                    .strip_prefix("RAM[")
+                    .and_then(|s| s.strip_suffix(']'))
+                    .ok_or_else(|| anyhow!("unknown variable \"{s}\""))?;
+                match addr.parse::<u16>() {
+                    Ok(a) => Ok(Variable::Ram(a)),
+                    _ => bail!("\"{addr}\" is not a valid RAM address"),
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Variable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Variable::A => write!(f, "A"),
+            Variable::D => write!(f, "D"),
+            Variable::PC => write!(f, "PC"),
+            Variable::Ram(a) => write!(f, "RAM[{a}]"),
+            Variable::Time => write!(f, "time"),
+        }
+    }
+}
+
+/// How a single column of the output list is rendered, e.g. `RAM[0]%D2.6.2`
+///
+/// The format is the base (`D`ecimal, `X` hex, `B`inary or `S`tring) followed by
+/// the left padding, the width of the value, and the right padding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Column {
+    pub var: Variable,
+    pub base: char,
+    pub left: usize,
+    pub width: usize,
+    pub right: usize,
+}
+
+impl Column {
+    fn parse(s: &str) -> Result<Self> {
+        let Some((var, format)) = s.split_once('%') else {
+            return Ok(Column {
+                var: s.parse()?,
+                base: 'D',
+                left: 1,
+                width: 6,
+                right: 1,
+            });
+        };
+        let mut chars = format.chars();
+        let base = match chars.next() {
+            Some(b @ ('D' | 'X' | 'B' | 'S')) => b,
+            _ => bail!("\"{format}\" is not a valid output format"),
+        };
+        let sizes = chars
+            .as_str()
+            .split('.')
+            .map(str::parse::<usize>)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| anyhow!("\"{format}\" is not a valid output format"))?;
+        let [left, width, right] = sizes[..] else {
+            bail!("\"{format}\" is not a valid output format");
+        };
+        Ok(Column {
+            var: var.parse()?,
+            base,
+            left,
+            width,
+            right,
+        })
+    }
+
+    /// The variable name, centered and truncated to fit the column.
+    fn header(&self) -> String {
+        let total = self.left + self.width + self.right;
+        let mut name = self.var.to_string();
+        name.truncate(total);
+        let left = (total - name.len()) / 2;
+        format!(
+            "{}{name}{}",
+            " ".repeat(left),
+            " ".repeat(total - left - name.len())
+        )
+    }
+
+    fn render(&self, value: i16) -> String {
+        let digits = |s: String| s[s.len().saturating_sub(self.width)..].to_string();
+        let value = match self.base {
+            'X' => digits(format!("{:04X}", value as u16)),
+            'B' => digits(format!("{:016b}", value as u16)),
+            'S' => format!("{value:<width$}", width = self.width),
+            _ => format!("{value:>width$}", width = self.width),
+        };
+        format!("{}{value}{}", " ".repeat(self.left), " ".repeat(self.right))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Load(Option<PathBuf>),
+    OutputFile(PathBuf),
+    CompareTo(PathBuf),
+    OutputList(Vec<Column>),
+    Set(Variable, i16),
+    Repeat(Option<u32>, Vec<Command>),
+    While(Variable, Comparison, i16, Vec<Command>),
+    Tick,
+    Tock,
+    TickTock,
+    Output,
+    Echo(String),
+    ClearEcho,
+}
+
+/// Splits a script into words and the `,` `;` `{` `}` separators, dropping comments.
+fn tokenize(script: &str) -> Result<Vec<String>> {
+    let mut tokens = vec![];
+    let mut chars = script.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                for c in chars.by_ref() {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            '"' => {
+                let mut s = String::from('"');
+                for c in chars.by_ref() {
+                    if c == '"' {
+                        break;
+                    }
+                    s.push(c);
+                }
+                tokens.push(s);
+            }
+            ',' | ';' | '{' | '}' => tokens.push(c.to_string()),
+            c if c.is_whitespace() => {}
+            c => {
+                let mut word = String::from(c);
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || ",;{}".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(word);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses a value as written in a script: decimal by default, or `%D`, `%X` and `%B` prefixed.
+fn parse_value(s: &str) -> Result<i16> {
+    let parsed = if let Some(hex) = s.strip_prefix("%X") {
+        u16::from_str_radix(hex, 16).map(|v| v as i16)
+    } else if let Some(bin) = s.strip_prefix("%B") {
+        u16::from_str_radix(bin, 2).map(|v| v as i16)
+    } else {
+        s.strip_prefix("%D").unwrap_or(s).parse::<i16>()
+    };
+    parsed.map_err(|_| anyhow!("\"{s}\" is not a valid value"))
+}
+
+pub fn parse(script: &str) -> Result<Vec<Command>> {
+    let tokens = tokenize(script)?;
+    let mut pos = 0;
+    let commands = parse_block(&tokens, &mut pos)?;
+    if pos < tokens.len() {
+        bail!("unexpected \"{}\"", tokens[pos]);
+    }
+    Ok(commands)
+}
+
+fn parse_block(tokens: &[String], pos: &mut usize) -> Result<Vec<Command>> {
+    let mut commands = vec![];
+    let next = |pos: &mut usize| {
+        let t = tokens.get(*pos).map(String::as_str);
+        *pos += 1;
+        t
+    };
+    loop {
+        let Some(word) = next(pos) else {
+            return Ok(commands);
+        };
+        let command = match word {
+            "," | ";" => continue,
+            "}" => {
+                // Let the enclosing block consume the closing brace
+                *pos -= 1;
+                return Ok(commands);
+            }
+            "load" => match tokens.get(*pos).map(String::as_str) {
+                Some("," | ";") | None => Command::Load(None),
+                Some(file) => {
+                    *pos += 1;
+                    Command::Load(Some(file.into()))
+                }
+            },
+            "output-file" => Command::OutputFile(next(pos).context("missing output file")?.into()),
+            "compare-to" => Command::CompareTo(next(pos).context("missing compare file")?.into()),
+            "output-list" => {
+                let mut columns = vec![];
+                while let Some(col) = tokens
+                    .get(*pos)
+                    .filter(|t| !matches!(t.as_str(), "," | ";"))
+                {
+                    columns.push(Column::parse(col)?);
+                    *pos += 1;
+                }
+                Command::OutputList(columns)
+            }
+            "set" => {
+                let var = next(pos).context("missing variable to set")?.parse()?;
+                let value = parse_value(next(pos).context("missing value to set")?)?;
+                Command::Set(var, value)
+            }
+            "repeat" => {
+                let count = match next(pos) {
+                    Some("{") => None,
+                    Some(n) => {
+                        let n = n
+                            .parse()
+                            .map_err(|_| anyhow!("\"{n}\" is not a repeat count"))?;
+                        if next(pos) != Some("{") {
+                            bail!("expected '{{' after repeat {n}");
+                        }
+                        Some(n)
+                    }
+                    None => bail!("unfinished repeat"),
+                };
+                let body = parse_block(tokens, pos)?;
+                if next(pos) != Some("}") {
+                    bail!("unclosed repeat block");
+                }
+                Command::Repeat(count, body)
+            }
+            "while" => {
+                let var = next(pos).context("missing while condition")?.parse()?;
+                let cmp = match next(pos) {
+                    Some("=") => Comparison::Eq,
+                    Some("<>") => Comparison::Ne,
+                    Some("<") => Comparison::Lt,
+                    Some(">") => Comparison::Gt,
+                    Some("<=") => Comparison::Le,
+                    Some(">=") => Comparison::Ge,
+                    other => bail!("\"{}\" is not a comparison", other.unwrap_or_default()),
+                };
+                let value = parse_value(next(pos).context("missing while condition")?)?;
+                if next(pos) != Some("{") {
+                    bail!("expected '{{' after while condition");
+                }
+                let body = parse_block(tokens, pos)?;
+                if next(pos) != Some("}") {
+                    bail!("unclosed while block");
+                }
+                Command::While(var, cmp, value, body)
+            }
+            "tick" => Command::Tick,
+            "tock" => Command::Tock,
+            "ticktock" => Command::TickTock,
+            "output" => Command::Output,
+            "echo" => {
+                let text = next(pos).context("missing echo text")?;
+                Command::Echo(text.trim_start_matches('"').to_string())
+            }
+            "clear-echo" => Command::ClearEcho,
+            other => bail!("unsupported script command \"{other}\""),
+        };
+        commands.push(command);
+    }
+}
+
+/// Runs a parsed script, keeping track of the output list and comparing each line of output as it is produced.
+pub struct ScriptRunner {
+    dir: PathBuf,
+    name: String,
+    columns: Vec<Column>,
+    output_file: Option<PathBuf>,
+    output: Vec<String>,
+    compare: Option<Vec<String>>,
+    time: u64,
+}
+
+impl ScriptRunner {
+    /// Scripts refer to files relative to their own directory, and `load` without a file loads the program of the same name.
+    pub fn new(dir: impl Into<PathBuf>, name: &str) -> Self {
+        Self {
+            dir: dir.into(),
+            name: name.to_string(),
+            columns: vec![],
+            output_file: None,
+            output: vec![],
+            compare: None,
+            time: 0,
+        }
+    }
+
+    /// Everything output so far, including the header line.
+    pub fn output(&self) -> &[String] {
+        &self.output
+    }
+
+    fn load(&self, file: &Option<PathBuf>) -> Result<Vec<Instruction>> {
+        let path = match file {
+            Some(f) => self.dir.join(f),
+            None => {
+                let asm = self.dir.join(&self.name).with_extension("asm");
+                if asm.exists() {
+                    asm
+                } else {
+                    self.dir.join(&self.name).with_extension("hack")
+                }
+            }
+        };
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("asm") => Ok(Assembler::new().assemble_source(&parse_asm(&path, false)?)?),
+            Some("hack") => read_hack(&path),
+            _ => bail!(
+                "the CPU emulator can only load .asm and .hack files, not {}",
+                path.display()
+            ),
+        }
+    }
+
+    /// Runs the script to completion, stopping at the first line of output that differs from the compare file.
+    ///
+    /// The output file is written either way.
+    pub fn run(&mut self, commands: &[Command]) -> Result<()> {
+        let result = self.run_loads(commands);
+        if let Some(path) = &self.output_file {
+            let mut out = BufWriter::new(
+                File::create(path)
+                    .with_context(|| format!("could not create {}", path.display()))?,
+            );
+            for line in &self.output {
+                writeln!(out, "{line}")?;
+            }
+            out.flush()?;
+        }
+        result
+    }
+
+    // The CPU borrows its ROM, so each `load` starts a new CPU that runs until the next one
+    fn run_loads(&mut self, commands: &[Command]) -> Result<()> {
+        let mut rom = vec![];
+        let mut rest = commands;
+        loop {
+            let mut cpu = Cpu::new(&rom);
+            let Some(i) = self.exec(&mut cpu, rest)? else {
+                return Ok(());
+            };
+            let Command::Load(file) = &rest[i] else {
+                unreachable!("only stops early at a load")
+            };
+            rom = self.load(file)?;
+            rest = &rest[i + 1..];
+        }
+    }
+
+    /// Executes commands until the end, or until a top level `load` is reached, returning its index.
+    fn exec(&mut self, cpu: &mut Cpu, commands: &[Command]) -> Result<Option<usize>> {
+        for (i, command) in commands.iter().enumerate() {
+            match command {
+                Command::Load(_) => return Ok(Some(i)),
+                Command::Repeat(count, body) => match count {
+                    Some(n) => {
+                        for _ in 0..*n {
+                            self.exec_nested(cpu, body)?;
+                        }
+                    }
+                    None => loop {
+                        self.exec_nested(cpu, body)?;
+                    },
+                },
+                Command::While(var, cmp, value, body) => {
+                    while self.test(cpu, *var, *cmp, *value) {
+                        self.exec_nested(cpu, body)?;
+                    }
+                }
+                c => self.exec_simple(cpu, c)?,
+            }
+        }
+        Ok(None)
+    }
+
+    fn exec_nested(&mut self, cpu: &mut Cpu, commands: &[Command]) -> Result<()> {
+        if self.exec(cpu, commands)?.is_some() {
+            bail!("load is not allowed inside a repeat or while block");
+        }
+        Ok(())
+    }
+
+    fn exec_simple(&mut self, cpu: &mut Cpu, command: &Command) -> Result<()> {
+        match command {
+            Command::OutputFile(f) => self.output_file = Some(self.dir.join(f)),
+            Command::CompareTo(f) => {
+                let path = self.dir.join(f);
+                let cmp = std::fs::read_to_string(&path)
+                    .with_context(|| format!("could not read {}", path.display()))?;
+                self.compare = Some(cmp.lines().map(|l| l.trim_end().to_string()).collect());
+            }
+            Command::OutputList(columns) => {
+                self.columns = columns.clone();
+                let header = columns.iter().map(Column::header).collect::<Vec<_>>();
+                self.emit(format!("|{}|", header.join("|")))?;
+            }
+            Command::Set(var, value) => match var {
+                Variable::A => cpu.a = *value,
+                Variable::D => cpu.d = *value,
+                Variable::PC => cpu.pc = *value as u16 as usize,
+                Variable::Ram(addr) => cpu.ram[*addr as usize] = *value,
+                Variable::Time => bail!("time is read only"),
+            },
+            Command::Tick => self.time += 1,
+            Command::Tock => {
+                self.time += 1;
+                cpu.tick()?;
+            }
+            Command::TickTock => {
+                self.time += 2;
+                cpu.tick()?;
+            }
+            Command::Output => {
+                let values = self
+                    .columns
+                    .iter()
+                    .map(|c| c.render(self.read(cpu, c.var)))
+                    .collect::<Vec<_>>();
+                self.emit(format!("|{}|", values.join("|")))?;
+            }
+            Command::Echo(text) => println!("{text}"),
+            Command::ClearEcho => {}
+            Command::Load(_) | Command::Repeat(..) | Command::While(..) => unreachable!(),
+        }
+        Ok(())
+    }
+
+    fn read(&self, cpu: &Cpu, var: Variable) -> i16 {
+        match var {
+            Variable::A => cpu.a,
+            Variable::D => cpu.d,
+            Variable::PC => cpu.pc as i16,
+            Variable::Ram(addr) => cpu.ram[addr as usize],
+            Variable::Time => (self.time / 2) as i16,
+        }
+    }
+
+    fn test(&self, cpu: &Cpu, var: Variable, cmp: Comparison, value: i16) -> bool {
+        let v = self.read(cpu, var);
+        match cmp {
+            Comparison::Eq => v == value,
+            Comparison::Ne => v != value,
+            Comparison::Lt => v < value,
+            Comparison::Gt => v > value,
+            Comparison::Le => v <= value,
+            Comparison::Ge => v >= value,
+        }
+    }
+
+    /// Records a line of output and checks it against the compare file, where `*` matches any character.
+    fn emit(&mut self, line: String) -> Result<()> {
+        let n = self.output.len();
+        let matches = match self.compare.as_ref().map(|c| c.get(n)) {
+            None => true,
+            Some(None) => false,
+            Some(Some(expected)) => {
+                expected.len() == line.len()
+                    && expected
+                        .chars()
+                        .zip(line.chars())
+                        .all(|(e, c)| e == '*' || e == c)
+            }
+        };
+        self.output.push(line);
+        if !matches {
+            bail!("Comparison failure at line {}", n + 1);
+        }
+        Ok(())
+    }
+}
+
+/// Runs the `.tst` script at `path`, writing its `.out` file and comparing it against its `.cmp` file.
+pub fn run_script(path: &Path) -> Result<()> {
+    let script = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    let commands = parse(&script).with_context(|| format!("in {}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let name = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default();
+    ScriptRunner::new(dir, name).run(&commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_script() {
+        let commands = parse(
+            "// Mult.tst
+            load Mult.hack,
+            output-list RAM[0]%D2.6.2 RAM[2]%B1.16.1;
+            set RAM[0] 3, /* inline */ set RAM[1] %XFF;
+            repeat 20 { ticktock; }
+            output;",
+        )
+        .unwrap();
+        assert_eq!(
+            commands,
+            vec![
+                Command::Load(Some("Mult.hack".into())),
+                Command::OutputList(vec![
+                    Column {
+                        var: Variable::Ram(0),
+                        base: 'D',
+                        left: 2,
+                        width: 6,
+                        right: 2
+                    },
+                    Column {
+                        var: Variable::Ram(2),
+                        base: 'B',
+                        left: 1,
+                        width: 16,
+                        right: 1
+                    },
+                ]),
+                Command::Set(Variable::Ram(0), 3),
+                Command::Set(Variable::Ram(1), 255),
+                Command::Repeat(Some(20), vec![Command::TickTock]),
+                Command::Output,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_output_format() {
+        let col = Column::parse("RAM[0]%D2.6.2").unwrap();
+        assert_eq!(col.header(), "  RAM[0]  ");
+        assert_eq!(col.render(-17), "     -17  ");
+        assert_eq!(Column::parse("A%X1.4.1").unwrap().render(-1), " FFFF ");
+        assert_eq!(Column::parse("D%B0.4.0").unwrap().render(5), "0101");
+    }
+
+    #[test]
+    fn test_run_against_compare_file() {
+        let dir = std::env::temp_dir().join(format!("hack_test_script_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("Add.asm"),
+            "@R0\nD=M\n@R1\nD=D+M\n@R2\nM=D\n(END)\n@END\n0;JMP\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("Add.cmp"),
+            "|  RAM[2]  |\n|       5  |\n|       9  |\n",
+        )
+        .unwrap();
+        let script = "load Add.asm, output-file Add.out, compare-to Add.cmp,
+            output-list RAM[2]%D2.6.2;
+            set RAM[0] 2, set RAM[1] 3; repeat 6 { ticktock; } output;
+            set PC 0, set RAM[0] 4, set RAM[1] 5; while PC < 6 { ticktock; } output;";
+        let commands = parse(script).unwrap();
+        let mut runner = ScriptRunner::new(&dir, "Add");
+        runner.run(&commands).unwrap();
+        assert_eq!(runner.output().len(), 3);
+
+        std::fs::write(dir.join("Add.cmp"), "|  RAM[2]  |\n|       6  |\n").unwrap();
+        let err = ScriptRunner::new(&dir, "Add").run(&commands).unwrap_err();
+        assert_eq!(err.to_string(), "Comparison failure at line 2");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -193,8 +193,8 @@ pub mod tests {
     #[test]
     fn test_registers() {
         assert_eq!(PixelFormatEnum::RGB24.byte_size_per_pixel(), 3);
-        assert_eq!(get_register(0x4000), (0, 0, 16, 1).into());
-        assert_eq!(get_register(0x4001), (16, 0, 16, 1).into());
-        assert_eq!(get_register(0x5FFF), (512 - 16, 255, 16, 1).into());
+        assert_eq!(get_register(0x4000), (0, 0, 16, 1));
+        assert_eq!(get_register(0x4001), (16, 0, 16, 1));
+        assert_eq!(get_register(0x5FFF), (512 - 16, 255, 16, 1));
     }
 }
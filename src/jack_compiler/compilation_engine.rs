@@ -7,32 +7,114 @@ use crate::jack_compiler::{
 use crate::tokens::{
     jack_tokens::{
         Keyword::{self, *},
-        Token,
+        Span, Token,
     },
     token_type::{TokenType, ValidToken},
-    vm_commands::{Comparison::*, MemSegment as Mem, VmCommand},
 };
+use crate::vm::{Comparison::*, MemSegment as Mem, VmCommand};
 
-// TODO: Remove when code generation and file writing are separated
-use std::path::PathBuf;
-
+#[derive(Default)]
 pub struct CompilationEngine {
     writer: VmWriter,
     tokenizer: Tokenizer,
     class_name: String,
     curr_token: Option<Token>,
+    /// Where the current token is in the source
+    curr_span: Span,
+    /// Where the most recently consumed token was, for errors about something already read
+    last_span: Span,
+    /// Set after a syntax error until the parser has skipped to somewhere it can carry on from,
+    /// so that one mistake doesn't cascade into a screen full of errors
+    recovering: bool,
     symbol_table: SymbolTable,
-    errors: Vec<(CompilationError, Option<Token>)>,
+    errors: Vec<Diagnostic>,
+    /// The Jack line each line of VM code from the last class compiled came from
+    lines: Vec<usize>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum CompilationError {
-    DuplicateIdentifier,
-    UnexpectedToken,
-    InvalidInt,
-    UnrecognizedToken,
-    UndeclaredIdentifier,
-    UnexpectedEndofTokens,
+    DuplicateIdentifier(String),
+    UnexpectedToken { expected: String, found: String },
+    InvalidInt(String),
+    UnrecognizedToken(char),
+    UnterminatedString,
+    UnterminatedComment,
+    UndeclaredIdentifier(String),
+    UnexpectedEndofTokens { expected: String },
+}
+
+impl std::fmt::Display for CompilationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DuplicateIdentifier(name) => {
+                write!(f, "`{name}` is already declared in this scope")
+            }
+            Self::UnexpectedToken { expected, found } => {
+                write!(f, "expected {expected}, found {found}")
+            }
+            Self::InvalidInt(num) => {
+                write!(f, "integer constant {num} is out of range (0 to 32767)")
+            }
+            Self::UnrecognizedToken(c) => write!(f, "unrecognized character `{c}`"),
+            Self::UnterminatedString => write!(f, "string constant is missing its closing `\"`"),
+            Self::UnterminatedComment => write!(f, "comment is missing its closing `*/`"),
+            Self::UndeclaredIdentifier(name) => write!(f, "`{name}` is not declared"),
+            Self::UnexpectedEndofTokens { expected } => {
+                write!(f, "expected {expected}, found the end of the file")
+            }
+        }
+    }
+}
+
+/// An error along with where in the source it was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub error: CompilationError,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn new(error: CompilationError, span: Span) -> Self {
+        Self { error, span }
+    }
+
+    /// Formats the error as `file:line:col: error: message`,
+    /// followed by the offending line of `source` with the span underlined.
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let Span { line, col, len } = self.span;
+        let text = source
+            .lines()
+            .nth(line.saturating_sub(1))
+            .unwrap_or_default();
+        // Tabs are kept so that the carets line up however wide the terminal draws them
+        let indent: String = text
+            .chars()
+            .take(col.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let line_no = line.to_string();
+        let gutter = " ".repeat(line_no.len());
+        format!(
+            "{file}:{line}:{col}: error: {}\n{line_no} | {text}\n{gutter} | {indent}{}",
+            self.error,
+            "^".repeat(len.max(1))
+        )
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.span.line, self.span.col, self.error)
+    }
+}
+
+/// How a token is described in an error, with string constants quoted so they stand out
+fn describe(token: &Token) -> String {
+    match token {
+        Token::StringConstant(s) => format!("`\"{s}\"`"),
+        _ => format!("`{token}`"),
+    }
 }
 
 use crate::tokens::token_type::TokenType::*;
@@ -44,17 +126,69 @@ impl CompilationEngine {
             class_name: String::new(),
             symbol_table: SymbolTable::default(),
             curr_token: None,
+            curr_span: Span::default(),
+            last_span: Span::default(),
+            recovering: false,
             errors: vec![],
+            lines: vec![],
         }
     }
 
+    /// The Jack line each line of VM code returned by the last [`compile`](Self::compile) came from
+    pub fn jack_lines(&self) -> &[usize] {
+        &self.lines
+    }
+
+    /// Reports an error about the token that was just consumed, such as an undeclared name
     pub fn throw_error(&mut self, err: CompilationError) {
-        let token = self.curr_token.as_ref();
-        self.errors.push((err, Option::<&Token>::cloned(token)));
+        self.errors.push(Diagnostic::new(err, self.last_span));
+    }
+
+    /// Reports that the current token isn't what the grammar allows here, unless already recovering from an earlier one
+    fn syntax_error(&mut self, expected: String) {
+        if !self.recovering {
+            let error = match &self.curr_token {
+                Some(token) => CompilationError::UnexpectedToken {
+                    expected,
+                    found: describe(token),
+                },
+                None => CompilationError::UnexpectedEndofTokens { expected },
+            };
+            self.errors.push(Diagnostic::new(error, self.curr_span));
+            self.recovering = true;
+        }
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let (token, span) = match self.tokenizer.advance() {
+            Some((token, span)) => (Some(token), span),
+            None => (None, self.tokenizer.position()),
+        };
+        self.curr_span = span;
+        std::mem::replace(&mut self.curr_token, token)
+    }
+
+    /// Whether the current token closes or opens something, or starts a statement,
+    /// which are left in place after an error so the parser can pick up from them
+    fn at_sync_point(&self) -> bool {
+        matches!(
+            self.curr_token,
+            None | Some(Token::Symbol(';' | '{' | '}' | ')' | ']'))
+        ) || self.curr_token_is(TokenType::Statement)
+    }
+
+    /// Skips the rest of a broken statement, stopping after its `;` or before the end of the enclosing block
+    fn synchronize(&mut self) {
+        while !self.curr_token_is('}') && !self.curr_token_is(TokenType::Statement) {
+            match self.advance() {
+                Some(Token::Symbol(';')) | None => break,
+                _ => {}
+            }
+        }
+        self.recovering = false;
     }
 
     pub fn curr_token_is<T: ValidToken + PartialEq<Token>>(&self, other: T) -> bool {
-        self.curr_token.is_some_and(|t| *t == other)
         if let Some(t) = self.curr_token.as_ref() {
             other == *t
         } else {
@@ -62,37 +196,49 @@ impl CompilationEngine {
         }
     }
 
-    pub fn compile(&mut self, file: PathBuf) -> Result<(), &[(CompilationError, Option<Token>)]> {
-        let filename = file.as_path().to_str().expect("could not convert to str");
-        let tokenizer = Tokenizer::new(std::fs::read_to_string(&file).expect("failed to read"));
-
-        self.writer = VmWriter::new(filename);
-        self.tokenizer = tokenizer;
-        self.curr_token = self.tokenizer.advance();
+    /// Compiles the source of a single class into VM code, or returns every error found in source order.
+    pub fn compile(&mut self, source: &str) -> Result<String, Vec<Diagnostic>> {
+        self.writer = VmWriter::default();
+        self.tokenizer = Tokenizer::new(source.to_string());
+        self.curr_token = None;
+        self.advance();
         self.symbol_table = SymbolTable::default();
+        self.recovering = false;
+        self.errors.clear();
 
         self.construct_class();
-        self.writer.flush();
 
-        let errors = &self.errors;
-        if !errors.is_empty() {
-            Err(errors)
+        self.errors.extend(self.tokenizer.take_errors());
+        if self.errors.is_empty() {
+            let (vm, lines) = std::mem::take(&mut self.writer).finish();
+            self.lines = lines;
+            Ok(vm)
         } else {
-            Ok(())
+            let mut errors = std::mem::take(&mut self.errors);
+            errors.sort_by_key(|d| (d.span.line, d.span.col));
+            Err(errors)
+        }
+    }
+
+    /// Adds a symbol to the table, reporting it if it was already declared in the same scope
+    fn define(&mut self, kind: Kind, type_of: &str, name: String) {
+        if let Err(e) = self.symbol_table.define(kind, type_of, name) {
+            self.throw_error(e);
         }
     }
 
     fn consume<T: ValidToken + PartialEq<Token> + Copy>(&mut self, requested: T) -> Token {
-        if self.curr_token.is_none() {
-            self.throw_error(CompilationError::UnexpectedEndofTokens);
-        } else if !self.curr_token_is(requested) {
-            self.throw_error(CompilationError::UnexpectedToken);
+        if !self.curr_token_is(requested) {
+            self.syntax_error(requested.describe());
+            // Leave anything that can be resynchronised on for the statement or block to deal with
+            if self.at_sync_point() {
+                return Token::Symbol('?');
+            }
         }
-        let mut token = self.tokenizer.advance();
-        std::mem::swap(&mut self.curr_token, &mut token);
+        self.last_span = self.curr_span;
         // return the last token in case it's wanted
         // using it is situational, and if it's not needed essentially discards it anyway
-        token.unwrap_or(Token::Symbol('?'))
+        self.advance().unwrap_or(Token::Symbol('?'))
     }
 
     fn construct_class(&mut self) {
@@ -101,13 +247,46 @@ impl CompilationEngine {
             self.class_name = name;
         }
         self.consume('{');
-        while self.curr_token_is(TokenType::ClassVarDec) {
-            self.handle_class_var_dec();
-        }
-        while self.curr_token_is(TokenType::SubroutineDec) {
-            self.handle_subroutine_dec();
+        loop {
+            if self.recovering {
+                self.skip_declaration();
+                self.recovering = false;
+            }
+            if self.curr_token_is(TokenType::ClassVarDec) {
+                self.handle_class_var_dec();
+            } else if self.curr_token_is(TokenType::SubroutineDec) {
+                self.handle_subroutine_dec();
+            } else if self.curr_token.is_none() || self.curr_token_is('}') {
+                break;
+            } else {
+                self.syntax_error(String::from("a class variable or subroutine declaration"));
+            }
         }
         self.consume('}');
+        if self.curr_token.is_some() {
+            self.syntax_error(String::from("the end of the file"));
+        }
+    }
+
+    /// Skips to the next class-level declaration or the closing brace of the class,
+    /// jumping over any subroutine bodies on the way
+    fn skip_declaration(&mut self) {
+        let mut depth = 0;
+        while let Some(token) = &self.curr_token {
+            if depth == 0
+                && (*token == TokenType::ClassVarDec
+                    || *token == TokenType::SubroutineDec
+                    || *token == '}')
+            {
+                break;
+            }
+            match token {
+                Token::Symbol('{') => depth += 1,
+                Token::Symbol('}') => depth -= 1,
+                _ => {}
+            }
+            self.advance();
+        }
     }
 
     fn handle_class_var_dec(&mut self) {
@@ -124,19 +303,13 @@ impl CompilationEngine {
             };
             let type_str = type_of.as_type();
             // Add the newly declared variable to the symbol table
-            self.symbol_table
-                .define(kind, &type_str, name)
-                .map_err(|e| self.throw_error(e))
-                .unwrap();
+            self.define(kind, &type_str, name);
 
             // Support multiple declarations of the same type before a semicolon
             while self.curr_token_is(',') {
                 self.consume(',');
                 if let Token::Identifier(name) = self.consume(TokenType::Name) {
-                    self.symbol_table
-                        .define(kind, &type_str, name)
-                        .map_err(|e| self.throw_error(e))
-                        .unwrap();
+                    self.define(kind, &type_str, name);
                 }
             }
             self.consume(';');
@@ -144,6 +317,7 @@ impl CompilationEngine {
     }
 
     fn handle_subroutine_dec(&mut self) {
+        self.writer.line = self.curr_span.line;
         // Clear the subroutine symbol table and reset the arg/var counts
         self.symbol_table.start_subroutine();
 
@@ -159,10 +333,8 @@ impl CompilationEngine {
         ) {
             // Jack methods include "this" as their first unspoken argument
             if func_type == Method {
-                self.symbol_table
-                    .define(Kind::Arg, &self.class_name, String::from("this"))
-                    .map_err(|e| self.throw_error(e))
-                    .unwrap();
+                let class_name = self.class_name.clone();
+                self.define(Kind::Arg, &class_name, String::from("this"));
             }
             self.consume('(');
             // Add 0 or more arguments to the symbol table
@@ -173,21 +345,34 @@ impl CompilationEngine {
     }
 
     fn handle_parameter_list(&mut self) {
-        while !self.curr_token_is(')') {
+        if self.at_sync_point() {
+            return;
+        }
+        loop {
             if let (type_of, Token::Identifier(name)) =
                 (self.consume(TokenType::Type), self.consume(TokenType::Name))
             {
-                self.symbol_table
-                    .define(Kind::Arg, &type_of.as_type(), name)
-                    .map_err(|e| self.throw_error(e))
-                    .unwrap();
+                self.define(Kind::Arg, &type_of.as_type(), name);
             }
-            if self.curr_token_is(',') {
-                self.consume(',');
+            if !self.list_continues() {
+                break;
             }
         }
     }
 
+    /// Consumes the `,` between two items of a list, or reports anything but the `)` that ends it.
+    /// The `)` itself, or whatever else the list stopped at, is left for the caller.
+    fn list_continues(&mut self) -> bool {
+        if self.curr_token_is(',') {
+            self.consume(',');
+            return true;
+        }
+        if !self.at_sync_point() {
+            self.syntax_error(String::from("`,` or `)`"));
+        }
+        false
+    }
+
     fn handle_subroutine_body(&mut self, func_type: Keyword, name: String) {
         self.consume('{');
 
@@ -225,17 +410,11 @@ impl CompilationEngine {
             self.consume(TokenType::Type),
             self.consume(TokenType::Name),
         ) {
-            self.symbol_table
-                .define(Kind::Var, &type_of.as_type(), name)
-                .map_err(|e| self.throw_error(e))
-                .unwrap();
+            self.define(Kind::Var, &type_of.as_type(), name);
             while self.curr_token_is(',') {
                 self.consume(',');
                 if let Token::Identifier(name) = self.consume(TokenType::Name) {
-                    self.symbol_table
-                        .define(Kind::Var, &type_of.as_type(), name)
-                        .map_err(|e| self.throw_error(e))
-                        .unwrap();
+                    self.define(Kind::Var, &type_of.as_type(), name);
                 }
             }
             self.consume(';');
@@ -243,14 +422,20 @@ impl CompilationEngine {
     }
 
     fn handle_statements(&mut self) {
-        while self.curr_token_is(TokenType::Statement) {
+        loop {
+            if self.recovering {
+                self.synchronize();
+            }
+            self.writer.line = self.curr_span.line;
             match self.curr_token.as_ref() {
                 Some(Token::Keyword(Let)) => self.handle_let(),
                 Some(Token::Keyword(If)) => self.handle_if(),
                 Some(Token::Keyword(While)) => self.handle_while(),
                 Some(Token::Keyword(Do)) => self.handle_do(),
                 Some(Token::Keyword(Return)) => self.handle_return(),
-                _ => break,
+                Some(Token::Symbol('}')) | None => break,
+                // Anything else is skipped by synchronising on the next pass
+                _ => self.syntax_error(String::from("a statement")),
             }
         }
     }
@@ -269,7 +454,7 @@ impl CompilationEngine {
                     entry.get_id(),
                 )
             } else {
-                self.throw_error(CompilationError::UndeclaredIdentifier);
+                self.throw_error(CompilationError::UndeclaredIdentifier(name));
                 (Mem::Constant, 0)
             };
             let arr = if self.curr_token_is('[') {
@@ -296,6 +481,7 @@ impl CompilationEngine {
     }
 
     fn handle_while(&mut self) {
+        let line = self.writer.line;
         self.consume(While);
         self.consume('(');
 
@@ -314,6 +500,7 @@ impl CompilationEngine {
 
         // Inside loop and jump to start
         self.handle_statements();
+        self.writer.line = line;
         self.writer.write(VmCommand::Goto(&start_label));
         self.consume('}');
 
@@ -322,6 +509,7 @@ impl CompilationEngine {
     }
 
     fn handle_if(&mut self) {
+        let line = self.writer.line;
         self.consume(If);
 
         self.consume('(');
@@ -340,6 +528,7 @@ impl CompilationEngine {
         self.handle_statements();
         self.consume('}');
 
+        self.writer.line = line;
         self.writer.write(VmCommand::Goto(&label2));
         self.writer.write(VmCommand::Label(&label1));
 
@@ -354,6 +543,7 @@ impl CompilationEngine {
             }
         }
 
+        self.writer.line = line;
         self.writer.write(VmCommand::Label(&label2));
     }
 
@@ -362,6 +552,8 @@ impl CompilationEngine {
         if let Token::Identifier(name) = self.consume(TokenType::Name) {
             if let Some(Token::Symbol(c @ ('.' | '('))) = self.curr_token {
                 self.handle_subroutine_call(name, c);
+            } else {
+                self.syntax_error(String::from("`.` or `(` after the subroutine name"));
             }
         }
         self.consume(';');
@@ -423,16 +615,17 @@ impl CompilationEngine {
     }
 
     fn handle_term(&mut self) {
-        // Check for unary operators
-        let op = if self.curr_token_is(TokenType::UnaryOp) {
-            match self.consume(TokenType::UnaryOp) {
-                Token::Symbol('-') => Some(VmCommand::Neg),
-                Token::Symbol('~') => Some(VmCommand::Not),
-                _ => None,
-            }
-        } else {
-            None
-        };
+        // Unary operators apply to the term that follows, which may itself be a unary operation
+        if self.curr_token_is(TokenType::UnaryOp) {
+            let op = match self.consume(TokenType::UnaryOp) {
+                Token::Symbol('-') => VmCommand::Neg,
+                _ => VmCommand::Not,
+            };
+            self.handle_term();
+            self.writer.write(op);
+            return;
+        }
+
         if self.curr_token_is('(') {
             self.consume('(');
             self.handle_expression();
@@ -460,24 +653,15 @@ impl CompilationEngine {
                         self.writer.write(VmCommand::Push(kind, id));
                     }
                 }
-                (None, _) => self.throw_error(CompilationError::UndeclaredIdentifier),
+                (None, _) => self.throw_error(CompilationError::UndeclaredIdentifier(name)),
             }
         }
-
-        // Use the unary operator if it exists
-        if let Some(o) = op {
-            self.writer.write(o);
-        }
     }
 
-    // TODO: maybe add a label for operator priority to get a feel for it
-    // could return a tuple (Option<Term>, Option<Term>, Option<Term>)
-    // with a vector of said tuples that gets appended recursively
-    // until the top-level expression is complete
-    // first things first though
+    // Jack has no operator precedence, so every binary operation is applied left to right as it is read
     fn handle_expression(&mut self) {
         self.handle_term();
-        if self.curr_token_is(TokenType::BinaryOp) {
+        while self.curr_token_is(TokenType::BinaryOp) {
             let op = self.consume(TokenType::BinaryOp);
             self.handle_term();
             let op_cmd = match op {
@@ -485,13 +669,13 @@ impl CompilationEngine {
                 Token::Symbol('-') => VmCommand::Sub,
                 Token::Symbol('&') => VmCommand::And,
                 Token::Symbol('|') => VmCommand::Or,
-                Token::Symbol('=') => VmCommand::Compare(Eq),
+                Token::Symbol('=') => VmCommand::Compare(EQ),
                 Token::Symbol('>') => VmCommand::Compare(GT),
                 Token::Symbol('<') => VmCommand::Compare(LT),
                 Token::Symbol('*') => VmCommand::Call("Math.multiply", 2),
                 Token::Symbol('/') => VmCommand::Call("Math.divide", 2),
-                Token::Symbol('%') => VmCommand::Call("Math.modulo", 2),
-                _ => VmCommand::Label("not a binary op"),
+                // Only reached when the current token is a binary op
+                _ => unreachable!("{op} is not a binary op"),
             };
             self.writer.write(op_cmd);
         }
@@ -500,13 +684,201 @@ impl CompilationEngine {
     // Evaluates the expressions and returns the total number of arguments for the function caller
     fn handle_expression_list(&mut self) -> i16 {
         let mut count: i16 = 0;
-        while !self.curr_token_is(')') {
+        if self.at_sync_point() {
+            return count;
+        }
+        loop {
             self.handle_expression();
             count += 1;
-            if self.curr_token_is(',') {
-                self.consume(',');
+            if !self.list_continues() {
+                break;
             }
         }
         count
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile_function() {
+        let vm = CompilationEngine::new()
+            .compile(
+                "class Main {
+                    function int twice(int x) {
+                        var int y;
+                        let y = x + x;
+                        return y;
+                    }
+                }",
+            )
+            .unwrap();
+        let expected = [
+            "function Main.twice 1",
+            "push argument 0",
+            "push argument 0",
+            "add",
+            "pop local 0",
+            "push local 0",
+            "return",
+        ];
+        assert_eq!(vm.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_jack_lines() {
+        let mut engine = CompilationEngine::new();
+        let vm = engine
+            .compile(
+                "class Main {
+                    function void main() {
+                        var int i;
+                        while (i < 3) {
+                            let i = i + 1;
+                        }
+                        return;
+                    }
+                }",
+            )
+            .unwrap();
+        let lines: Vec<_> = vm.lines().zip(engine.jack_lines()).collect();
+        assert_eq!(
+            lines,
+            [
+                ("function Main.main 1", &2),
+                ("label while0", &4),
+                ("push local 0", &4),
+                ("push constant 3", &4),
+                ("lt", &4),
+                ("not", &4),
+                ("if-goto while1", &4),
+                ("push local 0", &5),
+                ("push constant 1", &5),
+                ("add", &5),
+                ("pop local 0", &5),
+                ("goto while0", &4),
+                ("label while1", &4),
+                ("push constant 0", &7),
+                ("return", &7),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reports_errors() {
+        let errors = CompilationEngine::new()
+            .compile(
+                "class Main {
+                    function void main() {
+                        var int x, x;
+                        let y = 1;
+                        return;
+                    }
+                }",
+            )
+            .unwrap_err();
+        let found: Vec<_> = errors
+            .iter()
+            .map(|d| (d.error.clone(), d.span.line, d.span.col))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (CompilationError::DuplicateIdentifier("x".to_string()), 3, 36),
+                (CompilationError::UndeclaredIdentifier("y".to_string()), 4, 29),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recovers_from_syntax_errors() {
+        let errors = CompilationEngine::new()
+            .compile(
+                "class Main {
+                    field int a b;
+                    function void main() {
+                        var int x;
+                        let x = 1
+                        let x = (2 + ;
+                        do Output.print(x;
+                        if (x) { let = 3; }
+                        return;
+                    }
+                    function void ok() { return 1 2; }
+                    function int f(int a int b) { return a; }
+                    function void g() { do Main.f(1 2); return; }
+                }",
+            )
+            .unwrap_err();
+        let found: Vec<_> = errors.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            found,
+            [
+                "2:33: expected `;`, found `b`",
+                "6:25: expected `;`, found `let`",
+                "6:38: expected a name, found `;`",
+                "7:42: expected `)`, found `;`",
+                "8:38: expected a name, found `=`",
+                "11:51: expected `;`, found `2`",
+                "12:42: expected `,` or `)`, found `int`",
+                "13:53: expected `,` or `)`, found `2`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_do_needs_a_call() {
+        let errors = CompilationEngine::new()
+            .compile(
+                "class Main {
+                    function void main() {
+                        var int x;
+                        do x;
+                        do Main.main();
+                        return;
+                    }
+                }",
+            )
+            .unwrap_err();
+        let found: Vec<_> = errors.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            found,
+            ["4:29: expected `.` or `(` after the subroutine name, found `;`"]
+        );
+    }
+
+    #[test]
+    fn test_string_length_counts_characters() {
+        let source =
+            "class Main { function void main() { do Output.printString(\"é!\"); return; } }";
+        let vm = CompilationEngine::new().compile(source).unwrap();
+        assert!(vm.contains("push constant 2\ncall String.new 1\n"), "{vm}");
+        assert_eq!(vm.matches("call String.appendChar 2").count(), 2);
+    }
+
+    #[test]
+    fn test_rejects_modulo() {
+        let errors = CompilationEngine::new()
+            .compile("class Main { function int main() { return 7 % 2; } }")
+            .unwrap_err();
+        assert_eq!(
+            errors[0].error,
+            CompilationError::UnrecognizedToken('%'),
+            "{errors:?}"
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let source = "class Main {\n\tfunction int main() {\n\t\treturn 40000;\n";
+        let errors = CompilationEngine::new().compile(source).unwrap_err();
+        assert_eq!(
+            errors[0].render("Main.jack", source),
+            "Main.jack:3:10: error: integer constant 40000 is out of range (0 to 32767)
+3 | \t\treturn 40000;
+  | \t\t       ^^^^^"
+        );
+    }
+}
@@ -1,3 +1,385 @@
 pub(crate) mod compilation_engine;
 pub(crate) mod symbol_table;
 pub(crate) mod tokenizer;
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+
+use compilation_engine::CompilationEngine;
+
+use crate::vm::debug_info::write_jack_lines;
+
+/// Compiles each Jack class into a `.vm` file next to it, returning the paths written.
+///
+/// Alongside each goes a map from its lines back to the Jack source, for the debugger.
+///
+/// Every class is attempted even if an earlier one fails, so that all errors are reported at once.
+pub fn compile_jack(files: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut engine = CompilationEngine::new();
+    let mut written = vec![];
+    let mut report = String::new();
+    for file in files {
+        let source = std::fs::read_to_string(file)
+            .with_context(|| format!("could not read {}", file.display()))?;
+        match engine.compile(&source) {
+            Ok(vm) => {
+                let out = file.with_extension("vm");
+                std::fs::write(&out, vm)
+                    .with_context(|| format!("could not write {}", out.display()))?;
+                write_jack_lines(&out, engine.jack_lines())?;
+                written.push(out);
+            }
+            Err(errors) => {
+                for diagnostic in errors {
+                    report.push_str(&diagnostic.render(&file.display().to_string(), &source));
+                    report.push_str("\n\n");
+                }
+            }
+        }
+    }
+    if !report.is_empty() {
+        bail!("{}", report.trim_end());
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::vm::differential::run_differential;
+    use crate::vm::emulator::{VmEmulator, VmProgram};
+    use crate::vm::translator::translate_vm;
+
+    /// Just enough of the Jack OS, written in Jack, for the classes below.
+    const OS: [(&str, &str); 5] = [
+        (
+            "Sys",
+            "class Sys {
+                function void init() {
+                    do Main.main();
+                    while (true) {}
+                    return;
+                }
+            }",
+        ),
+        (
+            "Memory",
+            "class Memory {
+                static int free;
+
+                function int alloc(int size) {
+                    var int block;
+                    if (free = 0) {
+                        let free = 2048;
+                    }
+                    let block = free;
+                    let free = free + size;
+                    return block;
+                }
+
+                function int peek(int address) {
+                    var Array ram;
+                    let ram = null;
+                    return ram[address];
+                }
+
+                function void poke(int address, int value) {
+                    var Array ram;
+                    let ram = null;
+                    let ram[address] = value;
+                    return;
+                }
+            }",
+        ),
+        (
+            "Array",
+            "class Array {
+                function Array new(int size) {
+                    return Memory.alloc(size);
+                }
+            }",
+        ),
+        (
+            "Math",
+            "class Math {
+                function int multiply(int x, int y) {
+                    var int sum;
+                    while (y > 0) {
+                        let sum = sum + x;
+                        let y = y - 1;
+                    }
+                    return sum;
+                }
+            }",
+        ),
+        (
+            "String",
+            "class String {
+                field Array chars;
+                field int length;
+
+                constructor String new(int max) {
+                    let chars = Array.new(max);
+                    let length = 0;
+                    return this;
+                }
+
+                method String appendChar(int c) {
+                    let chars[length] = c;
+                    let length = length + 1;
+                    return this;
+                }
+
+                method int length() {
+                    return length;
+                }
+
+                method char charAt(int i) {
+                    return chars[i];
+                }
+            }",
+        ),
+    ];
+
+    const PROGRAM: [(&str, &str); 2] = [
+        (
+            "Point",
+            "class Point {
+                field int x, y;
+                static int count;
+
+                constructor Point new(int ax, int ay) {
+                    let x = ax;
+                    let y = ay;
+                    let count = count + 1;
+                    return this;
+                }
+
+                method int getX() { return x; }
+                method int getY() { return y; }
+
+                /** Unqualified calls are calls to methods on this */
+                method int dot(Point other) {
+                    return (x * other.getX()) + (getY() * other.getY());
+                }
+
+                function int count() { return count; }
+            }",
+        ),
+        (
+            "Main",
+            "class Main {
+                function void main() {
+                    var Point p, q;
+                    var Array a;
+                    var String s;
+                    var int i, sum;
+                    let p = Point.new(2, 3);
+                    let q = Point.new(4, 5);
+                    let a = Array.new(5);
+                    let i = 0;
+                    while (i < 5) {
+                        let a[i] = i * i;
+                        let i = i + 1;
+                    }
+                    // a[0] = -(~7) = 8
+                    let a[a[2] - 4] = -~7;
+                    let s = \"Hi!\";
+                    // 23 + 16 + 8 + 'i' + 3 + 2, all left to right
+                    let sum = p.dot(q) + a[4] + a[0] + s.charAt(1) + s.length() + Point.count();
+                    do Memory.poke(8000, sum);
+                    if (~(p = null) & true) {
+                        do Memory.poke(8001, 1);
+                    } else {
+                        do Memory.poke(8001, 2);
+                    }
+                    if (false | (1 + 2 * 3 = 9)) {
+                        do Memory.poke(8002, -1);
+                    }
+                    return;
+                }
+            }",
+        ),
+    ];
+
+    #[test]
+    fn test_compiles_full_language() {
+        let mut engine = CompilationEngine::new();
+        let sources: Vec<(String, String)> = OS
+            .iter()
+            .chain(&PROGRAM)
+            .map(|(name, jack)| (name.to_string(), engine.compile(jack).unwrap()))
+            .collect();
+
+        let program = VmProgram::new(&sources).unwrap();
+        let mut vm = VmEmulator::new(&program, true).unwrap();
+        vm.run(Some(20_000)).unwrap();
+        assert_eq!(vm.cpu.ram[8000], 157);
+        assert_eq!(vm.cpu.ram[8001], 1);
+        // 1 + 2 * 3 is (1 + 2) * 3
+        assert_eq!(vm.cpu.ram[8002], -1);
+
+        // The translated program has to agree all the way through
+        assert!(run_differential(&sources, true, &[], 20_000)
+            .unwrap()
+            .is_ok());
+    }
+
+    /// The nand2tetris project 11 programs, which between them use all of Jack
+    const CONVERT_TO_BIN: [(&str, &str); 1] =
+        [("Main", include_str!("testdata/ConvertToBin/Main.jack"))];
+    const SQUARE: [(&str, &str); 3] = [
+        ("Main", include_str!("testdata/Square/Main.jack")),
+        ("Square", include_str!("testdata/Square/Square.jack")),
+        (
+            "SquareGame",
+            include_str!("testdata/Square/SquareGame.jack"),
+        ),
+    ];
+    const PONG: [(&str, &str); 4] = [
+        ("Main", include_str!("testdata/Pong/Main.jack")),
+        ("PongGame", include_str!("testdata/Pong/PongGame.jack")),
+        ("Bat", include_str!("testdata/Pong/Bat.jack")),
+        ("Ball", include_str!("testdata/Pong/Ball.jack")),
+    ];
+
+    /// Compiles each class, failing on any diagnostic, and returns the `function` commands of all of them
+    fn compile_project(classes: &[(&str, &str)]) -> Vec<String> {
+        let mut engine = CompilationEngine::new();
+        let mut functions = vec![];
+        for (class, jack) in classes {
+            let vm = engine.compile(jack).unwrap_or_else(|errors| {
+                let rendered: Vec<_> = errors
+                    .iter()
+                    .map(|d| d.render(&format!("{class}.jack"), jack))
+                    .collect();
+                panic!("{}", rendered.join("\n\n"))
+            });
+            functions.extend(
+                vm.lines()
+                    .filter(|line| line.starts_with("function "))
+                    .map(String::from),
+            );
+        }
+        functions
+    }
+
+    #[test]
+    fn test_compiles_projects() {
+        assert_eq!(
+            compile_project(&CONVERT_TO_BIN),
+            [
+                "function Main.main 1",
+                "function Main.convert 3",
+                "function Main.nextMask 0",
+                "function Main.fillMemory 0",
+            ]
+        );
+        assert_eq!(
+            compile_project(&SQUARE),
+            [
+                "function Main.main 1",
+                "function Square.new 0",
+                "function Square.dispose 0",
+                "function Square.draw 0",
+                "function Square.erase 0",
+                "function Square.incSize 0",
+                "function Square.decSize 0",
+                "function Square.moveUp 0",
+                "function Square.moveDown 0",
+                "function Square.moveLeft 0",
+                "function Square.moveRight 0",
+                "function SquareGame.new 0",
+                "function SquareGame.dispose 0",
+                "function SquareGame.moveSquare 0",
+                "function SquareGame.run 2",
+            ]
+        );
+        assert_eq!(
+            compile_project(&PONG),
+            [
+                "function Main.main 1",
+                "function PongGame.new 0",
+                "function PongGame.dispose 0",
+                "function PongGame.newInstance 0",
+                "function PongGame.getInstance 0",
+                "function PongGame.run 1",
+                "function PongGame.moveBall 5",
+                "function Bat.new 0",
+                "function Bat.dispose 0",
+                "function Bat.show 0",
+                "function Bat.hide 0",
+                "function Bat.draw 0",
+                "function Bat.setDirection 0",
+                "function Bat.getLeft 0",
+                "function Bat.getRight 0",
+                "function Bat.setWidth 0",
+                "function Bat.move 0",
+                "function Ball.new 0",
+                "function Ball.dispose 0",
+                "function Ball.show 0",
+                "function Ball.hide 0",
+                "function Ball.draw 0",
+                "function Ball.getLeft 0",
+                "function Ball.getRight 0",
+                "function Ball.setDestination 3",
+                "function Ball.move 0",
+                "function Ball.bounce 5",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_runs_convert_to_bin() {
+        let mut engine = CompilationEngine::new();
+        let sources: Vec<(String, String)> = OS
+            .iter()
+            .chain(&CONVERT_TO_BIN)
+            .map(|(name, jack)| (name.to_string(), engine.compile(jack).unwrap()))
+            .collect();
+        let value = 0x8005_u16 as i16;
+
+        let program = VmProgram::new(&sources).unwrap();
+        let mut vm = VmEmulator::new(&program, true).unwrap();
+        vm.cpu.ram[8000] = value;
+        vm.run(Some(20_000)).unwrap();
+        let mut bits = [0; 16];
+        bits[0] = 1;
+        bits[2] = 1;
+        bits[15] = 1;
+        assert_eq!(vm.cpu.ram[8001..8017], bits);
+
+        assert!(run_differential(&sources, true, &[(8000, value)], 20_000)
+            .unwrap()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_builds_pong() {
+        let dir = std::env::temp_dir().join(format!("pong_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files: Vec<PathBuf> = PONG
+            .iter()
+            .map(|(class, jack)| {
+                let path = dir.join(format!("{class}.jack"));
+                std::fs::write(&path, jack).unwrap();
+                path
+            })
+            .collect();
+
+        let written = compile_jack(&files).unwrap();
+        assert_eq!(written.len(), PONG.len());
+        let translation = translate_vm(&written, false, None).unwrap();
+        let rom = Assembler::new().assemble(&translation.asm).unwrap();
+        assert!(!rom.is_empty());
+        // Every command is traced back to the Jack line it came from, for the debugger
+        assert!(translation
+            .marks
+            .iter()
+            .filter_map(|mark| mark.source.as_ref())
+            .all(|source| source.jack_line.is_some()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,7 +1,7 @@
 use std::{collections::HashMap, fmt::Display};
 
 use crate::jack_compiler::compilation_engine::CompilationError;
-use crate::tokens::vm_commands::MemSegment as Seg;
+use crate::vm::MemSegment as Seg;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Kind {
@@ -70,7 +70,7 @@ impl SymbolTable {
             *counter += 1;
             Ok(())
         } else {
-            Err(CompilationError::DuplicateIdentifier)
+            Err(CompilationError::DuplicateIdentifier(name))
         }
     }
 
@@ -83,14 +83,11 @@ impl SymbolTable {
         }
     }
 
+    /// Arguments and locals shadow fields and statics of the same name
     pub fn get(&self, name: &str) -> Option<&SymbolEntry> {
-        if let Some(e) = self.class_lvl_table.get(name) {
-            Some(e)
-        } else if let Some(e) = self.subroutine_lvl_table.get(name) {
-            Some(e)
-        } else {
-            None
-        }
+        self.subroutine_lvl_table
+            .get(name)
+            .or_else(|| self.class_lvl_table.get(name))
     }
 
     pub fn start_subroutine(&mut self) {
@@ -1,44 +1,91 @@
-use crate::jack_compiler::compilation_engine::CompilationError;
+use crate::jack_compiler::compilation_engine::{CompilationError, Diagnostic};
 use crate::tokens::jack_tokens::*;
 use std::collections::VecDeque;
 
-impl From<std::num::ParseIntError> for CompilationError {
-    fn from(_: std::num::ParseIntError) -> Self {
-        CompilationError::InvalidInt
-    }
-}
-
 #[derive(Debug, Default)]
 pub struct Tokenizer {
     chars: VecDeque<char>,
-    errors: Vec<CompilationError>,
+    line: usize,
+    col: usize,
+    errors: Vec<Diagnostic>,
 }
 
 impl Tokenizer {
     pub fn new(file: String) -> Self {
         Tokenizer {
-            chars: file.trim().chars().collect(),
+            chars: file.chars().collect(),
+            line: 1,
+            col: 1,
             errors: vec![],
         }
     }
 
+    /// Errors found so far, which are skipped over rather than ending tokenization
+    pub fn take_errors(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Where the next token would start, or the end of the file if there are none left.
+    pub fn position(&self) -> Span {
+        Span {
+            line: self.line,
+            col: self.col,
+            len: 1,
+        }
+    }
+
+    fn pop(&mut self) -> Option<char> {
+        let c = self.chars.pop_front()?;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn take_while(&mut self, mut pred: impl FnMut(char) -> bool) -> String {
+        let mut taken = String::new();
+        while let Some(&c) = self.chars.front() {
+            if !pred(c) {
+                break;
+            }
+            taken.push(c);
+            self.pop();
+        }
+        taken
+    }
+
     // Called when we have already seen a '/'
     // So we only care if the very next character is '/' or '*'
     // Advances to the next character after the comment before returning true
     // Otherwise returns false
-    fn advance_past_comment(&mut self) -> bool {
-        match self.chars.get(0) {
+    // A block comment that is never closed is reported at `start`, where its `/` was
+    fn advance_past_comment(&mut self, start: Span) -> bool {
+        match self.chars.front() {
             Some('*') => {
-                while let Some(c) = self.chars.pop_front() {
-                    if c == '*' && self.chars.get(0) == Some(&'/') {
-                        self.chars.pop_front();
-                        break;
+                self.pop();
+                loop {
+                    match self.pop() {
+                        Some('*') if self.chars.front() == Some(&'/') => {
+                            self.pop();
+                            break;
+                        }
+                        Some(_) => {}
+                        None => {
+                            self.errors.push(Diagnostic::new(
+                                CompilationError::UnterminatedComment,
+                                Span { len: 2, ..start },
+                            ));
+                            break;
+                        }
                     }
                 }
                 true
             }
             Some('/') => {
-                while let Some(c) = self.chars.pop_front() {
+                while let Some(c) = self.pop() {
                     if c == '\n' {
                         break;
                     }
@@ -49,75 +96,78 @@ impl Tokenizer {
         }
     }
 
-    fn get_string(&mut self) -> Option<Token> {
-        let mut end = self.chars.len();
-        for (i, &c) in self.chars.iter().enumerate() {
-            if c == '"' {
-                end = i;
-                break;
-            }
+    fn get_string(&mut self, start: Span) -> Token {
+        let s = self.take_while(|c| c != '"' && c != '\n');
+        if self.chars.front() == Some(&'"') {
+            self.pop();
+        } else {
+            self.errors.push(Diagnostic::new(
+                CompilationError::UnterminatedString,
+                Span {
+                    len: s.chars().count() + 1,
+                    ..start
+                },
+            ));
         }
-        let s: String = self.chars.drain(..end).collect();
-        self.chars.pop_front();
-        Some(Token::StringConstant(s))
+        Token::StringConstant(s)
     }
 
-    pub fn advance(&mut self) -> Option<Token> {
-        if let Some(c) = self.chars.pop_front() {
-            if SYMBOLS.contains(&c) {
+    /// The next token along with where it is in the source
+    pub fn advance(&mut self) -> Option<(Token, Span)> {
+        loop {
+            let start = self.position();
+            let c = self.pop()?;
+            let token = if SYMBOLS.contains(&c) {
                 match c {
                     // String constant
-                    '"' => self.get_string(),
+                    '"' => self.get_string(start),
+                    '/' if self.advance_past_comment(start) => continue,
                     // Symbols
-                    _ => {
-                        if c == '/' && self.advance_past_comment() {
-                            self.advance()
-                        } else {
-                            Some(Token::Symbol(c))
-                        }
-                    }
+                    _ => Token::Symbol(c),
                 }
             // Integer constant
-            } else if c.is_numeric() {
-                let mut num = String::from(c);
-                let mut end = self.chars.len();
-                for (i, &c) in self.chars.iter().enumerate() {
-                    if !c.is_numeric() {
-                        end = i;
-                        break;
+            } else if c.is_ascii_digit() {
+                let num = format!("{c}{}", self.take_while(|c| c.is_ascii_digit()));
+                match num.parse::<i16>() {
+                    Ok(i) => Token::IntConstant(i),
+                    Err(_) => {
+                        let span = Span {
+                            len: num.len(),
+                            ..start
+                        };
+                        self.errors
+                            .push(Diagnostic::new(CompilationError::InvalidInt(num), span));
+                        continue;
                     }
                 }
-                num.extend(self.chars.drain(..end));
-                if let Ok(i) = num.parse::<i16>() {
-                    Some(Token::IntConstant(i))
-                } else {
-                    self.errors.push(CompilationError::InvalidInt);
-                    self.advance()
-                }
             // Keywords and Identifiers
             } else if c.is_alphabetic() || c == '_' {
-                let mut word = String::from(c);
-                let mut end = self.chars.len();
-                for (i, &c) in self.chars.iter().enumerate() {
-                    if !(c.is_alphanumeric() || c == '_') {
-                        end = i;
-                        break;
-                    }
-                }
-                word.extend(self.chars.drain(..end));
+                let word = format!(
+                    "{c}{}",
+                    self.take_while(|c| c.is_alphanumeric() || c == '_')
+                );
                 if let Some(&k) = KEYWORDS.get(word.as_str()) {
-                    Some(Token::Keyword(k))
+                    Token::Keyword(k)
                 } else {
-                    Some(Token::Identifier(word))
+                    Token::Identifier(word)
                 }
             } else if !c.is_whitespace() {
-                self.errors.push(CompilationError::UnrecognizedToken);
-                self.advance()
+                self.errors.push(Diagnostic::new(
+                    CompilationError::UnrecognizedToken(c),
+                    start,
+                ));
+                continue;
             } else {
-                self.advance()
-            }
-        } else {
-            None
+                continue;
+            };
+
+            // Everything but strings is on a single line, and strings stop at the end of one
+            let len = if self.line == start.line {
+                self.col - start.col
+            } else {
+                1
+            };
+            return Some((token, Span { len, ..start }));
         }
     }
 }
@@ -128,21 +178,21 @@ mod tests {
     #[test]
     fn test_keyword() {
         let mut tknzr = Tokenizer::new("class".chars().collect());
-        let token = tknzr.advance().expect("no token");
+        let (token, _) = tknzr.advance().expect("no token");
         assert_eq!(token, Keyword::Class);
     }
 
     #[test]
     fn test_symbol() {
         let mut tknzr = Tokenizer::new(String::from('('));
-        let token = tknzr.advance().expect("no token");
+        let (token, _) = tknzr.advance().expect("no token");
         assert_eq!(token, '(');
     }
 
     #[test]
     fn test_int() {
         let mut tknzr = Tokenizer::new("12364".chars().collect());
-        let token = tknzr.advance().expect("no token");
+        let (token, _) = tknzr.advance().expect("no token");
         assert_eq!(token, 12364);
     }
 
@@ -150,7 +200,7 @@ mod tests {
     fn test_identifier() {
         let s = "_helf12_3rd";
         let mut tknzr = Tokenizer::new(s.chars().collect());
-        let token = tknzr.advance().expect("no token");
+        let (token, _) = tknzr.advance().expect("no token");
         assert_eq!(token, Token::Identifier(String::from(s)));
     }
 
@@ -158,7 +208,7 @@ mod tests {
     fn test_string() {
         let s = "\"this is a string with a // comment in it and a /*/comment**/\"";
         let mut tknzr = Tokenizer::new(s.chars().collect());
-        let token = tknzr.advance().expect("no token");
+        let (token, _) = tknzr.advance().expect("no token");
         assert_eq!(
             token,
             String::from("this is a string with a // comment in it and a /*/comment**/")
@@ -169,7 +219,7 @@ mod tests {
     fn test_single_line_comment() {
         let s = "//Hello this is a comment\nvoid";
         let mut tknzr = Tokenizer::new(s.chars().collect());
-        let token = tknzr.advance().expect("no token");
+        let (token, _) = tknzr.advance().expect("no token");
         assert_eq!(token, Keyword::Void);
     }
 
@@ -177,7 +227,7 @@ mod tests {
     fn test_multi_line_comment() {
         let s = "/**Hello this is a comment\n\n\n**/let";
         let mut tknzr = Tokenizer::new(s.chars().collect());
-        let token = tknzr.advance().expect("no token");
+        let (token, _) = tknzr.advance().expect("no token");
         assert_eq!(token, Keyword::Let);
     }
     #[test]
@@ -185,10 +235,10 @@ mod tests {
         let s = "let do { } \"strings still work\" ;";
         let mut tknzr = Tokenizer::new(s.chars().collect());
         let mut tokens = vec![];
-        while let Some(t) = tknzr.advance() {
+        while let Some((t, _)) = tknzr.advance() {
             tokens.push(t);
         }
-        let t2 = vec![
+        let expected = vec![
             Token::Keyword(Keyword::Let),
             Token::Keyword(Keyword::Do),
             Token::Symbol('{'),
@@ -196,8 +246,67 @@ mod tests {
             Token::StringConstant(String::from("strings still work")),
             Token::Symbol(';'),
         ];
-        for i in 0..6 {
-            assert_eq!(&tokens[i], &t2[i]);
+        assert_eq!(tokens, expected);
+    }
+
+    #[test]
+    fn test_spans() {
+        let s = "class Main {\n  field int x;\n  \"ab\" 40000 $";
+        let mut tknzr = Tokenizer::new(s.to_string());
+        let mut spans = vec![];
+        while let Some((_, span)) = tknzr.advance() {
+            spans.push((span.line, span.col, span.len));
+        }
+        assert_eq!(
+            spans,
+            [
+                (1, 1, 5),
+                (1, 7, 4),
+                (1, 12, 1),
+                (2, 3, 5),
+                (2, 9, 3),
+                (2, 13, 1),
+                (2, 14, 1),
+                (3, 3, 4)
+            ]
+        );
+        let errors: Vec<_> = tknzr
+            .take_errors()
+            .into_iter()
+            .map(|d| (d.error, d.span.line, d.span.col))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (CompilationError::InvalidInt("40000".to_string()), 3, 8),
+                (CompilationError::UnrecognizedToken('$'), 3, 14)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unterminated() {
+        let s = "let\n  \"héllo\n/* never closed";
+        let mut tknzr = Tokenizer::new(s.to_string());
+        let mut tokens = vec![];
+        while let Some((t, _)) = tknzr.advance() {
+            tokens.push(t);
         }
+        assert_eq!(
+            tokens,
+            [Token::Keyword(Keyword::Let), Token::StringConstant("héllo".to_string())]
+        );
+        let errors: Vec<_> = tknzr
+            .take_errors()
+            .into_iter()
+            .map(|d| (d.error, d.span.line, d.span.col, d.span.len))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (CompilationError::UnterminatedString, 2, 3, 6),
+                (CompilationError::UnterminatedComment, 3, 1, 2)
+            ]
+        );
     }
 }
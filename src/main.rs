@@ -1,163 +1,748 @@
 #![allow(dead_code)]
 #![allow(clippy::unusual_byte_groupings)]
-//mod optimizer;
 mod asm;
 mod cpu;
 mod vm;
-//mod code_writer;
+mod code_writer;
 mod io;
-//mod jack_compiler;
-//mod tokens;
-//mod pong;
+mod jack_compiler;
+mod optimizer;
+mod tokens;
+mod pong;
 
-use anyhow::{Context, Result};
-use asm::Assembler;
-//use crate::jack_compiler::compilation_engine::CompilationEngine;
+use anyhow::{anyhow, bail, Context, Result};
+use asm::{
+    diagnostic::Location,
+    listing::{write_listing, Listing},
+    parse_asm,
+    rom::{read_ram_image, read_rom, write_ram_image, write_rom, RomFormat},
+    usage::RomUsage,
+    Asm, AsmSource, Assembler, Instruction,
+};
 use clap::{Args, Parser, Subcommand};
-use cpu::Cpu;
+use cpu::{
+    debugger::Debugger,
+    headless::{parse_ram_range, parse_ram_set, parse_screen_hash},
+    recording::KeyRecording,
+    snapshot::Snapshot,
+    Cpu,
+};
 use io::{get_key, SCREEN_ROW_BYTES};
-use sdl2::{event::Event, pixels::Color};
-use std::path::PathBuf;
+use optimizer::{AsmOptimizer, AsmRule, Optimize};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use vm::debug_info::DebugInfo;
+use vm::optimizer::{VmOptimizer, VmRule};
+use vm::translator::{CodeSize, Translation};
 
 use crate::io::{as_pixels, SCREEN_PIXELS};
 
+/// Assembler, VM translator, Jack compiler and emulator for the Hack platform.
 #[derive(Debug, Parser)]
-pub struct ProgArgs {
-    path: PathBuf,
+#[command(name = "hack", version)]
+pub struct HackArgs {
+    #[command(subcommand)]
+    pub command: HackCommand,
 }
 
 #[derive(Debug, Subcommand)]
-pub enum HackArgs {
+pub enum HackCommand {
+    /// Assemble Hack assembly (`.asm`) into Hack machine code (`.hack`)
+    Assemble(AssembleArgs),
+    /// Turn Hack machine code (`.hack` or a binary ROM) back into Hack assembly
+    Disassemble(DisassembleArgs),
+    /// Translate VM code (`.vm`) into Hack assembly (`.asm`)
+    Translate(TranslateArgs),
+    /// Compile Jack classes (`.jack`) into VM code (`.vm`)
     Compile(CompileArgs),
+    /// Take a program from whatever sources are present all the way down to Hack machine code
+    Build(BuildArgs),
+    /// Build a program and run it in the emulator
+    Run(Box<RunArgs>),
+    /// Run VM code through both the VM emulator and the translated assembly, reporting where they first disagree
+    Diff(DiffArgs),
+    /// Run Nand2Tetris CPU emulator test scripts (`.tst`) and compare their output
+    Test(TestArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct AssembleArgs {
+    /// Path to an `.asm` file, or a directory of them
+    pub path: PathBuf,
+    /// Where to write the `.hack` file, defaults to next to the source
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    #[command(flatten)]
+    pub rom: RomArgs,
+    /// Make an `@symbol` that is neither a label nor built in an error, instead of a new variable
+    #[arg(long)]
+    pub no_implicit_variables: bool,
+    /// Also write where every instruction and symbol ended up to this file, as JSON if it ends in `.json`
+    #[arg(long)]
+    pub listing: Option<PathBuf>,
+    /// Put `.data` and `.string` tables in this RAM image for `run --ram-image`,
+    /// instead of assembling code that writes them when the program starts
+    #[arg(long)]
+    pub ram_image: Option<PathBuf>,
+    /// Print how many instructions each source file takes up
+    #[arg(long)]
+    pub size_report: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct DisassembleArgs {
+    /// Path to a `.hack` file or binary ROM image
+    pub path: PathBuf,
+    /// Where to write the `.asm` file, defaults to printing it
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    #[command(flatten)]
+    pub rom: RomArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct TranslateArgs {
+    /// Path to a `.vm` file, or a directory of them
+    pub path: PathBuf,
+    /// Where to write the `.asm` file, defaults to next to the source
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    #[command(flatten)]
+    pub bootstrap: BootstrapArgs,
+    #[command(flatten)]
+    pub optimize: OptimizeArgs,
+    /// Print how many instructions each source file and VM function takes up, largest first
+    #[arg(long)]
+    pub size_report: bool,
 }
 
 #[derive(Debug, Args)]
 pub struct CompileArgs {
-    /// Path to the file to be compiled
+    /// Path to the file to be compiled, or a directory of them
     pub path: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct BuildArgs {
+    /// Path to a Jack project directory, or a single `.vm`/`.asm` source
+    pub path: PathBuf,
+    #[command(flatten)]
+    pub bootstrap: BootstrapArgs,
+    #[command(flatten)]
+    pub optimize: OptimizeArgs,
+    #[command(flatten)]
+    pub rom: RomArgs,
+    /// Also write where every instruction and symbol ended up to this file, as JSON if it ends in `.json`
+    #[arg(long)]
+    pub listing: Option<PathBuf>,
+    /// Print how many instructions each source file and VM function takes up, largest first
+    #[arg(long)]
+    pub size_report: bool,
+    /// Also write a `.dbg` file next to the ROM mapping it back to the VM and Jack sources, for `run --debug`
+    #[arg(long)]
+    pub debug_info: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    /// Path to a Jack project directory, or a single `.vm`/`.asm` source or `.hack`/`.bin` ROM
+    pub path: PathBuf,
+    #[command(flatten)]
+    pub bootstrap: BootstrapArgs,
+    #[command(flatten)]
+    pub optimize: OptimizeArgs,
+    #[command(flatten)]
+    pub rom: RomArgs,
+    #[command(flatten)]
+    pub headless: HeadlessArgs,
+    /// Interpret the `.vm` files directly instead of translating them, `--max-ticks` then counts VM commands
+    #[arg(long, requires = "headless")]
     pub vm: bool,
+    /// Load RAM from this image before starting, as written by `assemble --ram-image`
+    #[arg(long, conflicts_with = "vm")]
+    pub ram_image: Option<PathBuf>,
+    /// Start from a save state instead, as written by `--save-state` or F5 in the window
+    #[arg(long, conflicts_with = "vm")]
+    pub load_state: Option<PathBuf>,
+    /// Save the machine state to this file once stopped
+    #[arg(long, requires = "headless", conflicts_with = "vm")]
+    pub save_state: Option<PathBuf>,
+    /// Where F5 saves the machine state in the window and F9 loads it from [default: next to the program, as `.state`]
+    #[arg(long, conflicts_with_all = ["headless", "debug"])]
+    pub state: Option<PathBuf>,
+    /// Write the keys pressed in the window to this file when it is closed, with the tick each came on
+    #[arg(long, conflicts_with_all = ["headless", "debug", "replay"])]
+    pub record: Option<PathBuf>,
+    /// Press the keys from a file written by `--record` instead of reading the keyboard
+    #[arg(long, conflicts_with_all = ["vm", "debug"])]
+    pub replay: Option<PathBuf>,
+    /// Step through the program in a debugger on the terminal instead of opening a window
+    #[arg(long, conflicts_with_all = ["headless", "vm"])]
+    pub debug: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct HeadlessArgs {
+    /// Run without a display until the program halts with `(END) @END 0;JMP`
+    #[arg(long)]
+    pub headless: bool,
+    /// Stop after this many CPU cycles even if the program has not halted
+    #[arg(long, requires = "headless")]
+    pub max_ticks: Option<u64>,
+    /// RAM to print once stopped, as an address (`256`) or range (`0..16`, `16384..=16415`)
+    #[arg(long = "dump", value_parser = parse_ram_range, requires = "headless")]
+    pub dumps: Vec<Range<usize>>,
+    /// Write the screen to this file as a PBM image once stopped
+    #[arg(long, requires = "headless")]
+    pub screen: Option<PathBuf>,
+    /// Fail unless the screen hash printed once stopped is this one, in hex
+    #[arg(long, value_parser = parse_screen_hash, requires = "headless")]
+    pub expect_screen: Option<u64>,
+}
+
+#[derive(Debug, Args)]
+pub struct DiffArgs {
+    /// Path to a `.vm` file, or a directory of them
+    pub path: PathBuf,
+    #[command(flatten)]
+    pub bootstrap: BootstrapArgs,
+    /// Stop comparing after this many VM commands
+    #[arg(long, default_value_t = 1_000_000)]
+    pub max_steps: u64,
+    /// Initial RAM contents for both machines, as `ADDR=VALUE` (`0=256`)
+    #[arg(long = "set", value_parser = parse_ram_set)]
+    pub setup: Vec<(usize, i16)>,
+}
+
+#[derive(Debug, Args)]
+pub struct TestArgs {
+    /// Path to a `.tst` script, or a directory of them
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct BootstrapArgs {
+    /// Do not emit the bootstrap code that sets up the stack and calls `Sys.init`
+    ///
+    /// By default it is only emitted if one of the translated files is `Sys.vm`
+    #[arg(long)]
+    pub no_bootstrap: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct OptimizeArgs {
+    /// Optimize VM commands before translating them, and run the peephole optimizer over the
+    /// translated assembly, printing how much each rule saved
+    #[arg(long)]
+    pub optimize: bool,
+    /// Leave one of the assembly optimizer's rules out, can be given more than once
+    #[arg(long = "no-rule", value_enum, requires = "optimize")]
+    pub disabled: Vec<AsmRule>,
+    /// Leave one of the VM optimizer's rules out, can be given more than once
+    #[arg(long = "no-vm-rule", value_enum, requires = "optimize")]
+    pub disabled_vm: Vec<VmRule>,
+}
+
+#[derive(Debug, Args)]
+pub struct RomArgs {
+    /// How the ROM is stored, defaults to `.hack` text or, for files not ending in `.hack`, a little-endian image
+    #[arg(long, value_enum)]
+    pub format: Option<RomFormat>,
+}
+
+impl RomArgs {
+    /// Where a ROM built from `path` goes if no output was given
+    fn output_path(&self, path: &Path) -> PathBuf {
+        output_path(path, self.format.unwrap_or(RomFormat::Hack).extension())
+    }
+}
+
+impl OptimizeArgs {
+    /// Translates VM files, optimizing the commands first if asked to
+    fn translate(&self, files: &[PathBuf], bootstrap: bool) -> Result<Translation> {
+        if !self.optimize {
+            return vm::translator::translate_vm(files, bootstrap, None);
+        }
+        let mut optimizer = VmOptimizer::new().without(&self.disabled_vm);
+        let translation = vm::translator::translate_vm(files, bootstrap, Some(&mut optimizer))?;
+        for (rule, saved) in &optimizer.saved {
+            eprintln!("{rule}: saved {saved} VM commands");
+        }
+        eprintln!("VM optimizer saved {} commands", optimizer.total_saved());
+        Ok(translation)
+    }
+
+    fn apply<'a>(&self, asm: Vec<Asm<'a>>) -> Vec<Asm<'a>> {
+        if !self.optimize {
+            return asm;
+        }
+        let mut optimizer = AsmOptimizer::new().without(&self.disabled);
+        let asm = optimizer.optimize(asm);
+        for (rule, saved) in &optimizer.saved {
+            eprintln!("{rule}: saved {saved} instructions");
+        }
+        eprintln!("optimizer saved {} instructions", optimizer.total_saved());
+        asm
+    }
+}
+
+impl BootstrapArgs {
+    fn wanted(&self, vm_files: &[PathBuf]) -> bool {
+        !self.no_bootstrap
+            && vm_files
+                .iter()
+                .any(|f| f.file_stem().is_some_and(|s| s == "Sys"))
+    }
 }
 
 fn main() -> Result<()> {
-    let args = ProgArgs::parse();
-    // let args: Vec<String> = std::env::args().collect();
-    let mut files: Vec<PathBuf> = vec![];
-    // let file_path = Path::new(&args[1]);
-    // //let mut parser = CompilationEngine::new();
-    if args.path.is_dir() {
-        for entry in args.path.read_dir().unwrap() {
-            if let Some(x) = entry.as_ref().unwrap().path().extension() {
-                match x.to_str().unwrap() {
-                    "jack" => todo!(),
-                    "vm" => todo!(),
-                    "asm" => files.push(entry.as_ref()?.path()),
+    match HackArgs::parse().command {
+        HackCommand::Assemble(args) => {
+            let files = source_files(&args.path, "asm")?;
+            let program = assemble(
+                &files,
+                !args.no_implicit_variables,
+                args.listing.as_deref(),
+                args.ram_image.as_deref(),
+                args.size_report,
+            )?;
+            let output = args
+                .output
+                .unwrap_or_else(|| args.rom.output_path(&args.path));
+            write_rom(&output, &program.rom, args.rom.format)
+        }
+        HackCommand::Disassemble(args) => {
+            let rom = read_rom(&args.path, args.rom.format)?;
+            let asm = asm::disassembler::disassemble(&rom)?;
+            match &args.output {
+                Some(path) => write_asm(path, &asm),
+                None => write_asm_to(std::io::stdout().lock(), &asm),
+            }
+        }
+        HackCommand::Translate(args) => {
+            let files = source_files(&args.path, "vm")?;
+            let translation = args
+                .optimize
+                .translate(&files, args.bootstrap.wanted(&files))?;
+            let asm = args.optimize.apply(translation.asm);
+            check_vm_size(&translation.sizes, &asm, args.size_report)?;
+            write_asm(
+                &args
+                    .output
+                    .unwrap_or_else(|| output_path(&args.path, "asm")),
+                &asm,
+            )
+        }
+        HackCommand::Compile(args) => compile(&args.path),
+        HackCommand::Build(args) => {
+            let program = build(
+                &args.path,
+                &args.bootstrap,
+                &args.optimize,
+                args.listing.as_deref(),
+                args.size_report,
+            )?;
+            let output = args.rom.output_path(&args.path);
+            write_rom(&output, &program.rom, args.rom.format)?;
+            if args.debug_info {
+                match &program.debug_info {
+                    Some(info) => info.write(&output.with_extension("dbg"))?,
+                    None => eprintln!(
+                        "only programs translated from VM code have debug info, \
+                         use --listing for assembly"
+                    ),
+                }
+            }
+            Ok(())
+        }
+        HackCommand::Run(args) if args.vm => run_vm(&args),
+        HackCommand::Run(args) => {
+            let is_rom = args.path.is_file()
+                && (args.rom.format.is_some()
+                    || args
+                        .path
+                        .extension()
+                        .is_some_and(|e| e == "hack" || e == "bin"));
+            let program = if is_rom {
+                read_program(&args.path, args.rom.format)?
+            } else {
+                build(&args.path, &args.bootstrap, &args.optimize, None, false)?
+            };
+            let ram = match &args.ram_image {
+                Some(path) => read_ram_image(path)?,
+                None => vec![],
+            };
+            let state = match &args.load_state {
+                Some(path) => Some(Snapshot::read(path)?),
+                None => None,
+            };
+            let cpu = start(&program.rom, &ram, state.as_ref())?;
+            let keys = match &args.replay {
+                Some(path) => Some(KeyRecording::read(path)?),
+                None => None,
+            };
+            if args.debug {
+                let mut debugger = Debugger::new(&program.rom, &program.listing);
+                debugger.debug_info = program.debug_info.as_ref();
+                debugger.cpu = cpu;
+                debugger.repl(std::io::stdin().lock(), std::io::stdout().lock())
+            } else if args.headless.headless {
+                let keys = keys.unwrap_or_default();
+                run_headless(cpu, &args.headless, &keys, args.save_state.as_deref())
+            } else {
+                run(cpu, &args, keys.as_ref())
+            }
+        }
+        HackCommand::Diff(args) => {
+            let files = source_files(&args.path, "vm")?;
+            if files.is_empty() {
+                bail!("no VM sources found at {}", args.path.display());
+            }
+            let sources = vm::emulator::read_sources(&files)?;
+            match vm::differential::run_differential(
+                &sources,
+                args.bootstrap.wanted(&files),
+                &args.setup,
+                args.max_steps,
+            )? {
+                Ok(steps) => {
+                    println!("no divergence after {steps} VM commands");
+                    Ok(())
                 }
-                // if x.to_str().unwrap() == "jack" {
-                //     files.push(entry.as_ref().unwrap().path())
-                // }
+                Err(divergence) => bail!("{divergence}"),
             }
         }
-    } else if let Some("asm") = file_path.extension().unwrap().to_str() {
-        files.push(file_path.to_path_buf())
+        HackCommand::Test(args) => {
+            let scripts = source_files(&args.path, "tst")?;
+            if scripts.is_empty() {
+                bail!("no test scripts found at {}", args.path.display());
+            }
+            let mut failed = 0;
+            for script in &scripts {
+                match cpu::test_script::run_script(script) {
+                    Ok(()) => println!(
+                        "{}: End of script - Comparison ended successfully",
+                        script.display()
+                    ),
+                    Err(e) => {
+                        println!("{}: {e:#}", script.display());
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                bail!("{failed} of {} test scripts failed", scripts.len());
+            }
+            Ok(())
+        }
     }
-    
-    let sdl_context = sdl2::init().context("Could not initialize SDL")?;
-    let video_subsys = sdl_context.video().context("Could not initialize video subsystem")?;
-    
+}
+
+/// Collects every file with the given extension at `path`, which can either be the file itself or a directory.
+///
+/// Files are sorted so that output is stable across platforms.
+fn source_files(path: &Path, ext: &str) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    if path.is_dir() {
+        for entry in path
+            .read_dir()
+            .with_context(|| format!("could not read {}", path.display()))?
+        {
+            let file = entry?.path();
+            if file.extension().is_some_and(|e| e == ext) {
+                files.push(file);
+            }
+        }
+        files.sort();
+    } else if path.extension().is_some_and(|e| e == ext) {
+        files.push(path.to_path_buf());
+    }
+    Ok(files)
+}
+
+/// The default output for a source file is next to it, and for a directory `Foo/` it is `Foo/Foo.<ext>`
+fn output_path(path: &Path, ext: &str) -> PathBuf {
+    if path.is_dir() {
+        let name = path.file_name().unwrap_or(path.as_os_str());
+        path.join(name).with_extension(ext)
+    } else {
+        path.with_extension(ext)
+    }
+}
+
+/// A program ready to run, along with what the debugger needs to show where it came from.
+struct Program {
+    rom: Vec<Instruction>,
+    listing: Listing,
+    /// Only for programs translated from VM code
+    debug_info: Option<DebugInfo>,
+}
+
+/// Takes whatever sources are found at `path` down to Hack machine code.
+///
+/// Jack classes are compiled to `.vm` files on disk first, as the Java tools would, and the rest happens in memory.
+fn build(
+    path: &Path,
+    bootstrap: &BootstrapArgs,
+    optimize: &OptimizeArgs,
+    listing: Option<&Path>,
+    size_report: bool,
+) -> Result<Program> {
+    if !source_files(path, "jack")?.is_empty() {
+        compile(path)?;
+    }
+
+    let vm_files = source_files(path, "vm")?;
+    if !vm_files.is_empty() {
+        let translation = optimize.translate(&vm_files, bootstrap.wanted(&vm_files))?;
+        let asm = optimize.apply(translation.asm);
+        check_vm_size(&translation.sizes, &asm, size_report)?;
+        let mut assembler = Assembler::new();
+        let hack = assembler.assemble(&asm)?;
+        let assembly = assembler.listing(&asm, &Location::generated(asm.len()), &hack);
+        if let Some(path) = listing {
+            write_listing(path, &assembly)?;
+        }
+        return Ok(Program {
+            debug_info: Some(DebugInfo::new(&asm, translation.marks)),
+            rom: hack,
+            listing: assembly,
+        });
+    }
+
+    let asm_files = source_files(path, "asm")?;
+    if asm_files.is_empty() {
+        bail!(
+            "no Jack, VM or assembly sources found at {}",
+            path.display()
+        );
+    }
+    assemble(&asm_files, true, listing, None, size_report)
+}
+
+fn compile(path: &Path) -> Result<()> {
+    let files = source_files(path, "jack")?;
+    if files.is_empty() {
+        bail!("no Jack sources found at {}", path.display());
+    }
+    jack_compiler::compile_jack(&files)?;
+    Ok(())
+}
+
+/// Assembles the files as one program, printing any warnings and writing a listing if asked for one.
+///
+/// With a `ram_image`, data tables are written there instead of being assembled.
+fn assemble(
+    files: &[PathBuf],
+    implicit_variables: bool,
+    listing: Option<&Path>,
+    ram_image: Option<&Path>,
+    size_report: bool,
+) -> Result<Program> {
+    let mut source = AsmSource::default();
+    for file in files {
+        source.extend(parse_asm(file, ram_image.is_some())?);
+    }
+    if let Some(path) = ram_image {
+        write_ram_image(path, &source.ram_image())?;
+    }
+    if size_report {
+        RomUsage::by_file(&source.asm, &source.locations).write(std::io::stdout().lock(), "File")?;
+    }
+    let mut assembler = Assembler::new();
+    assembler.implicit_variables = implicit_variables;
+    let hack = assembler.assemble_source(&source)?;
+    for warning in &assembler.warnings {
+        eprintln!("{warning}");
+    }
+    let assembly = assembler.listing(&source.asm, &source.locations, &hack);
+    if let Some(path) = listing {
+        write_listing(path, &assembly)?;
+    }
+    Ok(Program {
+        rom: hack,
+        listing: assembly,
+        debug_info: None,
+    })
+}
+
+/// Reads a ROM without its sources, listing it by assembling it back from its disassembly.
+///
+/// Any debug info written next to it by `build --debug-info` is read as well.
+fn read_program(path: &Path, format: Option<RomFormat>) -> Result<Program> {
+    let rom = read_rom(path, format)?;
+    let asm = asm::disassembler::disassemble(&rom)?;
+    let mut assembler = Assembler::new();
+    let hack = assembler.assemble(&asm)?;
+    let listing = assembler.listing(&asm, &Location::generated(asm.len()), &hack);
+    let debug_path = path.with_extension("dbg");
+    let debug_info = if debug_path.is_file() {
+        Some(DebugInfo::read(&debug_path)?)
+    } else {
+        None
+    };
+    Ok(Program {
+        rom,
+        listing,
+        debug_info,
+    })
+}
+
+/// Prints how much of ROM each file and function was translated into if asked to,
+/// and fails naming the largest functions if the program does not fit.
+fn check_vm_size(sizes: &[CodeSize], asm: &[Asm], size_report: bool) -> Result<()> {
+    let functions = RomUsage::new(sizes.iter().map(|s| (s.function.clone(), s.instructions)));
+    if size_report {
+        let files = RomUsage::new(sizes.iter().map(|s| (s.file.clone(), s.instructions)));
+        let mut out = std::io::stdout().lock();
+        files.write(&mut out, "File")?;
+        writeln!(out)?;
+        functions.write(&mut out, "Function")?;
+    }
+    // The sizes are from before the peephole optimizer, which may have made the program fit
+    let total = asm
+        .iter()
+        .filter(|line| matches!(line, Asm::At(_) | Asm::Asm(_)))
+        .count();
+    RomUsage { total, ..functions }.check()
+}
+
+fn write_asm(path: &Path, asm: &[Asm]) -> Result<()> {
+    write_asm_to(
+        BufWriter::new(
+            File::create(path).with_context(|| format!("could not create {}", path.display()))?,
+        ),
+        asm,
+    )
+}
+
+fn write_asm_to(mut out: impl Write, asm: &[Asm]) -> Result<()> {
+    for line in asm {
+        match line {
+            Asm::Label(_) => writeln!(out, "{line}")?,
+            _ => writeln!(out, "    {line}")?,
+        }
+    }
+    Ok(out.flush()?)
+}
+
+/// A CPU about to run `program` from the given RAM, or from a save state if there is one.
+fn start<'a>(
+    program: &'a [Instruction],
+    ram: &[i16],
+    state: Option<&Snapshot>,
+) -> Result<Cpu<'a>> {
+    let mut cpu = Cpu::new(program);
+    cpu.ram[..ram.len()].copy_from_slice(ram);
+    if let Some(state) = state {
+        state.restore(&mut cpu)?;
+    }
+    Ok(cpu)
+}
+
+/// Runs the program without a display, then reports whatever state was asked for.
+fn run_headless(
+    mut cpu: Cpu,
+    args: &HeadlessArgs,
+    keys: &KeyRecording,
+    save_state: Option<&Path>,
+) -> Result<()> {
+    let (stop, ticks) = cpu.run_replaying(args.max_ticks, keys)?;
+
+    let mut out = std::io::stdout().lock();
+    writeln!(out, "{stop} after {ticks} ticks")?;
+    cpu.dump_registers(&mut out)?;
+    if let Some(path) = save_state {
+        Snapshot::take(&cpu).write(path)?;
+    }
+    report(&cpu, args)
+}
+
+/// Interprets VM code directly, compiling any Jack classes first, then reports like [`run_headless`].
+fn run_vm(args: &RunArgs) -> Result<()> {
+    if !source_files(&args.path, "jack")?.is_empty() {
+        compile(&args.path)?;
+    }
+    let files = source_files(&args.path, "vm")?;
+    if files.is_empty() {
+        bail!("no VM sources found at {}", args.path.display());
+    }
+    let sources = vm::emulator::read_sources(&files)?;
+    let program = vm::emulator::VmProgram::new(&sources)?;
+    let mut emu = vm::emulator::VmEmulator::new(&program, args.bootstrap.wanted(&files))?;
+    let (stop, steps) = emu.run(args.headless.max_ticks)?;
+
+    let mut out = std::io::stdout().lock();
+    writeln!(out, "{stop} after {steps} VM commands")?;
+    emu.dump_state(&mut out)?;
+    report(&emu.cpu, &args.headless)
+}
+
+/// Prints the requested RAM ranges and the screen hash, and writes the screen image if one was asked for.
+fn report(cpu: &Cpu, args: &HeadlessArgs) -> Result<()> {
+    let mut out = std::io::stdout().lock();
+    for range in &args.dumps {
+        cpu.dump_ram(range.clone(), &mut out)?;
+    }
+    let hash = cpu.screen_hash();
+    writeln!(out, "Screen hash: {hash:016x}")?;
+
+    if let Some(path) = &args.screen {
+        let mut file = BufWriter::new(
+            File::create(path).with_context(|| format!("could not create {}", path.display()))?,
+        );
+        cpu.write_pbm(&mut file)?;
+        file.flush()?;
+    }
+    if let Some(expected) = args.expect_screen {
+        if hash != expected {
+            bail!("the screen hash is {hash:016x}, not {expected:016x}");
+        }
+    }
+    Ok(())
+}
+
+/// Runs the program in the SDL emulator until the window is closed.
+///
+/// F5 saves the machine state and F9 restores it; the program sees neither key.
+/// The keys are played back from `keys` if given, and otherwise read from the keyboard and recorded.
+fn run(mut cpu: Cpu, args: &RunArgs, keys: Option<&KeyRecording>) -> Result<()> {
+    let state_path = match &args.state {
+        Some(path) => path.clone(),
+        None => output_path(&args.path, "state"),
+    };
+    let mut replay = keys.map(KeyRecording::replay);
+    let mut recording = KeyRecording::default();
+
+    let sdl_context = sdl2::init().map_err(|e| anyhow!("Could not initialize SDL: {e}"))?;
+    let video_subsys = sdl_context
+        .video()
+        .map_err(|e| anyhow!("Could not initialize video subsystem: {e}"))?;
+
     let window = video_subsys
         .window("Hack Emulator", 1024, 512)
         .position_centered()
-        .build();
-    
-        //.expect("Could not initialize window");
-
-    let mut canvas = window
-        .into_canvas()
         .build()?;
-        //.expect("Could not create canvas");
 
-    canvas.set_logical_size(512, 256)?;//.expect("Could not set logical size");
+    let mut canvas = window.into_canvas().build()?;
+
+    canvas
+        .set_logical_size(512, 256)
+        .map_err(|e| anyhow!("Could not set logical size: {e}"))?;
 
     let creator = canvas.texture_creator();
 
-    let mut screen = creator
-        .create_texture_streaming(Some(sdl2::pixels::PixelFormatEnum::RGB24), 512, 256)?;
+    let mut screen =
+        creator.create_texture_streaming(Some(sdl2::pixels::PixelFormatEnum::RGB24), 512, 256)?;
     screen.update(None, &[255; SCREEN_PIXELS], SCREEN_ROW_BYTES)?;
-    //let screen = Screen::new(canvas, texture);
 
-    let mut assembler = Assembler::new();
-    let mut asm = vec![];
-    for file in files {
-        asm.extend(assembler.assemble(file));
-    }
-    // let asm = assembler.assemble(&asm_macro::asm![
-    // ("START")
-    //     @KBD
-    //     D=M
-    //     @"PRESS"
-    //     D;JNE
-    //     @"NO_PRESS"
-    //     D;JEQ
-
-    // // fill = is_pressed ? -1 : 0
-    // ("PRESS")
-    //     @"fill"
-    //     M=-1
-    //     @"CHECK"
-    //     0;JMP
-    // ("NO_PRESS")
-    //     @"fill"
-    //     M=0
-
-    // // Only update screen if fill has changed since last fill
-    // ("CHECK")
-    //     @"last"
-    //     D=M
-    //     @"fill"
-    //     D=D-M
-    //     @"START"
-    //     D;JEQ
-
-    // // Initialize fill parameters
-    //     @8192
-    //     D=A
-    //     @"i"
-    //     M=D // i = 8192
-    //     @SCREEN
-    //     D=A
-    //     @"address"
-    //     M=D // address = start of screen RAM
-    //     @"fill"
-    //     D=M
-    //     @"last"
-    //     M=D     // filling, so set last fill to current
-
-    // ("FILL_LOOP")
-    //     @"i"
-    //     D=M
-    //     @"START"
-    //     D;JLE // while i > 0
-
-    //     @"fill"
-    //     D=M
-    //     @"address"
-    //     A=M
-    //     M=D // addr = fill
-
-    //     @"i"
-    //     M=M-1 // i--
-    //     @"address"
-    //     M=M+1 // addr++
-    //     @"FILL_LOOP"
-    //     0;JMP
-
-    // ]);
-
-    
-    let mut cpu = Cpu::new(&asm);
-    let mut event_pump = sdl_context.event_pump()?;
+    let mut event_pump = sdl_context.event_pump().map_err(|e| anyhow!(e))?;
     let mut last_frame = std::time::Instant::now();
-    let mut ticks = 0;
+    let mut ticks: u64 = 0;
     let start = last_frame;
     let mut frames = 0;
     let mut buf = Vec::with_capacity(SCREEN_PIXELS);
@@ -168,23 +753,59 @@ fn main() -> Result<()> {
                 buf.extend(as_pixels(cpu.ram[addr]));
             }
             screen.update(None, &buf, SCREEN_ROW_BYTES)?;
-            canvas.copy(&screen, None, None)?;
+            canvas.copy(&screen, None, None).map_err(|e| anyhow!(e))?;
             canvas.present();
             last_frame = std::time::Instant::now();
             frames += 1;
             while let Some(event) = event_pump.poll_event() {
                 match event {
                     Event::Quit { .. } => break 'running,
-                    Event::KeyDown { .. } | Event::KeyUp { .. } => {
-                        cpu.set_kbd(get_key(event_pump.keyboard_state()))
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F5),
+                        repeat: false,
+                        ..
+                    } => match Snapshot::take(&cpu).write(&state_path) {
+                        Ok(()) => eprintln!("Saved the state to {}", state_path.display()),
+                        Err(e) => eprintln!("{e:#}"),
+                    },
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F9),
+                        repeat: false,
+                        ..
+                    } => {
+                        // The recorded ticks would no longer line up with the program
+                        if replay.is_some() || args.record.is_some() {
+                            eprintln!("Cannot load a state while recording or replaying keys");
+                            continue;
+                        }
+                        match Snapshot::read(&state_path).and_then(|s| s.restore(&mut cpu)) {
+                            Ok(()) => eprintln!("Loaded the state from {}", state_path.display()),
+                            Err(e) => eprintln!("{e:#}"),
+                        }
+                    }
+                    Event::KeyDown {
+                        keycode: Some(Keycode::F5 | Keycode::F9),
+                        ..
+                    } => {}
+                    Event::KeyDown { .. } | Event::KeyUp { .. } if replay.is_none() => {
+                        let key = get_key(event_pump.keyboard_state());
+                        cpu.set_kbd(key);
+                        recording.record(ticks, key);
                     }
                     _ => {}
                 }
             }
         }
+        if let Some(replay) = &mut replay {
+            replay.apply(&mut cpu, ticks);
+        }
         cpu.tick()?;
         ticks += 1;
     }
+    if let Some(path) = &args.record {
+        recording.write(path)?;
+        eprintln!("Recorded {} key changes to {}", recording.changes.len(), path.display());
+    }
     let elapsed = start.elapsed().as_secs_f64();
     println!("{}", frames as f64 / elapsed);
     println!("{}", ticks as f64 / elapsed);
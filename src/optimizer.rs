@@ -1,15 +1,413 @@
-use crate::asm::{Instruction, Asm};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
 
-pub(crate) trait Optimize {
-    type Item;
+use clap::ValueEnum;
 
-    fn optimize<I: Iterator<Item = Self::Item>>(data: &mut impl Iterator<Item = Self::Item>) -> T;
+use crate::asm::{parse_constant, Asm, Assembler, Comp, InstructionType, Jump};
+
+/// A rewrite of a whole program that keeps what it does.
+pub(crate) trait Optimize<T> {
+    fn optimize(&mut self, program: Vec<T>) -> Vec<T>;
+}
+
+/// The peephole rewrites [`AsmOptimizer`] knows, from `VM_Translation_Optimizations.md`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, ValueEnum)]
+pub enum AsmRule {
+    /// `push X` straight followed by `pop X` does nothing, so neither needs any code
+    SameSlotPushPop,
+    /// An unconditional jump to a label that starts with `@SP` can set `A=0` on the way instead,
+    /// as the jump happens before `A` is written
+    JumpIntoSp,
+    /// `@X` when `A` already holds `X`
+    RedundantLoad,
+}
+
+impl AsmRule {
+    /// Every rule, in the order they are applied
+    pub const ALL: [Self; 3] = [Self::SameSlotPushPop, Self::JumpIntoSp, Self::RedundantLoad];
+}
+
+impl Display for AsmRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.to_possible_value() {
+            Some(value) => f.pad(value.get_name()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A peephole optimizer for translated assembly.
+///
+/// Rules are applied over and over until none of them can save anything more.
+pub struct AsmOptimizer {
+    pub rules: HashSet<AsmRule>,
+    /// How many instructions each rule has removed so far
+    pub saved: BTreeMap<AsmRule, usize>,
+}
+
+impl AsmOptimizer {
+    /// An optimizer with every rule turned on
+    pub fn new() -> Self {
+        Self {
+            rules: AsmRule::ALL.into_iter().collect(),
+            saved: BTreeMap::new(),
+        }
+    }
+
+    pub fn without(mut self, rules: &[AsmRule]) -> Self {
+        for rule in rules {
+            self.rules.remove(rule);
+        }
+        self
+    }
+
+    pub fn total_saved(&self) -> usize {
+        self.saved.values().sum()
+    }
+}
+
+impl<'a> Optimize<Asm<'a>> for AsmOptimizer {
+    fn optimize(&mut self, mut asm: Vec<Asm<'a>>) -> Vec<Asm<'a>> {
+        loop {
+            let mut saved_any = false;
+            for rule in AsmRule::ALL {
+                if !self.rules.contains(&rule) {
+                    continue;
+                }
+                let before = instructions(&asm);
+                asm = match rule {
+                    AsmRule::SameSlotPushPop => same_slot_push_pop(asm),
+                    AsmRule::JumpIntoSp => jump_into_sp(asm),
+                    AsmRule::RedundantLoad => redundant_load(asm),
+                };
+                let saved = before - instructions(&asm);
+                if saved > 0 {
+                    *self.saved.entry(rule).or_default() += saved;
+                    saved_any = true;
+                }
+            }
+            if !saved_any {
+                return asm;
+            }
+        }
+    }
 }
 
-// struct AsmOptimizer {
-//     buf: Vec<Asm>,
-// }
+/// How many lines of assembly take up ROM
+fn instructions(asm: &[Asm]) -> usize {
+    asm.iter()
+        .filter(|line| matches!(line, Asm::At(_) | Asm::Asm(_)))
+        .count()
+}
 
-// impl AsmOptimizer {
+/// What an `@` instruction puts in `A`, with numbers and built in symbols resolved so that `@SP` and `@0` match
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Loaded<'s> {
+    Value(i16),
+    Symbol(&'s str),
+}
 
-// }
\ No newline at end of file
+fn loaded<'s>(line: &'s Asm) -> Option<Loaded<'s>> {
+    match line {
+        Asm::At(symbol) => Some(match parse_constant(symbol) {
+            Some(Ok(value)) => Loaded::Value(value),
+            _ => match Assembler::builtin(symbol) {
+                Some(value) => Loaded::Value(value),
+                None => Loaded::Symbol(symbol),
+            },
+        }),
+        Asm::Asm(inst) => match inst.get() {
+            Ok(InstructionType::A(value)) => Some(Loaded::Value(value.value() as i16)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Relies on the comments the translator writes before each VM command to find where commands start and end.
+fn same_slot_push_pop(asm: Vec<Asm>) -> Vec<Asm> {
+    // Where each command's code starts and ends, as the index of its comment and of the line after its last one
+    let mut commands: Vec<(usize, usize)> = vec![];
+    for (index, line) in asm.iter().enumerate() {
+        if matches!(line, Asm::Comment(_) | Asm::Label(_)) {
+            // A label ends a command too, but starts nothing
+            if let Some((_, end @ usize::MAX)) = commands.last_mut() {
+                *end = index;
+            }
+            if matches!(line, Asm::Comment(_)) {
+                commands.push((index, usize::MAX));
+            }
+        }
+    }
+    if let Some((_, end @ usize::MAX)) = commands.last_mut() {
+        *end = asm.len();
+    }
+
+    let mut removed = HashSet::new();
+    for pair in commands.windows(2) {
+        let [(push, push_end), (pop, pop_end)] = [pair[0], pair[1]];
+        if push_end != pop {
+            continue;
+        }
+        let (Asm::Comment(push_cmd), Asm::Comment(pop_cmd)) = (&asm[push], &asm[pop]) else {
+            continue;
+        };
+        let same_slot = match (push_cmd.strip_prefix("push "), pop_cmd.strip_prefix("pop ")) {
+            (Some(pushed), Some(popped)) => pushed == popped && !pushed.starts_with("constant"),
+            _ => false,
+        };
+        if same_slot {
+            removed.extend((push + 1..push_end).chain(pop + 1..pop_end));
+        }
+    }
+
+    asm.into_iter()
+        .enumerate()
+        // The comments stay, so that it is clear where the commands went
+        .filter(|(index, line)| !removed.contains(index) || matches!(line, Asm::Comment(_)))
+        .map(|(_, line)| line)
+        .collect()
+}
+
+fn jump_into_sp(mut asm: Vec<Asm>) -> Vec<Asm> {
+    let code: Vec<usize> = (0..asm.len())
+        .filter(|&i| matches!(asm[i], Asm::At(_) | Asm::Asm(_)))
+        .collect();
+    let is_jump_to = |i: usize| {
+        // `0;JMP` that does not already write A
+        matches!(asm[i], Asm::Asm(inst) if matches!(inst.get(), Ok(InstructionType::C(c))
+            if c.jump() == Jump::JMP && matches!(c.comp().get(), Some(Comp::Zero)) && !c.dest().a()))
+    };
+
+    // Every label and the instructions loading it
+    let mut uses: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (n, &i) in code.iter().enumerate() {
+        if let Asm::At(symbol) = &asm[i] {
+            uses.entry(symbol).or_default().push(n);
+        }
+    }
+
+    let mut jumps = vec![];
+    let mut dead = vec![];
+    for (n, &i) in code.iter().enumerate() {
+        if loaded(&asm[i]) != Some(Loaded::Value(0)) || n == 0 {
+            continue;
+        }
+        // Everything between the previous instruction and this one has to be labels only reached by `@L 0;JMP`,
+        // and the previous instruction has to jump away so that `@SP` is never reached by carrying on
+        let labels: Vec<&str> = (code[n - 1] + 1..i)
+            .filter_map(|j| match &asm[j] {
+                Asm::Label(label) => Some(label.as_ref()),
+                _ => None,
+            })
+            .collect();
+        let falls_through = !matches!(asm[code[n - 1]], Asm::Asm(inst)
+            if matches!(inst.get(), Ok(InstructionType::C(c)) if c.jump() == Jump::JMP));
+        if labels.is_empty() || falls_through {
+            continue;
+        }
+        let entries: Option<Vec<usize>> = labels
+            .iter()
+            .flat_map(|label| uses.get(label).into_iter().flatten())
+            .map(|&m| code.get(m + 1).copied().filter(|&j| is_jump_to(j)))
+            .collect();
+        if let Some(entries) = entries {
+            jumps.extend(entries);
+            dead.push(i);
+        }
+    }
+
+    for i in jumps {
+        if let Asm::Asm(inst) = &mut asm[i] {
+            if let Ok(InstructionType::C(c)) = inst.get() {
+                *inst = inst.with_c_inst(c.with_dest(c.dest().with_a(true)));
+            }
+        }
+    }
+    asm.into_iter()
+        .enumerate()
+        .filter(|(i, _)| !dead.contains(i))
+        .map(|(_, line)| line)
+        .collect()
+}
+
+fn redundant_load(asm: Vec<Asm>) -> Vec<Asm> {
+    let mut keep = vec![true; asm.len()];
+    let mut a = None;
+    for (i, line) in asm.iter().enumerate() {
+        match line {
+            Asm::Comment(_) => {}
+            // Anything could jump here, with anything in A
+            Asm::Label(_) => a = None,
+            Asm::At(_) | Asm::Asm(_) => match loaded(line) {
+                Some(value) if a.as_ref() == Some(&value) => keep[i] = false,
+                Some(value) => a = Some(value),
+                None => {
+                    let writes_a = match line {
+                        Asm::Asm(inst) => match inst.get() {
+                            Ok(InstructionType::C(c)) => c.dest().a(),
+                            _ => true,
+                        },
+                        _ => true,
+                    };
+                    if writes_a {
+                        a = None;
+                    }
+                }
+            },
+        }
+    }
+    asm.into_iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .map(|(line, _)| line)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::AsmSource;
+    use crate::cpu::Cpu;
+    use crate::vm::differential::first_difference;
+    use crate::vm::translator::translate_sources;
+
+    fn parse(text: &str) -> Vec<Asm<'static>> {
+        AsmSource::parse("", text).unwrap().asm
+    }
+
+    fn text(asm: &[Asm]) -> Vec<String> {
+        asm.iter()
+            .filter(|line| !matches!(line, Asm::Comment(_)))
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    fn optimize(rule: AsmRule, asm: Vec<Asm>) -> (Vec<String>, usize) {
+        let others: Vec<_> = AsmRule::ALL.into_iter().filter(|&r| r != rule).collect();
+        let mut optimizer = AsmOptimizer::new().without(&others);
+        let asm = optimizer.optimize(asm);
+        (text(&asm), optimizer.total_saved())
+    }
+
+    #[test]
+    fn test_redundant_load() {
+        let asm = parse("@0\nD=A\n@SP\nM=D+M\n@SP\nAM=M-1\n@SP\n(LOOP)\n@SP");
+        let (text, saved) = optimize(AsmRule::RedundantLoad, asm);
+        assert_eq!(
+            text,
+            ["@0", "D=A", "M=D+M", "AM=M-1", "@SP", "(LOOP)", "@SP"]
+        );
+        assert_eq!(saved, 2);
+    }
+
+    #[test]
+    fn test_jump_into_sp() {
+        let asm = parse("@TARGET\n0;JMP\n@END\n0;JMP\n(TARGET)\n@SP\nM=M+1\n(END)\n@END\n0;JMP");
+        let (text, saved) = optimize(AsmRule::JumpIntoSp, asm.clone());
+        assert_eq!(
+            text,
+            [
+                "@TARGET", "A=0;JMP", "@END", "0;JMP", "(TARGET)", "M=M+1", "(END)", "@END",
+                "0;JMP"
+            ]
+        );
+        assert_eq!(saved, 1);
+
+        // Not if anything else can get there
+        let mut fallthrough = asm.clone();
+        fallthrough[3] = parse("D;JGT").remove(0);
+        assert_eq!(optimize(AsmRule::JumpIntoSp, fallthrough).1, 0);
+        let mut loaded = asm;
+        loaded.extend(parse("@TARGET\nD=A"));
+        assert_eq!(optimize(AsmRule::JumpIntoSp, loaded).1, 0);
+    }
+
+    fn sources(src: &str) -> Vec<(String, String)> {
+        vec![("Main".to_string(), src.to_string())]
+    }
+
+    #[test]
+    fn test_same_slot_push_pop() {
+        // The whole of both commands goes, or none of it when the slots differ
+        for (vm, expected) in [
+            ("push local 1\npop local 1", 18),
+            ("push static 2\npop static 2", 11),
+            ("push temp 0\npop temp 0", 11),
+            ("push static 2\npop static 3", 0),
+        ] {
+            let asm = translate_sources(&sources(vm), false, None).unwrap().asm;
+            let (text, saved) = optimize(AsmRule::SameSlotPushPop, asm.clone());
+            assert_eq!(saved, expected, "{vm}");
+            assert_eq!(text.len(), instructions(&asm) - expected, "{vm}");
+        }
+
+        let src = sources(
+            "push constant 5\npop local 1\npush local 1\npop local 1\npush constant 9
+            pop static 2\npush static 2\npop static 3\npush temp 0\npop temp 0
+            label END\ngoto END",
+        );
+        let asm = translate_sources(&src, false, None).unwrap().asm;
+        let mut optimizer =
+            AsmOptimizer::new().without(&[AsmRule::JumpIntoSp, AsmRule::RedundantLoad]);
+        let optimized = optimizer.optimize(asm.clone());
+        assert_eq!(optimizer.total_saved(), 18 + 11);
+
+        let run = |asm: &[Asm]| {
+            let rom = Assembler::new().assemble(asm).unwrap();
+            let mut cpu = Cpu::new(&rom);
+            cpu.ram[..3].copy_from_slice(&[256, 300, 400]);
+            cpu.ram[5] = 3;
+            cpu.run_headless(Some(1000)).unwrap();
+            cpu.ram[..4096].to_vec()
+        };
+        let ram = run(&asm);
+        let optimized_ram = run(&optimized);
+        assert_eq!((ram[301], ram[16], ram[17], ram[5]), (5, 9, 9, 3));
+        assert_eq!(first_difference(&ram, &optimized_ram), None);
+    }
+
+    #[test]
+    fn test_optimized_program_agrees() {
+        let src = sources(
+            "function Main.main 2
+            push constant 10
+            pop local 0
+            push local 0
+            pop local 0
+            push constant 7
+            call Main.double 1
+            pop local 1
+            push local 1
+            push local 0
+            add
+            pop static 0
+            label END
+            goto END
+            function Main.double 0
+            push argument 0
+            push argument 0
+            add
+            return",
+        );
+        let asm = translate_sources(&src, false, None).unwrap().asm;
+        let mut optimizer = AsmOptimizer::new();
+        let optimized = optimizer.optimize(asm.clone());
+        assert!(optimizer.saved[&AsmRule::SameSlotPushPop] > 0);
+        assert!(optimizer.saved[&AsmRule::RedundantLoad] > 0);
+
+        let run = |asm: &[Asm]| {
+            let rom = Assembler::new().assemble(asm).unwrap();
+            let mut cpu = Cpu::new(&rom);
+            cpu.ram[..3].copy_from_slice(&[256, 300, 400]);
+            cpu.run_headless(Some(10_000)).unwrap();
+            (rom.len(), cpu.ram[..4096].to_vec())
+        };
+        let (before, ram) = run(&asm);
+        let (after, optimized_ram) = run(&optimized);
+        assert_eq!(before - after, optimizer.total_saved());
+        assert_eq!(optimized_ram[16], 24);
+        // Only the scratch registers differ, as R14 holds a ROM address
+        assert_eq!(first_difference(&ram, &optimized_ram), None);
+    }
+}
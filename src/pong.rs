@@ -1,7 +1,9 @@
-use crate::asm::Instruction;
-use std::{cell::OnceCell, sync::OnceLock};
+use crate::asm::{rom::parse_hack, Instruction};
+use std::sync::LazyLock;
 
-pub static PONG: OnceLock<Vec<Instruction>> = OnceLock::new(|| "0110000000000000
+pub static PONG: LazyLock<Vec<Instruction>> = LazyLock::new(|| {
+    parse_hack(
+        "0110000000000000
 1111110000010000
 0000000000000110
 1110001100000101
@@ -46,7 +48,7 @@ pub static PONG: OnceLock<Vec<Instruction>> = OnceLock::new(|| "0110000000000000
 1111110111001000
 0000000000011110
 1110101010000111
-"
-.lines()
-.map(|l| i16::from_str_radix(l, 2).map(Instruction::from).unwrap())
-.collect());
+",
+    )
+    .expect("the bundled Pong ROM is valid .hack")
+});
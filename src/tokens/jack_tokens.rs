@@ -2,6 +2,7 @@ use std::{
     collections::{HashMap, HashSet},
     fmt::{Debug, Display},
 };
+use lazy_static::lazy_static;
 use Keyword::*;
 
 use crate::tokens::token_type::TokenType;
@@ -15,6 +16,14 @@ pub enum Token {
     StringConstant(String),
 }
 
+/// Where a token is in its source file, with lines and columns counted from 1.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+}
+
 impl Token {
     pub fn as_type(&self) -> String {
         match self {
@@ -218,7 +227,6 @@ lazy_static! {
         hs.insert('=');
         hs.insert('~');
         hs.insert('"');
-        hs.insert('%');
         hs
     };
 }
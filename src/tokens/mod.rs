@@ -1,3 +1,2 @@
 pub(crate) mod jack_tokens;
 pub(crate) mod token_type;
-pub(crate) mod vm_commands;
@@ -5,7 +5,12 @@ use crate::tokens::jack_tokens::{
     Token,
 };
 
-pub trait ValidToken: Display + Debug + PartialEq<TokenType> {}
+pub trait ValidToken: Display + Debug + PartialEq<TokenType> {
+    /// How the token is referred to in an error message when it was expected
+    fn describe(&self) -> String {
+        format!("`{self}`")
+    }
+}
 impl ValidToken for Token {}
 impl ValidToken for Keyword {}
 impl ValidToken for char {}
@@ -24,7 +29,11 @@ pub enum TokenType {
     Type,
     ReturnType,
 }
-impl ValidToken for TokenType {}
+impl ValidToken for TokenType {
+    fn describe(&self) -> String {
+        format!("a {self}")
+    }
+}
 impl PartialEq<Token> for TokenType {
     fn eq(&self, other: &Token) -> bool {
         match other {
@@ -66,7 +75,7 @@ impl PartialEq<TokenType> for char {
             TokenType::BinaryOp => {
                 matches!(
                     self,
-                    '+' | '-' | '*' | '/' | '%' | '&' | '|' | '<' | '>' | '='
+                    '+' | '-' | '*' | '/' | '&' | '|' | '<' | '>' | '='
                 )
             }
             TokenType::UnaryOp => matches!(self, '-' | '~'),
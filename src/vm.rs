@@ -1,3 +1,7 @@
+pub mod debug_info;
+pub mod differential;
+pub mod emulator;
+pub mod optimizer;
 pub mod translator;
 
 //use std::borrow::Cow;
@@ -25,6 +29,57 @@ pub enum VmCommand<'a> {
     Function(&'a str, i16),
     Call(&'a str, i16),
     Return,
+    // Unofficial, only written by the optimizer
+    /// `push constant n` followed by a binary operation, done without pushing `n`
+    Immediate(BinaryOp, i16),
+    /// `push a i` followed by `pop b j`, copying the value without going through the stack.
+    /// A command refers to at most one static, so both can't be `static`.
+    Move(MemSegment, i16, MemSegment, i16),
+}
+
+/// The arithmetic commands that take two values off the stack and push one back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Compare(Comparison),
+}
+
+impl BinaryOp {
+    /// The operation a command performs, if it is a binary one
+    pub fn of(command: VmCommand) -> Option<Self> {
+        match command {
+            VmCommand::Add => Some(Self::Add),
+            VmCommand::Sub => Some(Self::Sub),
+            VmCommand::And => Some(Self::And),
+            VmCommand::Or => Some(Self::Or),
+            VmCommand::Compare(cmp) => Some(Self::Compare(cmp)),
+            _ => None,
+        }
+    }
+
+    /// `x op y`, where `y` was at the top of the stack
+    pub fn apply(self, x: i16, y: i16) -> i16 {
+        let result = match self {
+            Self::Add => return x.wrapping_add(y),
+            Self::Sub => return x.wrapping_sub(y),
+            Self::And => return x & y,
+            Self::Or => return x | y,
+            Self::Compare(Comparison::EQ) => x == y,
+            Self::Compare(Comparison::GT) => x > y,
+            Self::Compare(Comparison::LT) => x < y,
+            Self::Compare(Comparison::LE) => x <= y,
+            Self::Compare(Comparison::GE) => x >= y,
+            Self::Compare(Comparison::NE) => x != y,
+        };
+        if result {
+            -1
+        } else {
+            0
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -79,6 +134,18 @@ impl std::fmt::Display for MemSegment {
     }
 }
 
+impl std::fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Add => write!(f, "add"),
+            Self::Sub => write!(f, "sub"),
+            Self::And => write!(f, "and"),
+            Self::Or => write!(f, "or"),
+            Self::Compare(cmp) => write!(f, "{cmp}"),
+        }
+    }
+}
+
 impl std::fmt::Display for VmCommand<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -97,11 +164,13 @@ impl std::fmt::Display for VmCommand<'_> {
             VmCommand::Function(func, n) => write!(f, "function {func} {n}"),
             VmCommand::Call(func, n) => write!(f, "call {func} {n}"),
             VmCommand::Return => write!(f, "return"),
+            VmCommand::Immediate(op, n) => write!(f, "{op} constant {n}"),
+            VmCommand::Move(from, i, to, j) => write!(f, "move {from} {i} {to} {j}"),
         }
     }
 }
 
-pub fn parse(cmd: &str) -> Result<VmCommand> {
+pub fn parse(cmd: &str) -> Result<VmCommand<'_>> {
     use Comparison as Cmp;
     use MemSegment as Seg;
     //asm.push(code_writer::comment(cmd)); // comment with original vm command, stored separately so it can be skipped
@@ -127,9 +196,7 @@ pub fn parse(cmd: &str) -> Result<VmCommand> {
             _ => bail!("No two word command \"{cmd}\""),
         },
         3 => {
-            let arg = parts[2]
-                .parse::<i16>()
-                .map_err(|_| anyhow!("{} is not a valid 16 bit integer", parts[2]))?;
+            let arg = int(parts[2])?;
 
             match (parts[0], parts[1]) {
                 ("push", "local") => VmCommand::Push(Seg::Local, arg),
@@ -158,10 +225,46 @@ pub fn parse(cmd: &str) -> Result<VmCommand> {
                 ("function", _) => VmCommand::Function(parts[1], arg),
                 ("call", _) => VmCommand::Call(parts[1], arg),
 
+                (op, "constant") => match parse(op).ok().and_then(BinaryOp::of) {
+                    Some(op) => VmCommand::Immediate(op, arg),
+                    None => bail!("No three word command \"{cmd}\""),
+                },
+
                 _ => bail!("No three word command \"{cmd}\""),
             }
         }
+        5 if parts[0] == "move" => {
+            let (from, i) = (segment(parts[1])?, int(parts[2])?);
+            let (to, j) = (segment(parts[3])?, int(parts[4])?);
+            if to == Seg::Constant {
+                bail!("cannot move to the constant segment");
+            }
+            if from == Seg::Static && to == Seg::Static {
+                bail!("cannot move from one static to another in a single command");
+            }
+            VmCommand::Move(from, i, to, j)
+        }
         _ => bail!("\"{cmd}\" is not a valid VM command"),
     };
     Ok(command)
 }
+
+fn int(word: &str) -> Result<i16> {
+    word.parse::<i16>()
+        .map_err(|_| anyhow!("{word} is not a valid 16 bit integer"))
+}
+
+fn segment(word: &str) -> Result<MemSegment> {
+    use MemSegment as Seg;
+    Ok(match word {
+        "local" => Seg::Local,
+        "argument" => Seg::Argument,
+        "this" => Seg::This,
+        "that" => Seg::That,
+        "constant" => Seg::Constant,
+        "static" => Seg::Static,
+        "pointer" => Seg::Pointer,
+        "temp" => Seg::Temp,
+        _ => bail!("{word} is not a memory segment"),
+    })
+}
@@ -0,0 +1,274 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::translator::Mark;
+use crate::asm::Asm;
+
+/// The VM command some code was translated from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmSource {
+    /// The `.vm` file, such as `Main.vm`
+    pub file: String,
+    pub line: usize,
+    pub command: String,
+    /// The line of the `.jack` file of the same name the command was compiled from, if known
+    pub jack_line: Option<usize>,
+}
+
+/// Where the code from some ROM address onwards came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugEntry {
+    pub addr: usize,
+    /// The VM function, or what the code is for when it is outside of any
+    pub function: String,
+    /// `None` for code that no one command was translated into, such as a shared routine
+    pub source: Option<VmSource>,
+}
+
+impl Display for DebugEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.function)?;
+        let Some(source) = &self.source else {
+            return Ok(());
+        };
+        if let Some(jack_line) = source.jack_line {
+            let class = source.file.strip_suffix(".vm").unwrap_or(&source.file);
+            write!(f, " at {class}.jack:{jack_line}")?;
+        }
+        write!(f, " ({}:{}: {})", source.file, source.line, source.command)
+    }
+}
+
+/// Maps ROM addresses of a translated program back to its VM and Jack sources.
+///
+/// Written next to the ROM as a `.dbg` file, one tab separated entry per line:
+/// the address, the function, and for code from a VM command its file, line, Jack line (`-` if unknown) and text.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    /// In order of address
+    pub entries: Vec<DebugEntry>,
+}
+
+impl DebugInfo {
+    /// Finds where each of the translator's marks ended up in `asm`, which may have been optimized since.
+    ///
+    /// The asm optimizer keeps every comment, so the marks are found by counting them.
+    pub fn new(asm: &[Asm], marks: Vec<Mark>) -> Self {
+        let mut marks = marks.into_iter().peekable();
+        let mut entries = vec![];
+        let mut comments = 0;
+        let mut addr = 0;
+        for line in asm {
+            match line {
+                Asm::Comment(_) => {
+                    while let Some(mark) = marks.next_if(|m| m.comment == comments) {
+                        entries.push(DebugEntry {
+                            addr,
+                            function: mark.function,
+                            source: mark.source,
+                        });
+                    }
+                    comments += 1;
+                }
+                Asm::At(_) | Asm::Asm(_) => addr += 1,
+                _ => {}
+            }
+        }
+        // Such as where the code after a shared routine would have been, if there was any
+        entries.retain(|entry| entry.addr < addr);
+        Self { entries }
+    }
+
+    /// Where the code at `addr` came from
+    pub fn at(&self, addr: usize) -> Option<&DebugEntry> {
+        let after = self.entries.partition_point(|e| e.addr <= addr);
+        after.checked_sub(1).map(|i| &self.entries[i])
+    }
+
+    /// The call that returns to `addr`.
+    ///
+    /// The translator marks every return address with its call, ahead of whatever code follows.
+    pub fn returning_to(&self, addr: usize) -> Option<&DebugEntry> {
+        let first = self.entries.partition_point(|e| e.addr < addr);
+        self.entries
+            .get(first)
+            .filter(|e| e.addr == addr)
+            .or_else(|| self.at(addr))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut out = BufWriter::new(
+            File::create(path).with_context(|| format!("could not create {}", path.display()))?,
+        );
+        for entry in &self.entries {
+            write!(out, "{}\t{}", entry.addr, entry.function)?;
+            if let Some(source) = &entry.source {
+                let jack_line = match source.jack_line {
+                    Some(line) => line.to_string(),
+                    None => "-".to_string(),
+                };
+                write!(
+                    out,
+                    "\t{}\t{}\t{jack_line}\t{}",
+                    source.file, source.line, source.command
+                )?;
+            }
+            writeln!(out)?;
+        }
+        Ok(out.flush()?)
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("{} is not valid debug info", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let number = |word: &str| {
+            word.parse::<usize>()
+                .map_err(|_| anyhow!("`{word}` is not a number"))
+        };
+        let mut entries = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let fields: Vec<_> = line.split('\t').collect();
+            let source = match fields[..] {
+                [_, _] => None,
+                [_, _, file, line, jack_line, command] => Some(VmSource {
+                    file: file.to_string(),
+                    line: number(line)?,
+                    command: command.to_string(),
+                    jack_line: match jack_line {
+                        "-" => None,
+                        _ => Some(number(jack_line)?),
+                    },
+                }),
+                _ => bail!("line {} has {} fields, not 2 or 6", i + 1, fields.len()),
+            };
+            entries.push(DebugEntry {
+                addr: number(fields[0])?,
+                function: fields[1].to_string(),
+                source,
+            });
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// The file the Jack compiler writes next to a `.vm` file, holding the Jack line each of its lines came from
+pub fn jack_lines_path(vm_file: &Path) -> PathBuf {
+    vm_file.with_extension("vmmap")
+}
+
+pub fn write_jack_lines(vm_file: &Path, lines: &[usize]) -> Result<()> {
+    let path = jack_lines_path(vm_file);
+    let text: String = lines.iter().map(|line| format!("{line}\n")).collect();
+    std::fs::write(&path, text).with_context(|| format!("could not write {}", path.display()))
+}
+
+/// The Jack line each line of `vm_file` came from, if it was compiled from Jack.
+///
+/// A map that doesn't have a line for every line of `source` is out of date, and ignored.
+pub fn read_jack_lines(vm_file: &Path, source: &str) -> Result<Option<Vec<usize>>> {
+    let path = jack_lines_path(vm_file);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    let lines = text
+        .lines()
+        .map(|line| line.parse().ok())
+        .collect::<Option<Vec<usize>>>();
+    Ok(lines.filter(|lines| lines.len() == source.lines().count()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::diagnostic::Location;
+    use crate::asm::Assembler;
+    use crate::optimizer::{AsmOptimizer, Optimize};
+    use crate::vm::translator::translate_sources;
+
+    #[test]
+    fn test_debug_info() {
+        let src = [(
+            "Sys".to_string(),
+            "function Sys.init 0
+            push constant 4
+            call Sys.double 1
+            label END
+            goto END
+            function Sys.double 0
+            push argument 0
+            push argument 0
+            add
+            return"
+                .to_string(),
+        )];
+        let translation = translate_sources(&src, true, None).unwrap();
+        // The marks are still found once the optimizer has removed instructions from around them
+        let mut optimizer = AsmOptimizer::new();
+        let asm = optimizer.optimize(translation.asm);
+        assert!(optimizer.total_saved() > 0);
+        let info = DebugInfo::new(&asm, translation.marks);
+
+        let functions: Vec<_> = info.entries.iter().map(|e| e.function.as_str()).collect();
+        let mut expected = vec!["(bootstrap)", "$$CALL", "(bootstrap)"];
+        // The call is marked again where it returns to
+        expected.extend(["Sys.init"; 6]);
+        expected.extend(["Sys.double"; 5]);
+        expected.push("$$RETURN");
+        assert_eq!(functions, expected);
+
+        let mut assembler = Assembler::new();
+        let rom = assembler.assemble(&asm).unwrap();
+        let listing = assembler.listing(&asm, &Location::generated(asm.len()), &rom);
+        for entry in &info.entries {
+            if let Some(source) = &entry.source {
+                let comment = listing.lines[entry.addr].comment.as_deref().unwrap();
+                assert!(
+                    comment.contains(&source.command) || comment.starts_with("back from"),
+                    "{entry:?} at {comment}"
+                );
+            }
+        }
+        let return_addr = listing
+            .symbols
+            .iter()
+            .find(|s| s.name == "Sys.ret$1")
+            .unwrap()
+            .value as usize;
+        let call = info.returning_to(return_addr).unwrap();
+        assert_eq!(call.source.as_ref().unwrap().command, "call Sys.double 1");
+        let after = info.at(return_addr).unwrap();
+        assert_eq!(after.source.as_ref().unwrap().command, "goto END");
+        assert_eq!(info.at(0).unwrap().function, "(bootstrap)");
+        assert_eq!(info.at(rom.len() - 1).unwrap().function, "$$RETURN");
+        assert_eq!(
+            info.entries[4].to_string(),
+            "Sys.init (Sys.vm:2: push constant 4)"
+        );
+
+        let mut info = info;
+        for entry in &mut info.entries {
+            if let Some(source) = &mut entry.source {
+                source.jack_line = Some(source.line + 10);
+            }
+        }
+        assert_eq!(
+            info.entries[4].to_string(),
+            "Sys.init at Sys.jack:12 (Sys.vm:2: push constant 4)"
+        );
+        let path = std::env::temp_dir().join(format!("debug_info_{}.dbg", std::process::id()));
+        info.write(&path).unwrap();
+        assert_eq!(DebugInfo::read(&path).unwrap(), info);
+        std::fs::remove_file(&path).unwrap();
+        assert!(DebugInfo::parse("0\tSys.init\tSys.vm").is_err());
+    }
+}
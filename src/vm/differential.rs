@@ -0,0 +1,339 @@
+use std::fmt::Display;
+use std::iter::zip;
+
+use anyhow::{bail, Result};
+
+use super::emulator::{VmEmulator, VmProgram};
+use super::translator::{translate_sources, Translation, BOOTSTRAP_RETURN};
+use crate::asm::{Asm, Assembler};
+use crate::cpu::Cpu;
+
+/// R13-R15 are scratch registers for the translated assembly, and have no VM equivalent.
+const SCRATCH: std::ops::RangeInclusive<usize> = 13..=15;
+
+/// Where the stack ends and the heap begins, anything between the stack pointer and here is garbage.
+const STACK_END: usize = 2048;
+
+/// How many CPU cycles a single VM command may take before the assembly is considered lost.
+const TICKS_PER_COMMAND: u64 = 100_000;
+
+/// The first point at which the VM emulator and the translated assembly disagree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// How many VM commands had been executed, including the offending one
+    pub step: u64,
+    /// The offending command, as `File.vm: command`, or the bootstrap
+    pub command: String,
+    pub kind: DivergenceKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DivergenceKind {
+    /// The assembly never arrived at the ROM address of the next VM command
+    Lost { expected: usize, pc: usize },
+    /// Both arrived, but with different values in RAM
+    Ram { addr: usize, vm: i16, asm: i16 },
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "step {}, {}: ", self.step, self.command)?;
+        match self.kind {
+            DivergenceKind::Lost { expected, pc } => write!(
+                f,
+                "the assembly never reached the next command at ROM[{expected}] (stopped at ROM[{pc}])"
+            ),
+            DivergenceKind::Ram { addr, vm, asm } => write!(
+                f,
+                "{} is {vm} in the VM emulator but {asm} in the translated assembly",
+                ram_name(addr)
+            ),
+        }
+    }
+}
+
+fn ram_name(addr: usize) -> String {
+    match addr {
+        0 => "SP (RAM[0])".to_string(),
+        1 => "LCL (RAM[1])".to_string(),
+        2 => "ARG (RAM[2])".to_string(),
+        3 => "THIS (RAM[3])".to_string(),
+        4 => "THAT (RAM[4])".to_string(),
+        5..=12 => format!("temp {} (RAM[{addr}])", addr - 5),
+        _ => format!("RAM[{addr}]"),
+    }
+}
+
+/// The first address at which the two RAMs differ, ignoring scratch registers and the dead part of the stack.
+pub fn first_difference(vm: &[i16], asm: &[i16]) -> Option<usize> {
+    let len = vm.len().min(asm.len());
+    let sp = (vm[0] as u16 as usize).clamp(256, STACK_END);
+    [0..*SCRATCH.start(), SCRATCH.end() + 1..sp, STACK_END..len]
+        .into_iter()
+        .find_map(|range| {
+            let start = range.start;
+            // Comparing whole slices first is much faster than going address by address
+            (vm[range.clone()] != asm[range.clone()]).then(|| {
+                start
+                    + zip(&vm[range.clone()], &asm[range])
+                        .position(|(v, a)| v != a)
+                        .unwrap()
+            })
+        })
+}
+
+/// Runs a VM program through both the [`VmEmulator`] and the translated assembly on a [`Cpu`],
+/// comparing RAM every time both reach the start of a VM command.
+///
+/// `setup` is written to RAM on both machines before starting, like a test script's `set` commands.
+/// Returns the number of VM commands executed if no divergence was found within `max_steps`.
+pub fn run_differential(
+    sources: &[(String, String)],
+    bootstrap: bool,
+    setup: &[(usize, i16)],
+    max_steps: u64,
+) -> Result<std::result::Result<u64, Divergence>> {
+    let program = VmProgram::new(sources)?;
+    let Translation { asm, starts, .. } = translate_sources(sources, bootstrap, None)?;
+    let mut assembler = Assembler::new();
+    let rom = assembler.assemble(&asm)?;
+
+    // Labels and comments take no ROM, so the ROM address of an assembly line is the number of instructions before it
+    let mut rom_addrs = Vec::with_capacity(starts.len());
+    let mut count = 0;
+    let mut line = 0;
+    for start in starts {
+        while line < start {
+            if matches!(asm[line], Asm::At(_) | Asm::Asm(_)) {
+                count += 1;
+            }
+            line += 1;
+        }
+        rom_addrs.push(count);
+    }
+
+    // The program is over once the bootstrap's call to Sys.init returns, or it runs off the end without one
+    let end = match assembler.labels.get(BOOTSTRAP_RETURN) {
+        Some(&addr) if bootstrap => addr as usize,
+        _ => rom_addrs[program.len()],
+    };
+    let expected_at = |pc: usize| {
+        if pc < program.len() {
+            rom_addrs[pc]
+        } else {
+            end
+        }
+    };
+
+    let vm_addrs = (0..=program.len()).map(|pc| expected_at(pc) as i16);
+    let mut vm = VmEmulator::with_rom_addrs(&program, bootstrap, Some(vm_addrs.collect()))?;
+    let mut cpu = Cpu::new(&rom);
+    for &(addr, value) in setup {
+        vm.cpu.ram[addr] = value;
+        cpu.ram[addr] = value;
+    }
+
+    let mut command = String::from("bootstrap");
+    let mut step = 0;
+    // Whether the last command had any assembly, in which case the CPU has to move even if
+    // the next command starts where it already is (a loop back to the top of itself)
+    let mut must_move = false;
+    loop {
+        let expected = expected_at(vm.pc());
+        let mut ticks = 0;
+        while cpu.pc() != expected || must_move {
+            if ticks == TICKS_PER_COMMAND || cpu.tick().is_err() {
+                return Ok(Err(Divergence {
+                    step,
+                    command,
+                    kind: DivergenceKind::Lost {
+                        expected,
+                        pc: cpu.pc(),
+                    },
+                }));
+            }
+            ticks += 1;
+            must_move = false;
+        }
+
+        if let Some(addr) = first_difference(&vm.cpu.ram, &cpu.ram) {
+            return Ok(Err(Divergence {
+                step,
+                command,
+                kind: DivergenceKind::Ram {
+                    addr,
+                    vm: vm.cpu.ram[addr],
+                    asm: cpu.ram[addr],
+                },
+            }));
+        }
+
+        if vm.is_halted() || step == max_steps {
+            return Ok(Ok(step));
+        }
+        let Some((file, cmd)) = vm.current() else {
+            bail!("the VM emulator ran past the end of the program");
+        };
+        command = format!("{file}.vm: {cmd}");
+        must_move = rom_addrs[vm.pc() + 1] > rom_addrs[vm.pc()];
+        vm.step()?;
+        step += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources(src: &str) -> Vec<(String, String)> {
+        vec![("Main".to_string(), src.to_string())]
+    }
+
+    #[test]
+    fn test_straight_line_agrees() {
+        let src = sources(
+            "push constant 10
+            pop temp 0
+            push constant 3030
+            pop pointer 1
+            push constant -3040
+            neg
+            pop pointer 0
+            push temp 0
+            push pointer 1
+            add
+            push constant 1
+            neg
+            and
+            not
+            pop static 3
+            push static 3
+            push constant 0
+            or
+            push constant 5
+            sub
+            pop temp 7
+            label END
+            goto END",
+        );
+        assert_eq!(
+            run_differential(&src, false, &[(0, 256)], 1000).unwrap(),
+            Ok(22)
+        );
+    }
+
+    #[test]
+    fn test_segments_and_calls_agree() {
+        let src = sources(
+            "function Main.main 2
+            push constant 10
+            pop local 0
+            push constant 21
+            push constant 22
+            pop argument 2
+            pop argument 1
+            push constant 3000
+            pop pointer 0
+            push constant 3010
+            pop pointer 1
+            push constant 36
+            pop this 6
+            push constant -42
+            pop that 5
+            push local 0
+            push that 5
+            add
+            push argument 1
+            sub
+            push this 6
+            call Main.max 2
+            pop local 1
+            label END
+            goto END
+            function Main.max 0
+            push argument 0
+            push argument 1
+            gt
+            if-goto FIRST
+            push argument 1
+            return
+            label FIRST
+            push argument 0
+            return",
+        );
+        let setup = [(0, 256), (1, 300), (2, 400)];
+        assert_eq!(run_differential(&src, false, &setup, 1000).unwrap(), Ok(31));
+    }
+
+    #[test]
+    fn test_optimizer_commands_agree() {
+        let src = sources(
+            "function Main.main 1
+            move constant 3000 pointer 1
+            move constant -1 that 2
+            move constant 2 temp 1
+            move constant -5 local 0
+            move that 2 static 4
+            move local 0 argument 1
+            move static 4 that 3
+            push that 3
+            add constant -32768
+            sub constant 7
+            or constant 3
+            and constant 12
+            push local 0
+            gt constant -6
+            push local 0
+            eq constant -5
+            push local 0
+            lt constant 2
+            pop temp 0
+            label END
+            goto END",
+        );
+        let setup = [(0, 256), (1, 300), (2, 400)];
+        assert_eq!(run_differential(&src, false, &setup, 1000).unwrap(), Ok(21));
+    }
+
+    #[test]
+    fn test_overflowing_comparisons_agree() {
+        // Subtracting any of these pairs overflows, which must not change the answer
+        let src = sources(
+            "push constant 32767
+            push constant -1
+            gt
+            push constant -32768
+            push constant 1
+            lt
+            push constant 1
+            push constant -32768
+            lt
+            push constant -2
+            push constant 32767
+            gt
+            push constant 32767
+            lt constant -1
+            push constant -32768
+            gt constant 1
+            push constant -1
+            eq constant 32767",
+        );
+        assert_eq!(
+            run_differential(&src, false, &[(0, 256)], 1000).unwrap(),
+            Ok(18)
+        );
+    }
+
+    #[test]
+    fn test_first_difference() {
+        let mut vm = vec![0; 4096];
+        vm[0] = 258;
+        let mut asm = vm.clone();
+        // Scratch registers and anything past the top of the stack are ignored
+        asm[14] = 1;
+        asm[300] = 1;
+        assert_eq!(first_difference(&vm, &asm), None);
+        asm[257] = 7;
+        assert_eq!(first_difference(&vm, &asm), Some(257));
+    }
+}
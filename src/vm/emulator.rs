@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+
+use super::{parse, MemSegment as Seg, VmCommand};
+use crate::cpu::{headless::Stop, Cpu};
+
+/// What the VM emulator should do after a command has been executed against RAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow<'a> {
+    /// Carry on with the following command
+    Next,
+    /// Jump to a label in the current function
+    Goto(&'a str),
+    /// Jump to the start of a function, the frame has already been pushed
+    Call(&'a str),
+    /// Jump back to the command after the matching call
+    Return(i16),
+}
+
+/// Reads every `.vm` file up front as `(name, source)` pairs, so that a [`VmProgram`] can borrow from them.
+pub fn read_sources(files: &[PathBuf]) -> Result<Vec<(String, String)>> {
+    files
+        .iter()
+        .map(|file| {
+            let name = file
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let source = std::fs::read_to_string(file)
+                .with_context(|| format!("could not read {}", file.display()))?;
+            Ok((name, source))
+        })
+        .collect()
+}
+
+/// A set of parsed VM files, with every jump target and static variable resolved.
+pub struct VmProgram<'a> {
+    commands: Vec<VmCommand<'a>>,
+    /// The file each command came from, as an index into `files`
+    origins: Vec<usize>,
+    files: Vec<&'a str>,
+    /// The command index each `goto`/`if-goto` jumps to
+    targets: Vec<Option<usize>>,
+    functions: HashMap<&'a str, usize>,
+    /// The RAM address of the static variable each command refers to, if any
+    static_addrs: Vec<i16>,
+}
+
+impl<'a> VmProgram<'a> {
+    /// Parses and links the given `(name, source)` pairs.
+    ///
+    /// Labels are scoped to the function they appear in, the same way the translator names them.
+    /// Statics are allocated from address 16 in order of first use, which is also
+    /// the order the assembler ends up allocating the translator's `File.n` variables in.
+    pub fn new(sources: &'a [(String, String)]) -> Result<Self> {
+        let mut program = Self {
+            commands: vec![],
+            origins: vec![],
+            files: vec![],
+            targets: vec![],
+            functions: HashMap::new(),
+            static_addrs: vec![],
+        };
+        let mut labels = HashMap::new();
+        let mut scopes = vec![];
+        let mut statics = HashMap::new();
+
+        for (file, (name, source)) in sources.iter().enumerate() {
+            program.files.push(name);
+            let mut scope = format!("${name}$");
+            for (line, text) in source.lines().enumerate() {
+                let text = text.split("//").next().unwrap_or_default().trim();
+                if text.is_empty() {
+                    continue;
+                }
+                let cmd = parse(text).with_context(|| format!("{name}.vm:{}", line + 1))?;
+                let index = program.commands.len();
+                match cmd {
+                    VmCommand::Function(func, _) => {
+                        if program.functions.insert(func, index).is_some() {
+                            bail!("{name}.vm:{}: function {func} is defined twice", line + 1);
+                        }
+                        scope = func.to_string();
+                    }
+                    VmCommand::Label(label) => {
+                        labels.insert(format!("{scope}${label}"), index);
+                    }
+                    _ => {}
+                }
+                let static_addr = match cmd {
+                    VmCommand::Push(Seg::Static, i)
+                    | VmCommand::Pop(Seg::Static, i)
+                    | VmCommand::Move(Seg::Static, i, _, _)
+                    | VmCommand::Move(_, _, Seg::Static, i) => {
+                        let next = 16 + statics.len() as i16;
+                        *statics.entry((file, i)).or_insert(next)
+                    }
+                    _ => 0,
+                };
+                program.commands.push(cmd);
+                program.origins.push(file);
+                program.static_addrs.push(static_addr);
+                scopes.push(scope.clone());
+            }
+        }
+
+        for (cmd, scope) in program.commands.iter().zip(&scopes) {
+            let target = match cmd {
+                VmCommand::Goto(label) | VmCommand::IfGoto(label) => {
+                    let Some(&target) = labels.get(&format!("{scope}${label}")) else {
+                        bail!("label {label} is not defined in {scope}");
+                    };
+                    Some(target)
+                }
+                _ => None,
+            };
+            program.targets.push(target);
+        }
+        Ok(program)
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// The command at `index` along with the name of the file it came from
+    pub fn get(&self, index: usize) -> Option<(&'a str, VmCommand<'a>)> {
+        let cmd = *self.commands.get(index)?;
+        Some((self.files[self.origins[index]], cmd))
+    }
+}
+
+/// Runs VM commands directly against the RAM of a [`Cpu`], without translating them to assembly first.
+pub struct VmEmulator<'p, 'a> {
+    program: &'p VmProgram<'a>,
+    pub cpu: Cpu<'static>,
+    /// Index of the next command to execute
+    pc: usize,
+    /// The ROM address each command was translated to, followed by where `Sys.init` returns to,
+    /// so that call frames save the same return addresses as the translated assembly would
+    rom_addrs: Option<Vec<i16>>,
+}
+
+impl<'p, 'a> VmEmulator<'p, 'a> {
+    /// With `bootstrap` the stack starts at 256 and `Sys.init` is called,
+    /// otherwise execution starts from the first command and RAM is left for the caller to set up.
+    pub fn new(program: &'p VmProgram<'a>, bootstrap: bool) -> Result<Self> {
+        Self::with_rom_addrs(program, bootstrap, None)
+    }
+
+    /// Like [`VmEmulator::new`], but with return addresses given as the ROM address of each command
+    /// (plus the bootstrap's return address, for the end of the program) rather than command indices.
+    pub(crate) fn with_rom_addrs(
+        program: &'p VmProgram<'a>,
+        bootstrap: bool,
+        rom_addrs: Option<Vec<i16>>,
+    ) -> Result<Self> {
+        let mut emu = Self {
+            program,
+            cpu: Cpu::new(&[]),
+            pc: 0,
+            rom_addrs,
+        };
+        if bootstrap {
+            emu.cpu.ram[0] = 256;
+            // Returning from Sys.init falls off the end of the program
+            let flow = emu.cpu.execute_vm(
+                VmCommand::Call("Sys.init", 0),
+                0,
+                emu.return_addr(program.len()),
+            )?;
+            emu.pc = emu.jump(flow)?;
+        }
+        Ok(emu)
+    }
+
+    fn return_addr(&self, index: usize) -> i16 {
+        match &self.rom_addrs {
+            Some(addrs) => addrs[index],
+            None => index as i16,
+        }
+    }
+
+    pub const fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// The next command to execute, along with the name of the file it came from
+    pub fn current(&self) -> Option<(&'a str, VmCommand<'a>)> {
+        self.program.get(self.pc)
+    }
+
+    /// Whether the program has run off the end, or is stuck in a `label X / goto X` loop.
+    pub fn is_halted(&self) -> bool {
+        match (
+            self.program.commands.get(self.pc),
+            self.program.targets.get(self.pc),
+        ) {
+            (None, _) => true,
+            (Some(VmCommand::Goto(_)), Some(&Some(target))) => {
+                target <= self.pc
+                    && self.program.commands[target..self.pc]
+                        .iter()
+                        .all(|cmd| matches!(cmd, VmCommand::Label(_)))
+            }
+            _ => false,
+        }
+    }
+
+    /// Executes a single VM command.
+    pub fn step(&mut self) -> Result<()> {
+        let Some((file, cmd)) = self.current() else {
+            bail!(
+                "VM program counter {} is past the end of the program",
+                self.pc
+            );
+        };
+        let flow = self
+            .cpu
+            .execute_vm(
+                cmd,
+                self.program.static_addrs[self.pc],
+                self.return_addr(self.pc + 1),
+            )
+            .with_context(|| format!("{file}.vm: {cmd}"))?;
+        self.pc = self
+            .jump(flow)
+            .with_context(|| format!("{file}.vm: {cmd}"))?;
+        Ok(())
+    }
+
+    fn jump(&self, flow: Flow) -> Result<usize> {
+        Ok(match flow {
+            Flow::Next => self.pc + 1,
+            // Only gotos have targets, and they were all resolved when linking
+            Flow::Goto(_) => self.program.targets[self.pc].unwrap_or(self.pc + 1),
+            Flow::Call(func) => match self.program.functions.get(func) {
+                Some(&index) => index,
+                None => bail!("function {func} is not defined"),
+            },
+            // Commands without any assembly share their ROM address with the next one,
+            // but the first of them is always the one following the call
+            Flow::Return(addr) => match self.rom_addrs.as_deref() {
+                Some([commands @ .., end]) if addr != *end => {
+                    commands.partition_point(|&a| a < addr)
+                }
+                Some(_) => self.program.len(),
+                None => addr as u16 as usize,
+            },
+        })
+    }
+
+    /// Steps until the program halts, or until `max_steps` commands have run if given.
+    pub fn run(&mut self, max_steps: Option<u64>) -> Result<(Stop, u64)> {
+        let mut steps = 0;
+        loop {
+            if self.is_halted() {
+                return Ok((Stop::Halted, steps));
+            }
+            if max_steps.is_some_and(|max| steps >= max) {
+                return Ok((Stop::TickLimit, steps));
+            }
+            self.step()?;
+            steps += 1;
+        }
+    }
+
+    pub fn dump_state(&self, mut out: impl Write) -> std::io::Result<()> {
+        match self.current() {
+            Some((file, cmd)) => writeln!(out, "VM PC: {} ({file}.vm: {cmd})", self.pc)?,
+            None => writeln!(out, "VM PC: {} (end of program)", self.pc)?,
+        }
+        for (name, addr) in [("SP", 0), ("LCL", 1), ("ARG", 2), ("THIS", 3), ("THAT", 4)] {
+            writeln!(out, "{name}: {}", self.cpu.ram[addr])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources(files: &[(&str, &str)]) -> Vec<(String, String)> {
+        files
+            .iter()
+            .map(|(name, src)| (name.to_string(), src.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let src = sources(&[(
+            "Main",
+            "push constant 7\npush constant 8\nadd\npush constant 15\neq\npush constant 3\nneg\nlt",
+        )]);
+        let program = VmProgram::new(&src).unwrap();
+        let mut vm = VmEmulator::new(&program, false).unwrap();
+        vm.cpu.ram[0] = 256;
+        assert_eq!(vm.run(None).unwrap(), (Stop::Halted, 8));
+        // (7 + 8 == 15) < -3 is false, since true is -1
+        assert_eq!(vm.cpu.ram[0], 257);
+        assert_eq!(vm.cpu.ram[256], 0);
+    }
+
+    #[test]
+    fn test_calls_and_statics() {
+        let src = sources(&[
+            (
+                "Sys",
+                "function Sys.init 0
+                push constant 4
+                call Main.fib 1
+                pop static 0
+                label END
+                goto END",
+            ),
+            (
+                "Main",
+                "// fib(n) = n < 2 ? n : fib(n - 1) + fib(n - 2)
+                function Main.fib 0
+                push argument 0
+                push constant 2
+                lt
+                if-goto BASE
+                push argument 0
+                push constant 1
+                sub
+                call Main.fib 1
+                push argument 0
+                push constant 2
+                sub
+                call Main.fib 1
+                add
+                return
+                label BASE
+                push argument 0
+                pop static 3
+                push static 3
+                return",
+            ),
+        ]);
+        let program = VmProgram::new(&src).unwrap();
+        let mut vm = VmEmulator::new(&program, true).unwrap();
+        let (stop, _) = vm.run(Some(10_000)).unwrap();
+        assert_eq!(stop, Stop::Halted);
+        // Sys.0 is used first, then Main.3
+        assert_eq!(vm.cpu.ram[16], 3);
+        assert_eq!(vm.cpu.ram[17], 0);
+        // Sys.init's frame is all that is left on the stack
+        assert_eq!(vm.cpu.ram[0], 261);
+    }
+
+    #[test]
+    fn test_undefined_label() {
+        let src = sources(&[("Main", "function Main.main 0\ngoto NOWHERE")]);
+        assert!(VmProgram::new(&src).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+use std::iter::zip;
+
+use clap::ValueEnum;
+
+use super::{BinaryOp, MemSegment as Seg, VmCommand};
+use crate::optimizer::Optimize;
+
+/// The rewrites [`VmOptimizer`] knows, from `VM_Translation_Optimizations.md`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, ValueEnum)]
+pub enum VmRule {
+    /// Arithmetic on constants is done ahead of time, so `push constant 2`, `push constant 3`, `add`
+    /// becomes `push constant 5`
+    ConstantFolding,
+    /// `push X` straight followed by `pop X` does nothing
+    DeadPushPop,
+    /// `push X` straight followed by `pop Y` becomes `move X Y`, which never touches the stack
+    DirectMove,
+    /// `push constant n` followed by a binary operation becomes `<op> constant n`,
+    /// which works on the top of the stack with `n` loaded straight into D
+    ImmediateOperand,
+}
+
+impl VmRule {
+    /// Every rule, in the order they are applied
+    pub const ALL: [Self; 4] = [
+        Self::ConstantFolding,
+        Self::DeadPushPop,
+        Self::DirectMove,
+        Self::ImmediateOperand,
+    ];
+}
+
+impl Display for VmRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.to_possible_value() {
+            Some(value) => f.pad(value.get_name()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// An optimizer for the VM commands of a single file, run before they are translated.
+///
+/// Every rule only looks at commands that follow each other directly, so nothing is
+/// rewritten across a label, a jump or a function boundary.
+pub struct VmOptimizer {
+    pub rules: HashSet<VmRule>,
+    /// How many VM commands each rule has removed so far
+    pub saved: BTreeMap<VmRule, usize>,
+}
+
+impl VmOptimizer {
+    /// An optimizer with every rule turned on
+    pub fn new() -> Self {
+        Self {
+            rules: VmRule::ALL.into_iter().collect(),
+            saved: BTreeMap::new(),
+        }
+    }
+
+    pub fn without(mut self, rules: &[VmRule]) -> Self {
+        for rule in rules {
+            self.rules.remove(rule);
+        }
+        self
+    }
+
+    pub fn total_saved(&self) -> usize {
+        self.saved.values().sum()
+    }
+}
+
+impl<'a> Optimize<VmCommand<'a>> for VmOptimizer {
+    fn optimize(&mut self, commands: Vec<VmCommand<'a>>) -> Vec<VmCommand<'a>> {
+        let commands = commands.into_iter().map(|command| (0, command)).collect();
+        let commands: Vec<(usize, VmCommand)> = self.optimize(commands);
+        commands.into_iter().map(|(_, command)| command).collect()
+    }
+}
+
+/// Optimizes commands along with the source line each is on.
+/// A command the optimizer writes is on the line of the first one it replaces.
+impl<'a> Optimize<(usize, VmCommand<'a>)> for VmOptimizer {
+    fn optimize(
+        &mut self,
+        mut commands: Vec<(usize, VmCommand<'a>)>,
+    ) -> Vec<(usize, VmCommand<'a>)> {
+        for rule in VmRule::ALL {
+            if !self.rules.contains(&rule) {
+                continue;
+            }
+            let before = commands.len();
+            commands = rewrite(commands, |tail| match rule {
+                VmRule::ConstantFolding => fold_constants(tail),
+                VmRule::DeadPushPop => dead_push_pop(tail),
+                VmRule::DirectMove => direct_move(tail),
+                VmRule::ImmediateOperand => immediate_operand(tail),
+            });
+            let saved = before - commands.len();
+            if saved > 0 {
+                *self.saved.entry(rule).or_default() += saved;
+            }
+        }
+        commands
+    }
+}
+
+/// What the last commands written so far should be replaced with, and how many of them
+type Rewrite<'a> = Option<(usize, Option<VmCommand<'a>>)>;
+
+/// Rewrites the end of the output for as long as `rule` finds something to do after each command,
+/// so that the result of one rewrite can take part in the next.
+fn rewrite<'a>(
+    commands: Vec<(usize, VmCommand<'a>)>,
+    rule: impl Fn(&[VmCommand<'a>]) -> Rewrite<'a>,
+) -> Vec<(usize, VmCommand<'a>)> {
+    let mut out = Vec::with_capacity(commands.len());
+    let mut lines = Vec::with_capacity(commands.len());
+    for (line, command) in commands {
+        out.push(command);
+        lines.push(line);
+        while let Some((replaced, with)) = rule(&out) {
+            let first = out.len() - replaced;
+            let line = lines[first];
+            out.truncate(first);
+            lines.truncate(first);
+            if let Some(with) = with {
+                out.push(with);
+                lines.push(line);
+            }
+        }
+    }
+    zip(lines, out).collect()
+}
+
+fn fold_constants<'a>(tail: &[VmCommand<'a>]) -> Rewrite<'a> {
+    use VmCommand::{Immediate, Neg, Not, Push};
+    let constant = |n| Some(Push(Seg::Constant, n));
+    match *tail {
+        [.., Push(Seg::Constant, n), Neg] => Some((2, constant(n.wrapping_neg()))),
+        [.., Push(Seg::Constant, n), Not] => Some((2, constant(!n))),
+        [.., Push(Seg::Constant, x), Immediate(op, y)] => Some((2, constant(op.apply(x, y)))),
+        [.., Push(Seg::Constant, x), Push(Seg::Constant, y), op] => {
+            BinaryOp::of(op).map(|op| (3, constant(op.apply(x, y))))
+        }
+        _ => None,
+    }
+}
+
+fn dead_push_pop<'a>(tail: &[VmCommand<'a>]) -> Rewrite<'a> {
+    match *tail {
+        [.., VmCommand::Push(from, i), VmCommand::Pop(to, j)]
+            if from == to && i == j && from != Seg::Constant =>
+        {
+            Some((2, None))
+        }
+        _ => None,
+    }
+}
+
+fn direct_move<'a>(tail: &[VmCommand<'a>]) -> Rewrite<'a> {
+    match *tail {
+        // A command can only refer to one static
+        [.., VmCommand::Push(from, i), VmCommand::Pop(to, j)]
+            if to != Seg::Constant && !(from == Seg::Static && to == Seg::Static) =>
+        {
+            Some((2, Some(VmCommand::Move(from, i, to, j))))
+        }
+        _ => None,
+    }
+}
+
+fn immediate_operand<'a>(tail: &[VmCommand<'a>]) -> Rewrite<'a> {
+    match *tail {
+        [.., VmCommand::Push(Seg::Constant, n), op] => {
+            BinaryOp::of(op).map(|op| (2, Some(VmCommand::Immediate(op, n))))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::cpu::headless::Stop;
+    use crate::cpu::Cpu;
+    use crate::vm::differential::first_difference;
+    use crate::vm::emulator::{VmEmulator, VmProgram};
+    use crate::vm::parse;
+    use crate::vm::translator::translate_sources;
+
+    fn optimize(rules: &[VmRule], src: &str) -> (Vec<String>, VmOptimizer) {
+        let commands = src.lines().map(|l| parse(l.trim()).unwrap()).collect();
+        let others: Vec<_> = VmRule::ALL
+            .into_iter()
+            .filter(|r| !rules.contains(r))
+            .collect();
+        let mut optimizer = VmOptimizer::new().without(&others);
+        let commands = optimizer.optimize(commands);
+        (
+            commands.iter().map(ToString::to_string).collect(),
+            optimizer,
+        )
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let (text, optimizer) = optimize(
+            &[VmRule::ConstantFolding],
+            "push constant 2
+            push constant 3
+            add
+            neg
+            push constant 7
+            push constant 7
+            eq
+            and
+            not
+            push local 0
+            push constant 1
+            sub",
+        );
+        assert_eq!(
+            text,
+            ["push constant 4", "push local 0", "push constant 1", "sub"]
+        );
+        assert_eq!(optimizer.total_saved(), 8);
+    }
+
+    #[test]
+    fn test_stack_shuffles() {
+        let src = "push argument 1
+            pop argument 1
+            push static 0
+            pop static 1
+            push constant 5
+            pop local 2
+            push that 0
+            push constant -3
+            lt";
+        let (text, _) = optimize(&[VmRule::DeadPushPop, VmRule::ImmediateOperand], src);
+        assert_eq!(
+            text,
+            [
+                "push static 0",
+                "pop static 1",
+                "push constant 5",
+                "pop local 2",
+                "push that 0",
+                "lt constant -3"
+            ]
+        );
+        let (text, optimizer) = optimize(&VmRule::ALL, src);
+        assert_eq!(
+            text,
+            [
+                "push static 0",
+                "pop static 1",
+                "move constant 5 local 2",
+                "push that 0",
+                "lt constant -3"
+            ]
+        );
+        assert_eq!(optimizer.saved[&VmRule::DeadPushPop], 2);
+        assert_eq!(optimizer.saved[&VmRule::DirectMove], 1);
+        assert_eq!(optimizer.saved[&VmRule::ImmediateOperand], 1);
+    }
+
+    #[test]
+    fn test_optimized_program_agrees() {
+        let src = "function Main.main 2
+            push constant 10
+            pop local 0
+            push local 0
+            pop local 0
+            push constant 3000
+            pop pointer 1
+            push constant 2
+            push constant 3
+            add
+            neg
+            pop that 4
+            push that 4
+            push constant 5
+            sub
+            pop static 0
+            push local 0
+            push constant 7
+            call Main.max 2
+            pop local 1
+            push static 0
+            pop temp 3
+            push local 1
+            push constant 1
+            eq
+            push constant -1
+            and
+            pop argument 0
+            label END
+            goto END
+            function Main.max 0
+            push argument 0
+            push argument 1
+            gt
+            if-goto FIRST
+            push argument 1
+            return
+            label FIRST
+            push argument 0
+            return";
+        let commands: Vec<_> = src.lines().map(|l| parse(l.trim()).unwrap()).collect();
+        let mut optimizer = VmOptimizer::new();
+        let optimized = optimizer.optimize(commands.clone());
+        for rule in VmRule::ALL {
+            assert!(optimizer.saved[&rule] > 0, "{rule} saved nothing");
+        }
+        assert_eq!(commands.len() - optimized.len(), optimizer.total_saved());
+
+        let run = |commands: &[VmCommand]| {
+            let text: Vec<_> = commands.iter().map(ToString::to_string).collect();
+            let sources = [("Main".to_string(), text.join("\n"))];
+            let program = VmProgram::new(&sources).unwrap();
+            let mut vm = VmEmulator::new(&program, false).unwrap();
+            vm.cpu.ram[..3].copy_from_slice(&[256, 300, 400]);
+            let (stop, _) = vm.run(Some(1000)).unwrap();
+            assert_eq!(stop, Stop::Halted);
+            vm.cpu.ram[..4096].to_vec()
+        };
+        let ram = run(&commands);
+        let optimized_ram = run(&optimized);
+        assert_eq!((ram[16], ram[4], ram[8]), (-10, 3000, -10));
+        assert_eq!(first_difference(&ram, &optimized_ram), None);
+    }
+
+    #[test]
+    fn test_folding_agrees_on_overflow() {
+        // Subtracting either pair overflows, which the translated comparisons must get right
+        // to give the same answers as the folded constants
+        let src = [(
+            "Main".to_string(),
+            "function Main.main 0
+            push constant 32767
+            push constant -1
+            gt
+            pop static 0
+            push constant -32768
+            push constant 1
+            lt
+            pop static 1
+            label END
+            goto END"
+                .to_string(),
+        )];
+        let run = |optimizer: Option<&mut VmOptimizer>| {
+            let translation = translate_sources(&src, false, optimizer).unwrap();
+            let rom = Assembler::new().assemble(&translation.asm).unwrap();
+            let mut cpu = Cpu::new(&rom);
+            cpu.ram[0] = 256;
+            let (stop, _) = cpu.run_headless(Some(1000)).unwrap();
+            assert_eq!(stop, Stop::Halted);
+            (cpu.ram[16], cpu.ram[17])
+        };
+        let mut optimizer = VmOptimizer::new();
+        assert_eq!(run(Some(&mut optimizer)), (-1, -1));
+        assert_eq!(optimizer.saved[&VmRule::ConstantFolding], 4);
+        assert_eq!(run(None), (-1, -1));
+    }
+}
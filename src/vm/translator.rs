@@ -1,95 +1,248 @@
 use std::borrow::Cow;
+use std::collections::HashSet;
 use std::fmt::Display;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::path::{Path, PathBuf};
+use std::iter::zip;
+use std::path::PathBuf;
 use std::vec;
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 
-use super::{Comparison as Cmp, MemSegment as Seg, VmCommand};
+use super::debug_info::{read_jack_lines, VmSource};
+use super::optimizer::VmOptimizer;
+use super::{
+    emulator::read_sources, parse, BinaryOp, Comparison as Cmp, MemSegment as Seg, VmCommand,
+};
 use crate::asm::{Asm, Mode};
+use crate::optimizer::Optimize;
 use asm_macro::asm;
 
-fn translate_vm(bootstrap: bool) -> Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    let mut files: Vec<PathBuf> = vec![];
-    let file_path = Path::new(&args[1]);
-    let filename = file_path.file_stem().unwrap().to_str().unwrap();
-    if file_path.is_dir() {
-        for entry in file_path.read_dir().unwrap() {
-            if let Some(x) = entry.as_ref().unwrap().path().extension() {
-                if x.to_str().unwrap() == "vm" {
-                    files.push(entry.as_ref().unwrap().path())
-                }
-            }
+/// A whole VM program translated into a single Hack assembly program.
+pub struct Translation {
+    pub asm: Vec<Asm<'static>>,
+    /// The index into `asm` where each VM command's expansion starts.
+    ///
+    /// There is one more start than there are commands, the last being the end of the program.
+    /// With an optimizer these are the optimized commands, not the ones in the source.
+    pub starts: Vec<usize>,
+    /// How many instructions each function was translated into, in the order they were written
+    pub sizes: Vec<CodeSize>,
+    /// Where the code from each VM command and shared routine starts, in order
+    pub marks: Vec<Mark>,
+}
+
+/// Where the code from a command or routine starts in the translated assembly,
+/// which [`DebugInfo`](super::debug_info::DebugInfo) turns into a ROM address.
+#[derive(Debug, Clone)]
+pub struct Mark {
+    /// How many comments come before the one the code starts with
+    pub comment: usize,
+    /// The VM function, or what the code is for when it is outside of any
+    pub function: String,
+    pub source: Option<VmSource>,
+}
+
+/// How many instructions a function, or some other part of a translated program, takes up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeSize {
+    /// The `.vm` file, or `(bootstrap)` and `(shared)` for code that comes from no file
+    pub file: String,
+    /// The function, or a description of the code outside of any function
+    pub function: String,
+    pub instructions: usize,
+}
+
+/// Translates the given `.vm` files into a single Hack assembly program,
+/// running each file's commands through `optimizer` first if given.
+pub fn translate_vm(
+    files: &[PathBuf],
+    bootstrap: bool,
+    optimizer: Option<&mut VmOptimizer>,
+) -> Result<Translation> {
+    let sources = read_sources(files)?;
+    let mut translation = translate_sources(&sources, bootstrap, optimizer)?;
+    for (file, (name, source)) in zip(files, &sources) {
+        let Some(jack_lines) = read_jack_lines(file, source)? else {
+            continue;
+        };
+        let vm_file = format!("{name}.vm");
+        for source in translation
+            .marks
+            .iter_mut()
+            .filter_map(|mark| mark.source.as_mut())
+            .filter(|source| source.file == vm_file)
+        {
+            source.jack_line = jack_lines.get(source.line - 1).copied();
         }
-    } else if let Some("vm") = file_path.extension().unwrap().to_str() {
-        files.push(file_path.to_path_buf())
-    }
-    let mut writer = VmTranslator::new(filename, bootstrap);
-    for file in files {
-        if let Ok(f) = File::open(&file) {
-            writer.set_filename(file.file_stem().unwrap().to_str().unwrap());
-            let reader = BufReader::new(f);
-            for _line in reader.lines().flatten() {
-                // let cmd = match line.find("//") { //if let Some(i) = line.find("//") {
-                //     Some(i) => &line[..i].trim(),
-                //     _ => &line.trim(),
-                // };
-                // if !cmd.is_empty() {
-                //     let vm_cmd = parse(&cmd).expect("could not parse command");
-                //     writer.generate_asm(vm_cmd, true)?;
-                // }
+    }
+    Ok(translation)
+}
+
+/// Translates `(name, source)` pairs.
+pub(crate) fn translate_sources(
+    sources: &[(String, String)],
+    bootstrap: bool,
+    mut optimizer: Option<&mut VmOptimizer>,
+) -> Result<Translation> {
+    let mut writer = VmTranslator::new("Bootstrap", bootstrap);
+    let mut starts = vec![];
+    for (name, source) in sources {
+        writer.set_filename(name);
+        let mut commands = vec![];
+        for (line, text) in source.lines().enumerate() {
+            let cmd = match text.find("//") {
+                Some(i) => text[..i].trim(),
+                None => text.trim(),
+            };
+            if !cmd.is_empty() {
+                let line = line + 1;
+                commands.push((
+                    line,
+                    parse(cmd).with_context(|| format!("{name}.vm:{line}"))?,
+                ));
             }
         }
+        if let Some(optimizer) = optimizer.as_deref_mut() {
+            commands = optimizer.optimize(commands);
+        }
+        for (line, vm_cmd) in commands {
+            let function = match vm_cmd {
+                VmCommand::Function(function, _) => function.to_string(),
+                _ => writer.part.1.clone(),
+            };
+            let source = VmSource {
+                file: format!("{name}.vm"),
+                line,
+                command: vm_cmd.to_string(),
+                jack_line: None,
+            };
+            writer.mark(function, Some(source));
+            starts.push(writer.asm.len());
+            writer
+                .generate_asm(vm_cmd, true)
+                .with_context(|| format!("{name}.vm:{line}: {vm_cmd}"))?;
+        }
     }
-    Ok(())
-    //writer.flush();
+    starts.push(writer.asm.len());
+    writer.enter(String::new(), String::new());
+
+    Ok(Translation {
+        asm: writer.asm.into_iter().map(Asm::into_owned).collect(),
+        starts,
+        sizes: writer.sizes,
+        marks: writer.marks,
+    })
+}
+
+/// Where `Sys.init` returns to when the bootstrap is used, a halt loop straight after the call.
+pub(crate) const BOOTSTRAP_RETURN: &str = "Bootstrap.ret$0";
+
+/// Where the value a segment index refers to is kept
+enum Place<'a> {
+    Constant(i16),
+    /// A variable or register with its own symbol
+    Direct(Asm<'a>),
+    /// An offset from a base pointer
+    Segment(Asm<'a>, i16),
 }
 
-struct VmTranslator<'a> {
+pub struct VmTranslator<'a> {
     filename: String,
     curr_func: String,
     comp_count: i16,
     call_count: i16,
-    return_written: bool,
+    /// The labels of the shared routines written so far
+    shared: HashSet<String>,
     asm: Vec<Asm<'a>>,
+    /// The file and function the code being written is counted towards, and where in `asm` it started
+    part: (String, String),
+    part_start: usize,
+    sizes: Vec<CodeSize>,
+    marks: Vec<Mark>,
+    /// How many comments there are in `asm` up to `counted`
+    comments: usize,
+    counted: usize,
 }
 
 impl<'a> VmTranslator<'a> {
     pub fn new(filename: &str, bootstrap: bool) -> Self {
-        let asm = if bootstrap {
-            Vec::from(asm![
+        let mut translator = Self {
+            filename: filename.to_string(),
+            curr_func: format!("${filename}$"),
+            comp_count: 0,
+            call_count: 0,
+            shared: HashSet::new(),
+            asm: vec![],
+            part: ("(bootstrap)".to_string(), "(bootstrap)".to_string()),
+            part_start: 0,
+            sizes: vec![],
+            marks: vec![],
+            comments: 0,
+            counted: 0,
+        };
+
+        if bootstrap {
+            translator.mark("(bootstrap)".to_string(), None);
+            // Sys.init gets a proper frame like any other function, so it is free to return
+            translator.asm.extend(asm![
+            "bootstrap"
                 @256
                 D=A
                 @SP
                 M=D
-                // Need to fine tune
-            "call Sys.init"
-                @"Sys.init"
+            "call Sys.init 0"
+            ]);
+            translator.call_func("Sys.init", 0);
+            // If Sys.init ever returns the program is over, so it returns straight into a halt loop
+            translator.asm.extend(asm![
+                @BOOTSTRAP_RETURN
                 0;JMP
-            ])
-        } else {
-            vec![]
-        };
-
-        Self {
-            filename: filename.to_string(),
-            curr_func: format!("${filename}$"),
-            comp_count: 0,
-            call_count: 0,
-            return_written: false,
-            asm,
+            ]);
         }
+        translator
     }
 
+    /// Statics and any labels outside of a function are named after the file they are in.
     fn set_filename(&mut self, filename: &str) {
         self.filename = filename.to_string();
+        self.curr_func = format!("${filename}$");
+        self.enter(
+            format!("{filename}.vm"),
+            format!("(outside any function in {filename}.vm)"),
+        );
     }
 
-    /// Naively generates assembly on demand per VM Command.
+    /// Counts everything written from here on towards another part of the program.
+    fn enter(&mut self, file: String, function: String) {
+        let instructions = self.asm[self.part_start..]
+            .iter()
+            .filter(|line| matches!(line, Asm::At(_) | Asm::Asm(_)))
+            .count();
+        let (file, function) = std::mem::replace(&mut self.part, (file, function));
+        if instructions > 0 {
+            self.sizes.push(CodeSize {
+                file,
+                function,
+                instructions,
+            });
+        }
+        self.part_start = self.asm.len();
+    }
 
+    /// Starts a new entry in the debug info at the comment written next.
+    fn mark(&mut self, function: String, source: Option<VmSource>) {
+        self.comments += self.asm[self.counted..]
+            .iter()
+            .filter(|line| matches!(line, Asm::Comment(_)))
+            .count();
+        self.counted = self.asm.len();
+        self.marks.push(Mark {
+            comment: self.comments,
+            function,
+            source,
+        });
+    }
+
+    /// Naively generates assembly on demand per VM Command.
     fn generate_asm(&mut self, command: VmCommand<'a>, comment: bool) -> Result<()> {
         if comment {
             self.asm.push(asm!("{command}"));
@@ -99,116 +252,119 @@ impl<'a> VmTranslator<'a> {
             VmCommand::Add => self.binary_op(asm!(M = D + M)),
             VmCommand::Sub => self.binary_op(asm!(M = M - D)),
             VmCommand::Neg => self.unary_op(asm!(M = -M)),
-            VmCommand::Compare(comp) => self.comparison(comp),
+            VmCommand::Compare(comp) => self.comparison(comp, false),
             VmCommand::And => self.binary_op(asm!(M = D & M)),
             VmCommand::Or => self.binary_op(asm!(M = D | M)),
             VmCommand::Not => self.unary_op(asm!(M = !M)),
-            VmCommand::Push(seg, n) => match seg {
-                Seg::Argument => self.push_segment(Asm::ARG, n),
-                Seg::Local => self.push_segment(Asm::LCL, n),
-                Seg::This => self.push_segment(Asm::THIS, n),
-                Seg::That => self.push_segment(Asm::THAT, n),
-                Seg::Static => self.push_value(format!("{}.{n}", self.filename), Mode::M),
-                Seg::Pointer => {
-                    self.push_value(if n == 0 { Asm::THIS } else { Asm::THAT }, Mode::M)
-                }
-                Seg::Temp => {
-                    let reg = match n {
-                        0 => Asm::R5,
-                        1 => Asm::R6,
-                        2 => Asm::R7,
-                        3 => Asm::R8,
-                        4 => Asm::R9,
-                        5 => Asm::R10,
-                        6 => Asm::R11,
-                        7 => Asm::R12,
-                        _ => bail!("Unsupported temp register {n}"),
-                    };
-                    self.push_value(reg, Mode::M)
-                }
-                Seg::Constant => self.push_constant(n),
+            VmCommand::Push(seg, n) => match self.place(seg, n)? {
+                Place::Constant(n) => self.push_constant(n),
+                Place::Direct(var) => self.push_value(var, Mode::M),
+                Place::Segment(base, n) => self.push_segment(base, n),
             },
-            VmCommand::Pop(seg, n) => match seg {
-                Seg::Argument => self.pop_segment(Asm::ARG, n),
-                Seg::Local => self.pop_segment(Asm::LCL, n),
-                Seg::This => self.pop_segment(Asm::THIS, n),
-                Seg::That => self.pop_segment(Asm::THAT, n),
-                Seg::Static => self.pop_value(format!("{}.{n}", self.filename)),
-                Seg::Pointer => self.pop_value(if n == 0 { Asm::THIS } else { Asm::THAT }),
-                Seg::Temp => self.pop_value(Asm::At(Cow::Owned(format!("R{}", n + 5)))),
-                _ => bail!("cannot pop to constant"),
+            VmCommand::Pop(seg, n) => match self.place(seg, n)? {
+                Place::Constant(_) => bail!("cannot pop to constant"),
+                Place::Direct(var) => self.pop_value(var),
+                Place::Segment(base, n) => self.pop_segment(base, n),
             },
             VmCommand::Label(l) => self.def_label(format!("{}${}", self.curr_func, l)),
             VmCommand::Goto(l) => self.goto(format!("{}${}", self.curr_func, l)),
             VmCommand::IfGoto(l) => self.if_goto(format!("{}${}", self.curr_func, l)),
-            VmCommand::Function(f, n) => self.func(f, n),
-            VmCommand::Call(f, n) => self.call_func(f, n),
-            VmCommand::Return => {
-                if self.return_written {
-                    self.asm.extend(asm![
-                        @"$$RETURN"
-                        0;JMP
-                    ])
-                } else {
-                    self.asm.extend(asm![
-                    "Shared return subroutine"
-                    ("$$RETURN")
-                    "Get the return address from 5 slots before the current local segment and store it in R14"
-                        @5
-                        D=A
-                        @LCL
-                        A=M-D
-                        D=M
-                        @R14
-                        M=D
-                        ""
-                        @SP
-                        A=M-1
-                        D=M
-                        @ARG
-                        A=M
-                        M=D
-                        D=A+1
-                        @SP
-                        M=D
-                        ""
-                        @LCL
-                        D=M-1
-                        @R13
-                        AM=D
-                    "restore saved that segment"
-                        D=M
-                        @THAT
-                        M=D
-                    "restore saved this segment"
-                        @R13
-                        AM=M-1
-                        D=M
-                        @THIS
-                        M=D
-                    "restore saved argument segment"
-                        @R13
-                        AM=M-1
-                        D=M
-                        @ARG
-                        M=D
-                    "restore saved local segment"
-                        @R13
-                        AM=M-1
-                        D=M
-                        @LCL
-                        M=D
-                    "jump to the saved return address"
-                        @R14
-                        A=M
-                        0;JMP
-                    ])
+            VmCommand::Immediate(op, n) => {
+                self.load_constant(n);
+                match op {
+                    BinaryOp::Add => self.unary_op(asm!(M = D + M)),
+                    BinaryOp::Sub => self.unary_op(asm!(M = M - D)),
+                    BinaryOp::And => self.unary_op(asm!(M = D & M)),
+                    BinaryOp::Or => self.unary_op(asm!(M = D | M)),
+                    BinaryOp::Compare(cmp) => self.comparison(cmp, true),
                 }
             }
+            VmCommand::Move(from, i, to, j) => {
+                let from = self.place(from, i)?;
+                self.move_value(from, self.place(to, j)?)?;
+            }
+            VmCommand::Function(f, n) => self.func(f, n),
+            VmCommand::Call(f, n) => self.call_func(f, n),
+            VmCommand::Return => self.shared("$$RETURN".to_string(), Self::return_routine),
         }
         Ok(())
     }
 
+    /// Jumps to a routine shared by every command that needs it, writing it out right here the first time.
+    ///
+    /// Routines always end in a jump, so the code after the first one never runs into it by accident.
+    fn shared(&mut self, label: String, routine: impl FnOnce(&mut Self)) {
+        if self.shared.insert(label.clone()) {
+            let (file, function) = self.part.clone();
+            self.enter("(shared)".to_string(), label.clone());
+            self.mark(label.clone(), None);
+            self.asm.push(asm!("shared routine {label}"));
+            self.def_label(label.clone());
+            routine(self);
+            self.enter(file, function);
+        } else {
+            self.asm.extend(asm![
+                @label
+                0;JMP
+            ]);
+        }
+    }
+
+    /// Pops the return value into the caller's stack and restores its frame
+    fn return_routine(&mut self) {
+        self.asm.extend(asm![
+            "Get the return address from 5 slots before the current local segment and store it in R14"
+                @5
+                D=A
+                @LCL
+                A=M-D
+                D=M
+                @R14
+                M=D
+                ""
+                @SP
+                A=M-1
+                D=M
+                @ARG
+                A=M
+                M=D
+                D=A+1
+                @SP
+                M=D
+                ""
+                @LCL
+                D=M-1
+                @R13
+                AM=D
+            "restore saved that segment"
+                D=M
+                @THAT
+                M=D
+            "restore saved this segment"
+                @R13
+                AM=M-1
+                D=M
+                @THIS
+                M=D
+            "restore saved argument segment"
+                @R13
+                AM=M-1
+                D=M
+                @ARG
+                M=D
+            "restore saved local segment"
+                @R13
+                AM=M-1
+                D=M
+                @LCL
+                M=D
+            "jump to the saved return address"
+                @R14
+                A=M
+                0;JMP
+        ]);
+    }
+
     fn unary_op(&mut self, last_line: Asm<'a>) {
         self.asm.extend(asm![
             @SP
@@ -218,34 +374,114 @@ impl<'a> VmTranslator<'a> {
         self.asm.push(last_line);
     }
 
-    fn comparison(&mut self, comparison: Cmp) {
+    /// Compares the top of the stack against D if `immediate`, or against the value above it otherwise
+    fn comparison(&mut self, comparison: Cmp, immediate: bool) {
         // making our comp_count into a simple identifier for formatting with the macro more easily
         let counter = self.comp_count;
         self.comp_count += 1;
         let end_comp = format!("END_COMP{counter}");
 
-        // Computes the difference between the two values at the top of the stack
-        self.binary_op(asm!(MD = M - D));
-
-        self.asm.extend(vec![
-            asm!(@end_comp),
-            match comparison {
-                Cmp::EQ => asm!(D;JNE),
-                Cmp::GT => asm!(D;JLE),
-                Cmp::LT => asm!(D;JGE),
-                // Unofficial
-                Cmp::LE => asm!(D;JGT),
-                Cmp::GE => asm!(D;JLT),
-                Cmp::NE => asm!(D;JEQ),
-            },
+        if immediate {
+            // Comparing inline without overflowing takes longer than pushing D for the shared routine
+            self.asm.extend(asm![
+                @SP
+                AM=M+1
+                A=A-1
+                M=D
+            ]);
+        }
+        // The shared routine returns to the address left in D
+        self.asm.extend(asm![
+            @end_comp
+            D=A
         ]);
+        let label = format!("$${}", comparison.to_string().to_uppercase());
+        self.shared(label.clone(), |writer| {
+            writer.comparison_routine(comparison, &label)
+        });
+        self.def_label(end_comp);
+    }
 
+    /// Replaces the top two values on the stack with -1 if the comparison holds or 0 otherwise,
+    /// then jumps to the return address that was in D
+    fn comparison_routine(&mut self, comparison: Cmp, label: &str) {
+        let holds = format!("{label}$TRUE");
         self.asm.extend(asm![
-            D=D+1
-        (end_comp)
+            @R15
+            M=D
+        ]);
+        if matches!(comparison, Cmp::EQ | Cmp::NE) {
+            // Equality only needs the difference to be zero, which it is even when it overflows
+            self.binary_op(asm!(D = M - D));
+        } else {
+            self.ordering(label);
+        }
+        self.asm.extend(asm![
+            @SP
+            A=M-1
+            M=-1
+            @holds
+        ]);
+        self.asm.push(match comparison {
+            Cmp::EQ => asm!(D;JEQ),
+            Cmp::GT => asm!(D;JGT),
+            Cmp::LT => asm!(D;JLT),
+            // Unofficial
+            Cmp::LE => asm!(D;JLE),
+            Cmp::GE => asm!(D;JGE),
+            Cmp::NE => asm!(D;JNE),
+        });
+        self.asm.extend(asm![
+            @SP
+            A=M-1
+            M=0
+        (holds)
+            @R15
+            A=M
+            0;JMP
+        ]);
+    }
+
+    /// Pops the top of the stack and leaves D with the sign of the value below it minus the popped one.
+    ///
+    /// Subtracting values of opposite signs can overflow, so they are told apart by their signs alone.
+    fn ordering(&mut self, label: &str) {
+        let x_negative = format!("{label}$XNEG");
+        let same_signs = format!("{label}$SAME");
+        let signed = format!("{label}$SIGNED");
+        self.asm.extend(asm![
+            @SP
+            AM=M-1
+            D=M
+            @R13
+            M=D
             @SP
             A=M-1
-            M=M-D
+            D=M
+            @x_negative
+            D;JLT
+            @R13
+            D=M
+            @same_signs
+            D;JGE
+            "x is at least 0 and y is negative, so x is greater"
+            D=1
+            @signed
+            0;JMP
+        (x_negative)
+            @R13
+            D=M
+            @same_signs
+            D;JLT
+            "x is negative and y is at least 0, so x is less"
+            D=-1
+            @signed
+            0;JMP
+        (same_signs)
+            @SP
+            A=M-1
+            D=M-D
+        (signed)
         ]);
     }
 
@@ -264,7 +500,7 @@ impl<'a> VmTranslator<'a> {
     }
 
     // local, argument, this, that
-    pub fn push_segment(&mut self, segment: impl Display, n: i16) {
+    pub fn push_segment(&mut self, segment: Asm<'a>, n: i16) {
         self.segment(segment, n);
 
         self.asm.extend(asm![
@@ -275,12 +511,12 @@ impl<'a> VmTranslator<'a> {
         self.push();
     }
 
-    pub fn segment(&mut self, segment: impl Display, n: i16) {
-        self.asm
-            .extend([Asm::from(n), asm!(D = A), asm!(@"{segment}")]);
+    /// Leaves `n` in D and the address of the segment's base pointer in A
+    pub fn segment(&mut self, segment: Asm<'a>, n: i16) {
+        self.asm.extend([Asm::from(n), asm!(D = A), segment]);
     }
 
-    pub fn pop_segment<T: Display>(&mut self, segment: T, n: i16) {
+    pub fn pop_segment(&mut self, segment: Asm<'a>, n: i16) {
         self.segment(segment, n);
 
         self.asm.extend(asm![
@@ -328,17 +564,93 @@ impl<'a> VmTranslator<'a> {
                 // This works for even -32768 which is why we do it instead of arithmetic negation
                 // `push constant n`
                 // `neg/not`
-                if var < 0 {
-                    self.asm.extend([Asm::from(!v), asm!(D = !A)])
-                } else {
-                    self.asm.extend([Asm::from(v), asm!(D = A)])
-                }
+                self.load_constant(v);
                 // Then we push it to the stack
                 self.push();
             }
         }
     }
 
+    /// Leaves any constant in D using two instructions
+    fn load_constant(&mut self, v: i16) {
+        if v < 0 {
+            self.asm.extend([Asm::from(!v), asm!(D = !A)])
+        } else {
+            self.asm.extend([Asm::from(v), asm!(D = A)])
+        }
+    }
+
+    /// Where a segment's `n`th value lives
+    fn place(&self, seg: Seg, n: i16) -> Result<Place<'a>> {
+        Ok(match seg {
+            Seg::Argument => Place::Segment(Asm::ARG, n),
+            Seg::Local => Place::Segment(Asm::LCL, n),
+            Seg::This => Place::Segment(Asm::THIS, n),
+            Seg::That => Place::Segment(Asm::THAT, n),
+            Seg::Static => Place::Direct(Asm::At(Cow::Owned(format!("{}.{n}", self.filename)))),
+            Seg::Pointer => match n {
+                0 => Place::Direct(Asm::THIS),
+                1 => Place::Direct(Asm::THAT),
+                _ => bail!("Unsupported pointer {n}"),
+            },
+            Seg::Temp => match n {
+                0..=7 => Place::Direct(Asm::At(Cow::Owned(format!("R{}", n + 5)))),
+                _ => bail!("Unsupported temp register {n}"),
+            },
+            Seg::Constant => Place::Constant(n),
+        })
+    }
+
+    /// Leaves the value at a place in D
+    fn load(&mut self, place: Place<'a>) {
+        match place {
+            Place::Constant(n) => self.load_constant(n),
+            Place::Direct(var) => self.asm.extend([var, asm!(D = M)]),
+            Place::Segment(base, n) => {
+                self.segment(base, n);
+                self.asm.extend(asm![
+                    A=D+M
+                    D=M
+                ]);
+            }
+        }
+    }
+
+    /// `push` then `pop`, without the stack in between
+    fn move_value(&mut self, from: Place<'a>, to: Place<'a>) -> Result<()> {
+        match (from, to) {
+            (_, Place::Constant(_)) => bail!("cannot pop to constant"),
+            (Place::Constant(v @ -1..=1), Place::Direct(var)) => {
+                self.asm.push(var);
+                self.asm.push(match v {
+                    -1 => asm!(M = -1),
+                    0 => asm!(M = 0),
+                    _ => asm!(M = 1),
+                });
+            }
+            (from, Place::Direct(var)) => {
+                self.load(from);
+                self.asm.extend([var, asm!(M = D)]);
+            }
+            (from, Place::Segment(base, n)) => {
+                // Loading the value needs A, so the address is worked out first and kept in R13
+                self.segment(base, n);
+                self.asm.extend(asm![
+                    D=D+M
+                    @R13
+                    M=D
+                ]);
+                self.load(from);
+                self.asm.extend(asm![
+                    @R13
+                    A=M
+                    M=D
+                ]);
+            }
+        }
+        Ok(())
+    }
+
     // Helper function to reduce code rewriting if we want to fine-tune the generated assembly
     fn push(&mut self) {
         self.asm.extend(asm![
@@ -385,6 +697,7 @@ impl<'a> VmTranslator<'a> {
 
     fn func(&mut self, fn_name: &str, n_vars: i16) {
         self.curr_func = String::from(fn_name);
+        self.enter(format!("{}.vm", self.filename), fn_name.to_string());
         self.asm.extend(asm![
         ("{fn_name}")
             @n_vars
@@ -404,48 +717,45 @@ impl<'a> VmTranslator<'a> {
     }
 
     fn call_func(&mut self, function: &'a str, n_args: i16) {
+        let caller = self.marks.last().cloned();
         let return_label = format!("{}.ret${}", self.filename, self.call_count);
         self.call_count += 1;
 
-        // Save return addr
-        // This has to be done separately for each call
-        self.push_value(asm!(@return_label), Mode::A);
-        match n_args {
-            0 => self.asm.extend(asm![
-                @R14
-                M=0
-            ]),
-            1 => self.asm.extend(asm![
-                @R14
-                M=1
-            ]),
-            a => self.asm.extend(asm![
-                @a
-                D=A
-                @R14
-                M=D
-            ]),
-        }
+        // The arguments start below the 5 saved values, and the locals right after them
+        let frame_size = n_args + 5;
         self.asm.extend(asm![
-            @n_args
+            @frame_size
+            D=A
+            @R13
+            M=D
+            @function
+            D=A
+            @R14
+            M=D
+            @return_label
             D=A
         ]);
+        self.shared("$$CALL".to_string(), Self::call_routine);
+        // So the debugger can tell which call a return address belongs to
+        if let Some(caller) = caller {
+            self.mark(caller.function, caller.source);
+            self.asm.push(asm!("back from {function}"));
+        }
+        self.def_label(return_label);
+    }
+
+    /// Saves the return address in D and the caller's frame, then jumps to the function in R14
+    /// with its arguments starting R13 slots below the saved frame's end
+    fn call_routine(&mut self) {
+        self.push();
+        self.push_value(Asm::LCL, Mode::M);
+        self.push_value(Asm::ARG, Mode::M);
+        self.push_value(Asm::THIS, Mode::M);
+        self.push_value(Asm::THAT, Mode::M);
 
-        // TODO: The rest can be put into a assembly subroutine
-        // We have to store n_args in a temp register
-        // Save current stack frame
-        self.asm.push(asm!(("$$Call")));
-        self.push_value("LCL", Mode::M);
-        self.push_value("ARG", Mode::M);
-        self.push_value("THIS", Mode::M);
-        self.push_value("THAT", Mode::M);
         self.asm.extend(asm![
-            //@n_args
-            //D=A
-            @R14
+            @R13
             D=M
-            @5
-            D=D+A
             @SP
             D=M-D
             @ARG
@@ -454,9 +764,141 @@ impl<'a> VmTranslator<'a> {
             D=M
             @LCL
             M=D
-            @function
+            @R14
+            A=M
             0;JMP
-        (return_label)
         ])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::cpu::{headless::Stop, Cpu};
+
+    #[test]
+    fn test_multiple_files_with_bootstrap() {
+        let sources = [
+            (
+                "Main".to_string(),
+                "function Main.double 0
+                push argument 0
+                push argument 0
+                add
+                pop static 0
+                push static 0
+                return"
+                    .to_string(),
+            ),
+            (
+                "Sys".to_string(),
+                "function Sys.init 1
+                push constant 21
+                call Main.double 1
+                pop local 0
+                push local 0
+                push constant 42
+                eq
+                pop static 0
+                label END
+                goto END"
+                    .to_string(),
+            ),
+        ];
+        let Translation { asm, starts, .. } = translate_sources(&sources, true, None).unwrap();
+        assert_eq!(starts.len(), 18);
+        let hack = Assembler::new().assemble(&asm).unwrap();
+        let mut cpu = Cpu::new(&hack);
+        cpu.run_headless(Some(1000)).unwrap();
+        // Main.0 and Sys.0 are separate statics
+        assert_eq!(cpu.ram[16], 42);
+        assert_eq!(cpu.ram[17], -1);
+        // Only the bootstrap frame, Sys.init's local and its leftover stack remain
+        assert_eq!(cpu.ram[0], 262);
+    }
+
+    #[test]
+    fn test_sys_init_returning_halts() {
+        let sources = [(
+            "Sys".to_string(),
+            "function Sys.init 0\npush constant 7\nreturn".to_string(),
+        )];
+        let Translation { asm, .. } = translate_sources(&sources, true, None).unwrap();
+        let hack = Assembler::new().assemble(&asm).unwrap();
+        let mut cpu = Cpu::new(&hack);
+        assert_eq!(cpu.run_headless(Some(1000)).unwrap().0, Stop::Halted);
+        assert_eq!(cpu.ram[256], 7);
+    }
+
+    #[test]
+    fn test_shared_routines() {
+        let sys = |calls: usize| {
+            let mut src = String::from("function Sys.init 0\n");
+            for i in 0..calls {
+                src += &format!(
+                    "push constant {i}\npush constant 2\ncall Sys.cmp 2\npop static {i}\n"
+                );
+            }
+            src += "label END\ngoto END
+                function Sys.cmp 0
+                push argument 0
+                push argument 1
+                lt
+                push argument 0
+                push argument 1
+                eq
+                push argument 0
+                push argument 1
+                gt
+                or
+                not
+                or
+                return";
+            [("Sys".to_string(), src)]
+        };
+        let rom_size = |calls| {
+            let Translation { asm, .. } = translate_sources(&sys(calls), true, None).unwrap();
+            Assembler::new().assemble(&asm).unwrap().len()
+        };
+        // Pushing two arguments, the call itself and the pop, where an inlined call alone took 42
+        assert_eq!(rom_size(4) - rom_size(3), 12 + 12 + 5);
+
+        let Translation { asm, sizes, .. } = translate_sources(&sys(4), true, None).unwrap();
+        for routine in ["$$CALL", "$$RETURN", "$$LT", "$$EQ", "$$GT"] {
+            let count = asm
+                .iter()
+                .filter(|line| matches!(line, Asm::Label(l) if l == routine))
+                .count();
+            assert_eq!(count, 1, "{routine}");
+        }
+        let parts: Vec<_> = sizes
+            .iter()
+            .map(|s| (s.file.as_str(), s.function.as_str()))
+            .collect();
+        assert_eq!(
+            parts,
+            [
+                ("(bootstrap)", "(bootstrap)"),
+                ("(shared)", "$$CALL"),
+                ("(bootstrap)", "(bootstrap)"),
+                ("Sys.vm", "Sys.init"),
+                ("Sys.vm", "Sys.cmp"),
+                ("(shared)", "$$LT"),
+                ("Sys.vm", "Sys.cmp"),
+                ("(shared)", "$$EQ"),
+                ("Sys.vm", "Sys.cmp"),
+                ("(shared)", "$$GT"),
+                ("Sys.vm", "Sys.cmp"),
+                ("(shared)", "$$RETURN"),
+            ]
+        );
+        let total: usize = sizes.iter().map(|s| s.instructions).sum();
+        let hack = Assembler::new().assemble(&asm).unwrap();
+        assert_eq!(hack.len(), total);
+        let mut cpu = Cpu::new(&hack);
+        assert_eq!(cpu.run_headless(Some(10_000)).unwrap().0, Stop::Halted);
+        // Whether each argument was less than 2
+        assert_eq!(cpu.ram[16..20], [-1, -1, 0, 0]);
+    }
+}